@@ -0,0 +1,70 @@
+//! Instructions per second with and without the decode cache
+//!
+//! Runs the golden test cases, 2048 and rogue with their keystroke scripts,
+//! alternating between the two settings and keeping the best of several
+//! rounds so other load on the machine matters less:
+//!
+//! ```text
+//! cargo bench --bench decode_cache
+//! ```
+
+use std::time::{Duration, Instant};
+
+use vm::{
+    unstable::{
+        cpu::VmCPU,
+        debugger,
+        testing::{self, TestCase},
+    },
+    MachineBuilder,
+};
+
+const CASES: [&str; 2] = ["resources/golden/2048.case", "resources/golden/rogue.case"];
+
+const ROUNDS: usize = 5;
+
+/// Instructions per second over runs of `case` taking about half a second
+fn measure(case: &TestCase, cache: bool) -> f64 {
+    let mut instructions = 0;
+    let mut elapsed = Duration::ZERO;
+    while elapsed < Duration::from_millis(500) {
+        let (origin, words, _) = debugger::read_program(&case.program, None).unwrap();
+        let mut builder = MachineBuilder::new()
+            .image(origin, &words)
+            .decode_cache(cache);
+        if let Some(seed) = case.seed {
+            builder = builder.seed(seed);
+        }
+        let mut vm = builder.build().into_cpu();
+
+        let start = Instant::now();
+        let run = testing::type_script(
+            &mut vm,
+            &case.script,
+            case.budget,
+            case.after_input,
+            VmCPU::advance,
+        );
+        elapsed += start.elapsed();
+        instructions += run.instructions;
+    }
+    instructions as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    for path in CASES {
+        let case = TestCase::load(path).unwrap();
+        let (mut uncached, mut cached) = (0f64, 0f64);
+        for _ in 0..ROUNDS {
+            uncached = uncached.max(measure(&case, false));
+            cached = cached.max(measure(&case, true));
+        }
+        println!(
+            "{:<6} uncached {:>6.2} M/s  cached {:>6.2} M/s  {:.2}x",
+            case.name,
+            uncached / 1e6,
+            cached / 1e6,
+            cached / uncached
+        );
+    }
+}
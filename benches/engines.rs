@@ -0,0 +1,47 @@
+//! Instructions per second of the interpreter and the block engine
+//!
+//! Runs the golden test cases, 2048 and rogue with their keystroke scripts,
+//! alternating between the engines and keeping the best of several rounds:
+//!
+//! ```text
+//! cargo bench --bench engines
+//! ```
+
+use std::time::{Duration, Instant};
+
+use vm::{unstable::testing::TestCase, Engine};
+
+const CASES: [&str; 2] = ["resources/golden/2048.case", "resources/golden/rogue.case"];
+
+const ROUNDS: usize = 5;
+
+/// Instructions per second over runs of `case` taking about half a second
+fn measure(case: &TestCase, engine: Engine) -> f64 {
+    let mut instructions = 0;
+    let mut elapsed = Duration::ZERO;
+    while elapsed < Duration::from_millis(500) {
+        let start = Instant::now();
+        let run = case.run_with(engine).unwrap();
+        elapsed += start.elapsed();
+        instructions += run.instructions;
+    }
+    instructions as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    for path in CASES {
+        let case = TestCase::load(path).unwrap();
+        let (mut interpreter, mut blocks) = (0f64, 0f64);
+        for _ in 0..ROUNDS {
+            interpreter = interpreter.max(measure(&case, Engine::Interpreter));
+            blocks = blocks.max(measure(&case, Engine::Blocks));
+        }
+        println!(
+            "{:<6} interpreter {:>6.2} M/s  blocks {:>6.2} M/s  {:.2}x",
+            case.name,
+            interpreter / 1e6,
+            blocks / 1e6,
+            blocks / interpreter
+        );
+    }
+}
@@ -0,0 +1,536 @@
+//! Two pass LC-3 assembler
+//!
+//! Pass one lays out every line to an address and collects labels,
+//! pass two encodes the words. Alongside the object words the assembler
+//! keeps a line table so debuggers can map addresses back to source lines.
+
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use crate::debug_info::{LineTable, SymbolTable};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    /// 1-based source line
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    pub symbols: SymbolTable,
+    pub lines: LineTable,
+}
+
+impl Assembly {
+    /// Bytes of an `.obj` file: big endian origin followed by the words.
+    pub fn to_obj_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((self.words.len() + 1) * 2);
+        bytes.extend_from_slice(&self.origin.to_be_bytes());
+        for word in &self.words {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+pub fn assemble_file<P: AsRef<Path>>(file_path: P) -> std::io::Result<Assembly> {
+    let source = fs::read_to_string(file_path)?;
+    assemble(&source).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+#[derive(Debug)]
+enum Operation<'a> {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<&'a str>,
+    },
+    Fill(&'a str),
+    Blkw(u16),
+    Stringz(Vec<u16>),
+}
+
+#[derive(Debug)]
+struct Statement<'a> {
+    line: usize,
+    address: u16,
+    operation: Operation<'a>,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AssembleError> {
+    let mut origin: Option<u16> = None;
+    let mut address: u32 = 0;
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut symbols = SymbolTable::default();
+    let mut statements = Vec::new();
+
+    for (index, raw_line) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| AssembleError { line, message };
+
+        let text = strip_comment(raw_line).trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mut head, mut rest) = split_first_token(text);
+
+        if !is_operation(head) {
+            // leading token is a label
+            let label = head.trim_end_matches(':');
+            if !is_valid_label(label) {
+                return Err(error(format!("invalid label `{}`", label)));
+            }
+            if origin.is_none() {
+                return Err(error("label before .ORIG".to_string()));
+            }
+            if labels.insert(label.to_string(), address as u16).is_some() {
+                return Err(error(format!("duplicate label `{}`", label)));
+            }
+            symbols.insert(label, address as u16);
+
+            if rest.is_empty() {
+                continue;
+            }
+            (head, rest) = split_first_token(rest);
+            if !is_operation(head) {
+                return Err(error(format!("unknown instruction `{}`", head)));
+            }
+        }
+
+        let upper = head.to_ascii_uppercase();
+        match upper.as_str() {
+            ".ORIG" => {
+                if origin.is_some() {
+                    return Err(error("multiple .ORIG directives".to_string()));
+                }
+                let value = parse_number(rest.trim()).map_err(error)?;
+                origin = Some(value as u16);
+                address = value as u16 as u32;
+                continue;
+            }
+            ".END" => break,
+            _ => {}
+        }
+
+        if origin.is_none() {
+            return Err(error(format!("`{}` before .ORIG", head)));
+        }
+
+        let operation = match upper.as_str() {
+            ".FILL" => Operation::Fill(rest.trim()),
+            ".BLKW" => {
+                let count = parse_number(rest.trim()).map_err(error)?;
+                Operation::Blkw(count as u16)
+            }
+            ".STRINGZ" => Operation::Stringz(parse_string(rest.trim()).map_err(error)?),
+            _ => Operation::Instruction {
+                mnemonic: upper,
+                operands: split_operands(rest),
+            },
+        };
+
+        let size = match &operation {
+            Operation::Instruction { .. } | Operation::Fill(_) => 1,
+            Operation::Blkw(count) => *count as u32,
+            Operation::Stringz(chars) => chars.len() as u32,
+        };
+
+        statements.push(Statement {
+            line,
+            address: address as u16,
+            operation,
+        });
+
+        address += size;
+        if address > 1 << 16 {
+            return Err(error("program does not fit in memory".to_string()));
+        }
+    }
+
+    let origin = origin.ok_or(AssembleError {
+        line: 1,
+        message: "missing .ORIG".to_string(),
+    })?;
+
+    let mut words = Vec::new();
+    let mut lines = LineTable::default();
+
+    for statement in statements {
+        let error = |message: String| AssembleError {
+            line: statement.line,
+            message,
+        };
+        match statement.operation {
+            Operation::Instruction { mnemonic, operands } => {
+                let word =
+                    encode(&mnemonic, &operands, statement.address, &labels).map_err(error)?;
+                lines.insert(statement.line, statement.address, 1, true);
+                words.push(word);
+            }
+            Operation::Fill(operand) => {
+                let word = match parse_number(operand) {
+                    Ok(value) => value as u16,
+                    Err(_) => *labels
+                        .get(operand)
+                        .ok_or_else(|| error(format!("undefined label `{}`", operand)))?,
+                };
+                lines.insert(statement.line, statement.address, 1, false);
+                words.push(word);
+            }
+            Operation::Blkw(count) => {
+                lines.insert(statement.line, statement.address, count, false);
+                words.extend(std::iter::repeat_n(0, count as usize));
+            }
+            Operation::Stringz(chars) => {
+                lines.insert(statement.line, statement.address, chars.len() as u16, false);
+                words.extend(chars);
+            }
+        }
+    }
+
+    Ok(Assembly {
+        origin,
+        words,
+        symbols,
+        lines,
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if in_string => escaped = !escaped,
+            '"' if !escaped => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => escaped = false,
+        }
+        if c != '\\' {
+            escaped = false;
+        }
+    }
+    line
+}
+
+fn split_first_token(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(|c: char| c.is_whitespace()) {
+        Some(i) => (&text[..i], text[i..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn is_valid_label(label: &str) -> bool {
+    let mut chars = label.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn branch_flags(mnemonic: &str) -> Option<(bool, bool, bool)> {
+    let flags = mnemonic.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some((true, true, true));
+    }
+    let (mut n, mut z, mut p) = (false, false, false);
+    for c in flags.chars() {
+        let flag = match c {
+            'N' => &mut n,
+            'Z' => &mut z,
+            'P' => &mut p,
+            _ => return None,
+        };
+        if *flag {
+            return None;
+        }
+        *flag = true;
+    }
+    Some((n, z, p))
+}
+
+fn is_operation(token: &str) -> bool {
+    let upper = token.to_ascii_uppercase();
+    if branch_flags(&upper).is_some() {
+        return true;
+    }
+    matches!(
+        upper.as_str(),
+        ".ORIG"
+            | ".END"
+            | ".FILL"
+            | ".BLKW"
+            | ".STRINGZ"
+            | "ADD"
+            | "AND"
+            | "NOT"
+            | "LD"
+            | "LDI"
+            | "LDR"
+            | "LEA"
+            | "ST"
+            | "STI"
+            | "STR"
+            | "JMP"
+            | "RET"
+            | "JSR"
+            | "JSRR"
+            | "RTI"
+            | "TRAP"
+            | "GETC"
+            | "OUT"
+            | "PUTS"
+            | "IN"
+            | "PUTSP"
+            | "HALT"
+    )
+}
+
+fn parse_number(token: &str) -> Result<i32, String> {
+    let token = token.trim();
+    let (radix, digits) = if let Some(digits) = token.strip_prefix('#') {
+        (10, digits)
+    } else if let Some(digits) = token.strip_prefix(['x', 'X']) {
+        (16, digits)
+    } else if let Some(digits) = token.strip_prefix(['b', 'B']) {
+        (2, digits)
+    } else {
+        (10, token)
+    };
+
+    let value =
+        i32::from_str_radix(digits, radix).map_err(|_| format!("invalid number `{}`", token))?;
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(format!("number `{}` does not fit in 16 bits", token));
+    }
+    Ok(value)
+}
+
+fn parse_string(token: &str) -> Result<Vec<u16>, String> {
+    let inner = token
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| format!("expected quoted string, found `{}`", token))?;
+
+    let mut chars = Vec::new();
+    let mut iter = inner.chars();
+    while let Some(c) = iter.next() {
+        let value = if c == '\\' {
+            match iter.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('e') => '\x1b',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some(other) => return Err(format!("unknown escape `\\{}`", other)),
+                None => return Err("dangling escape".to_string()),
+            }
+        } else {
+            c
+        };
+        chars.push(value as u16);
+    }
+    chars.push(0);
+    Ok(chars)
+}
+
+fn parse_register(token: &str) -> Result<u16, String> {
+    match token.as_bytes() {
+        [b'R' | b'r', digit @ b'0'..=b'7'] => Ok((digit - b'0') as u16),
+        _ => Err(format!("expected register, found `{}`", token)),
+    }
+}
+
+fn fit_signed(value: i32, bits: u32, token: &str) -> Result<u16, String> {
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    if value < min || value > max {
+        return Err(format!(
+            "`{}` does not fit in a {} bit signed field",
+            token, bits
+        ));
+    }
+    Ok((value as u16) & ((1 << bits) - 1))
+}
+
+fn pc_offset(
+    token: &str,
+    bits: u32,
+    address: u16,
+    labels: &HashMap<String, u16>,
+) -> Result<u16, String> {
+    let offset = match labels.get(token) {
+        Some(target) => *target as i32 - (address as i32 + 1),
+        None => parse_number(token).map_err(|_| format!("undefined label `{}`", token))?,
+    };
+    fit_signed(offset, bits, token)
+}
+
+fn expect_operands(mnemonic: &str, operands: &[&str], count: usize) -> Result<(), String> {
+    if operands.len() != count {
+        return Err(format!(
+            "{} expects {} operand(s), found {}",
+            mnemonic,
+            count,
+            operands.len()
+        ));
+    }
+    Ok(())
+}
+
+fn encode(
+    mnemonic: &str,
+    operands: &[&str],
+    address: u16,
+    labels: &HashMap<String, u16>,
+) -> Result<u16, String> {
+    if let Some((n, z, p)) = branch_flags(mnemonic) {
+        expect_operands(mnemonic, operands, 1)?;
+        let flags = (n as u16) << 11 | (z as u16) << 10 | (p as u16) << 9;
+        return Ok(flags | pc_offset(operands[0], 9, address, labels)?);
+    }
+
+    let trap = |vector: u16| -> Result<u16, String> {
+        expect_operands(mnemonic, operands, 0)?;
+        Ok(0xF000 | vector)
+    };
+
+    match mnemonic {
+        "ADD" | "AND" => {
+            expect_operands(mnemonic, operands, 3)?;
+            let op_code: u16 = if mnemonic == "ADD" { 0x1000 } else { 0x5000 };
+            let dest = parse_register(operands[0])?;
+            let src = parse_register(operands[1])?;
+            let last = match parse_register(operands[2]) {
+                Ok(src_2) => src_2,
+                Err(_) => 1 << 5 | fit_signed(parse_number(operands[2])?, 5, operands[2])?,
+            };
+            Ok(op_code | dest << 9 | src << 6 | last)
+        }
+        "NOT" => {
+            expect_operands(mnemonic, operands, 2)?;
+            let dest = parse_register(operands[0])?;
+            let src = parse_register(operands[1])?;
+            Ok(0x9000 | dest << 9 | src << 6 | 0x3F)
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect_operands(mnemonic, operands, 2)?;
+            let op_code: u16 = match mnemonic {
+                "LD" => 0x2000,
+                "LDI" => 0xA000,
+                "LEA" => 0xE000,
+                "ST" => 0x3000,
+                _ => 0xB000,
+            };
+            let register = parse_register(operands[0])?;
+            Ok(op_code | register << 9 | pc_offset(operands[1], 9, address, labels)?)
+        }
+        "LDR" | "STR" => {
+            expect_operands(mnemonic, operands, 3)?;
+            let op_code: u16 = if mnemonic == "LDR" { 0x6000 } else { 0x7000 };
+            let register = parse_register(operands[0])?;
+            let base = parse_register(operands[1])?;
+            let offset = fit_signed(parse_number(operands[2])?, 6, operands[2])?;
+            Ok(op_code | register << 9 | base << 6 | offset)
+        }
+        "JMP" => {
+            expect_operands(mnemonic, operands, 1)?;
+            Ok(0xC000 | parse_register(operands[0])? << 6)
+        }
+        "RET" => {
+            expect_operands(mnemonic, operands, 0)?;
+            Ok(0xC1C0)
+        }
+        "JSR" => {
+            expect_operands(mnemonic, operands, 1)?;
+            Ok(0x4800 | pc_offset(operands[0], 11, address, labels)?)
+        }
+        "JSRR" => {
+            expect_operands(mnemonic, operands, 1)?;
+            Ok(0x4000 | parse_register(operands[0])? << 6)
+        }
+        "RTI" => {
+            expect_operands(mnemonic, operands, 0)?;
+            Ok(0x8000)
+        }
+        "TRAP" => {
+            expect_operands(mnemonic, operands, 1)?;
+            let vector = parse_number(operands[0])?;
+            if !(0..=0xFF).contains(&vector) {
+                return Err(format!("trap vector `{}` out of range", operands[0]));
+            }
+            Ok(0xF000 | vector as u16)
+        }
+        "GETC" => trap(0x20),
+        "OUT" => trap(0x21),
+        "PUTS" => trap(0x22),
+        "IN" => trap(0x23),
+        "PUTSP" => trap(0x24),
+        "HALT" => trap(0x25),
+        _ => Err(format!("unknown instruction `{}`", mnemonic)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_assemble_2048() {
+        let assembly = assemble_file("./resources/2048.asm").unwrap();
+
+        assert_eq!(assembly.origin, 0x3000);
+        // LD R6, STACK with STACK at x3018
+        assert_eq!(assembly.words[0], 0x2C17);
+        // LEA R5, BOARD with BOARD at x301A
+        assert_eq!(assembly.words[1], 0xEA18);
+        assert_eq!(assembly.to_obj_bytes()[..4], [0x30, 0x00, 0x2C, 0x17]);
+    }
+
+    #[test]
+    fn test_symbols_match_2048_sym() {
+        let assembly = assemble_file("./resources/2048.asm").unwrap();
+        let symbols = SymbolTable::load_from_file("./resources/2048.sym").unwrap();
+
+        for (name, address) in symbols.iter() {
+            assert_eq!(assembly.symbols.address_of(name), Some(address), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_line_table() {
+        let source = ".ORIG x3000\nLOOP ADD R0, R0, #-1 ; count down\n  BRp LOOP\n  HALT\nDATA .FILL x10\n.END\n";
+        let assembly = assemble(source).unwrap();
+
+        assert_eq!(assembly.words, vec![0x103F, 0x03FE, 0xF025, 0x0010]);
+        assert_eq!(assembly.lines.address_of_line(2), Some(0x3000));
+        assert_eq!(assembly.lines.line_of_address(0x3002), Some(4));
+        assert_eq!(assembly.lines.address_of_line(5), None);
+    }
+
+    #[test]
+    fn test_errors_report_line() {
+        let error = assemble(".ORIG x3000\n  ADD R0, R0, #16\n.END").unwrap_err();
+        assert_eq!(error.line, 2);
+
+        let error = assemble(".ORIG x3000\n  BR MISSING\n.END").unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+}
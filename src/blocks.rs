@@ -0,0 +1,420 @@
+//! Basic block engine
+//!
+//! [`VmCPU::step`] decodes and dispatches one instruction at a time, with
+//! room around each for undo history, profiling and the other observers.
+//! For long unobserved runs, like grading a pile of submissions,
+//! [`Engine::Blocks`] translates each straight run of instructions up to
+//! the next BR, JMP, JSR, JSRR, TRAP or RTI once into an array of ops with
+//! PC relative addresses worked out, and executes a whole run at a time.
+//! Blocks ending in BR or JMP run to the end on their own; calls, returns,
+//! TRAP and RTI, which the call stack follows, still go through `step`, and
+//! so does everything while an observer is set. The two engines differ only
+//! in speed:
+//!
+//! - memory is read and written through the device bus as usual and the
+//!   devices tick after every instruction
+//! - a block is left early when an interrupt becomes due or a device asks
+//!   to halt, so both happen at the same instruction as with `step`
+//! - a block is compared with the words it was translated from whenever it
+//!   is entered, and left right after a store into itself
+
+use std::rc::Rc;
+
+use crate::{
+    cpu::{StepResult, VmCPU, FL_NEG, FL_POS, FL_ZRO},
+    devices::DEVICE_PAGE,
+    instructions::{Instructions, JumpType, LoadType},
+    register::Registers,
+};
+
+/// How [`VmCPU::advance`] executes instructions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// one [`VmCPU::step`] at a time
+    #[default]
+    Interpreter,
+    /// a basic block at a time while nothing observes single instructions
+    Blocks,
+}
+
+// longest run of instructions translated into one block
+const MAX_BLOCK: usize = 64;
+
+/// A translated instruction, registers as indices and PC relative
+/// addresses resolved
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add(usize, usize, usize),
+    AddImmediate(usize, usize, u16),
+    And(usize, usize, usize),
+    AndImmediate(usize, usize, u16),
+    Not(usize, usize),
+    Load(usize, u16),
+    LoadIndirect(usize, u16),
+    LoadRegister(usize, usize, u16),
+    LoadAddress(usize, u16),
+    Store(usize, u16),
+    StoreIndirect(usize, u16),
+    StoreRegister(usize, usize, u16),
+    /// condition codes to test and the target
+    Branch(u16, u16),
+    Jump(usize),
+}
+
+impl Op {
+    /// `instruction` with the PC at `pc`, `None` for those ending a block
+    fn translate(instruction: Instructions, pc: u16) -> Option<Self> {
+        let index = |register: u16| register as usize;
+        let op = match instruction {
+            Instructions::Add {
+                dest_register,
+                src_register,
+                add_type,
+            } => match add_type {
+                LoadType::Register {
+                    src_register: second,
+                } => Op::Add(index(dest_register), index(src_register), index(second)),
+                LoadType::Immediate { value } => {
+                    Op::AddImmediate(index(dest_register), index(src_register), value)
+                }
+            },
+            Instructions::And {
+                dest_register,
+                src_register,
+                add_type,
+            } => match add_type {
+                LoadType::Register {
+                    src_register: second,
+                } => Op::And(index(dest_register), index(src_register), index(second)),
+                LoadType::Immediate { value } => {
+                    Op::AndImmediate(index(dest_register), index(src_register), value)
+                }
+            },
+            Instructions::Not {
+                dest_register,
+                src_register,
+            } => Op::Not(index(dest_register), index(src_register)),
+            Instructions::LoadDirect {
+                pc_offset_9,
+                dest_register,
+            } => Op::Load(index(dest_register), pc.wrapping_add(pc_offset_9)),
+            Instructions::LoadIndirect {
+                pc_offset_9,
+                dest_register,
+            } => Op::LoadIndirect(index(dest_register), pc.wrapping_add(pc_offset_9)),
+            Instructions::LoadRegister {
+                offset6,
+                base_register,
+                dest_register,
+            } => Op::LoadRegister(index(dest_register), index(base_register), offset6),
+            Instructions::LoadEffectiveAddress {
+                pc_offset_9,
+                dest_register,
+            } => Op::LoadAddress(index(dest_register), pc.wrapping_add(pc_offset_9)),
+            Instructions::StoreDirect {
+                pc_offset_9,
+                src_register,
+            } => Op::Store(index(src_register), pc.wrapping_add(pc_offset_9)),
+            Instructions::StoreIndirect {
+                pc_offset_9,
+                src_register,
+            } => Op::StoreIndirect(index(src_register), pc.wrapping_add(pc_offset_9)),
+            Instructions::StoreRegister {
+                offset6,
+                base_register,
+                src_register,
+            } => Op::StoreRegister(index(src_register), index(base_register), offset6),
+            Instructions::Branch {
+                pc_offset_9,
+                p,
+                z,
+                n,
+            } => {
+                let flags = [(n, FL_NEG), (z, FL_ZRO), (p, FL_POS)];
+                let mask = flags.iter().filter(|(set, _)| *set).map(|(_, flag)| flag);
+                Op::Branch(mask.sum(), pc.wrapping_add(pc_offset_9))
+            }
+            Instructions::Jump(JumpType::BaseRegister(register)) => Op::Jump(index(register)),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    fn ends_block(&self) -> bool {
+        matches!(self, Op::Branch(..) | Op::Jump(_))
+    }
+}
+
+#[derive(Debug)]
+struct Block {
+    /// the words translated, including the one ending the block
+    words: Vec<u16>,
+    ops: Vec<Op>,
+}
+
+impl Block {
+    fn translate(memory: &[u16], start: u16) -> Self {
+        let mut block = Block {
+            words: Vec::new(),
+            ops: Vec::new(),
+        };
+        for address in (start..DEVICE_PAGE).take(MAX_BLOCK) {
+            let word = memory[address as usize];
+            block.words.push(word);
+            match Op::translate(Instructions::from(word), address + 1) {
+                Some(op) => block.ops.push(op),
+                None => break,
+            }
+            if block.ops.last().is_some_and(Op::ends_block) {
+                break;
+            }
+        }
+        block
+    }
+
+    fn is_current(&self, memory: &[u16], start: u16) -> bool {
+        let start = start as usize;
+        memory.get(start..start + self.words.len()) == Some(&self.words[..])
+    }
+}
+
+/// Translated blocks by start address
+#[derive(Debug)]
+pub(crate) struct Blocks(Vec<Option<Rc<Block>>>);
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Self(vec![None; DEVICE_PAGE as usize])
+    }
+}
+
+impl VmCPU {
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.blocks = match engine {
+            Engine::Interpreter => None,
+            Engine::Blocks => Some(Blocks::default()),
+        };
+    }
+
+    pub fn engine(&self) -> Engine {
+        match self.blocks {
+            Some(_) => Engine::Blocks,
+            None => Engine::Interpreter,
+        }
+    }
+
+    /// Execute at least one and at most `limit` instructions, a basic block
+    /// at a time with [`Engine::Blocks`]. Returns how the last one ended,
+    /// like [`VmCPU::step`], and how many were executed.
+    pub fn advance(&mut self, limit: u64) -> (StepResult, u64) {
+        let observed = self.history.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+            || self.frames.is_some();
+        let pc = self.read_register(Registers::ProgramCounter);
+        if self.blocks.is_none() || observed || pc >= DEVICE_PAGE || self.interrupt_due() {
+            return (self.step(), 1);
+        }
+
+        let block = self.block_at(pc);
+        // what a store must not touch for the rest of the block to be valid
+        let translated = pc..pc + block.words.len() as u16;
+        let limit = limit.max(1);
+        let mut executed = 0;
+        for op in &block.ops {
+            if executed == limit {
+                return (StepResult::Continue, executed);
+            }
+            self.halt_requested = false;
+            self.registers[usize::from(Registers::ProgramCounter)] = pc + executed as u16 + 1;
+            let written = self.execute_op(*op);
+            executed += 1;
+
+            self.with_devices(|bus, context| bus.tick(context));
+            if self.halt_requested {
+                return (StepResult::Halted, executed);
+            }
+            let stale = written.is_some_and(|address| translated.contains(&address));
+            if stale || self.interrupt_due() {
+                return (StepResult::Continue, executed);
+            }
+        }
+        if executed == limit || block.ops.last().is_some_and(Op::ends_block) {
+            return (StepResult::Continue, executed);
+        }
+        (self.step(), executed + 1)
+    }
+
+    /// The block starting at `pc`, translated again if memory changed
+    fn block_at(&mut self, pc: u16) -> Rc<Block> {
+        let memory = self.memory.words();
+        let blocks = self.blocks.as_mut().expect("block engine is off");
+        match &blocks.0[pc as usize] {
+            Some(block) if block.is_current(memory, pc) => block.clone(),
+            _ => {
+                let block = Rc::new(Block::translate(memory, pc));
+                blocks.0[pc as usize] = Some(block.clone());
+                block
+            }
+        }
+    }
+
+    /// Returns the address written by a store
+    fn execute_op(&mut self, op: Op) -> Option<u16> {
+        match op {
+            Op::Add(dest, first, second) => self.set_result(
+                dest,
+                self.registers[first].wrapping_add(self.registers[second]),
+            ),
+            Op::AddImmediate(dest, src, value) => {
+                self.set_result(dest, self.registers[src].wrapping_add(value))
+            }
+            Op::And(dest, first, second) => {
+                self.set_result(dest, self.registers[first] & self.registers[second])
+            }
+            Op::AndImmediate(dest, src, value) => {
+                self.set_result(dest, self.registers[src] & value)
+            }
+            Op::Not(dest, src) => self.set_result(dest, !self.registers[src]),
+            Op::Load(dest, address) => {
+                let value = self.read_memory(address);
+                self.set_result(dest, value);
+            }
+            Op::LoadIndirect(dest, pointer) => {
+                let address = self.read_memory(pointer);
+                let value = self.read_memory(address);
+                self.set_result(dest, value);
+            }
+            Op::LoadRegister(dest, base, offset) => {
+                let value = self.read_memory(self.registers[base].wrapping_add(offset));
+                self.set_result(dest, value);
+            }
+            Op::LoadAddress(dest, address) => self.set_result(dest, address),
+            Op::Store(src, address) => {
+                self.write_memory(address, self.registers[src]);
+                return Some(address);
+            }
+            Op::StoreIndirect(src, pointer) => {
+                let address = self.read_memory(pointer);
+                self.write_memory(address, self.registers[src]);
+                return Some(address);
+            }
+            Op::StoreRegister(src, base, offset) => {
+                let address = self.registers[base].wrapping_add(offset);
+                self.write_memory(address, self.registers[src]);
+                return Some(address);
+            }
+            Op::Branch(mask, target) => {
+                if self.registers[usize::from(Registers::Condition)] & mask != 0 {
+                    self.registers[usize::from(Registers::ProgramCounter)] = target;
+                }
+            }
+            Op::Jump(register) => {
+                self.registers[usize::from(Registers::ProgramCounter)] = self.registers[register]
+            }
+        }
+        None
+    }
+
+    /// Write `value` to a register and set the condition codes from it,
+    /// like [`VmCPU::update_flag`]
+    fn set_result(&mut self, register: usize, value: u16) {
+        self.registers[register] = value;
+        self.registers[usize::from(Registers::Condition)] = if value == 0 {
+            FL_ZRO
+        } else if value >> 15 == 1 {
+            FL_NEG
+        } else {
+            FL_POS
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        console::BufferConsole,
+        machine::{Exit, MachineBuilder},
+        testing::TestCase,
+    };
+
+    // polls the keyboard with interrupts enabled, so one becomes due in the
+    // middle of a block, and patches the instruction after its own store
+    const PROGRAM: &str = "
+        .ORIG x3000
+        LD R0, ENABLE
+        STI R0, KBSR
+        LD R1, COUNT
+LOOP    LEA R2, PATCH
+        LDR R4, R2, #0
+        ADD R4, R4, #1
+        STR R4, R2, #0
+PATCH   ADD R3, R3, #1
+        LDI R5, KBSR
+        ADD R3, R3, R5
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+ENABLE  .FILL x4000
+COUNT   .FILL #40
+KBSR    .FILL xFE00
+        .END
+";
+
+    const HANDLER: &str = "
+        .ORIG x0180
+        .FILL HANDLER
+HANDLER LDI R7, KBDR
+        ADD R3, R3, R7
+        RTI
+KBDR    .FILL xFE02
+        .END
+";
+
+    fn machine(engine: Engine) -> VmCPU {
+        let mut console = BufferConsole::default();
+        console.input.extend(b"lc3");
+        let mut machine = MachineBuilder::new()
+            .assemble(PROGRAM)
+            .unwrap()
+            .assemble(HANDLER)
+            .unwrap()
+            .console(console)
+            .quiet()
+            .build()
+            .into_cpu();
+        machine.set_engine(engine);
+        machine
+    }
+
+    #[test]
+    fn test_engines_agree() {
+        for budget in 0..400 {
+            let mut reference = machine(Engine::Interpreter);
+            let mut blocks = machine(Engine::Blocks);
+            let exit = reference.run(budget);
+            assert_eq!(blocks.run(budget), exit, "budget {}", budget);
+            assert_eq!(blocks.registers, reference.registers, "budget {}", budget);
+            assert_eq!(blocks.psr(), reference.psr());
+            assert_eq!(blocks.memory.words(), reference.memory.words());
+            assert_eq!(blocks.devices.save(), reference.devices.save());
+        }
+        let mut blocks = machine(Engine::Blocks);
+        assert_eq!(blocks.run(1000), Ok(Exit::Halted));
+        // every key was taken by the handler
+        assert_eq!(blocks.peek(0xFE00) & 0x8000, 0);
+    }
+
+    #[test]
+    fn test_golden_cases() {
+        for path in ["resources/golden/2048.case", "resources/golden/rogue.case"] {
+            let case = TestCase::load(path).unwrap();
+            let reference = case.run_with(Engine::Interpreter).unwrap();
+            let blocks = case.run_with(Engine::Blocks).unwrap();
+            assert_eq!(blocks.end, reference.end, "{}", path);
+            assert_eq!(blocks.instructions, reference.instructions, "{}", path);
+            assert_eq!(blocks.output, reference.output, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Shadow call stack
+//!
+//! LC-3 has no hardware stack, but JSR/JSRR (and TRAP into an OS routine)
+//! leave the return address in R7 and RET jumps back through it. Watching
+//! those instructions is enough to rebuild the chain of active subroutines
+//! and to notice when a RET does not go back where the call came from,
+//! usually because R7 was overwritten without being saved.
+
+use std::fmt;
+
+use crate::{
+    debug_info::SymbolTable,
+    instructions::{Instructions, JumpType},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Subroutine,
+    Trap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// address of the JSR/JSRR/TRAP
+    pub call_site: u16,
+    /// first instruction of the callee
+    pub target: u16,
+    pub return_address: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallEvent {
+    Call,
+    Trap,
+    Return,
+}
+
+impl CallEvent {
+    pub fn of(instruction: &Instructions) -> Option<Self> {
+        match instruction {
+            Instructions::JumpRegister(_) => Some(CallEvent::Call),
+            Instructions::Trap { .. } => Some(CallEvent::Trap),
+            Instructions::Jump(JumpType::Return) => Some(CallEvent::Return),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallDiagnostic {
+    /// RET went somewhere other than the return address of the innermost
+    /// call, usually because R7 was clobbered before RET
+    MismatchedReturn { pc: u16, expected: u16, actual: u16 },
+    /// RET with no call on the shadow stack
+    ReturnWithoutCall { pc: u16, actual: u16 },
+}
+
+impl fmt::Display for CallDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallDiagnostic::MismatchedReturn {
+                pc,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "RET at x{:04X} returned to x{:04X}, expected x{:04X} (R7 clobbered?)",
+                pc, actual, expected
+            ),
+            CallDiagnostic::ReturnWithoutCall { pc, actual } => write!(
+                f,
+                "RET at x{:04X} returned to x{:04X} without a matching call",
+                pc, actual
+            ),
+        }
+    }
+}
+
+/// How one instruction changed the stack, kept so reverse execution can
+/// undo it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallStackChange {
+    Pushed,
+    Popped(Vec<Frame>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    diagnostics: Vec<CallDiagnostic>,
+}
+
+/// One line of a backtrace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    pub address: u16,
+    /// label of the subroutine the frame is in, if known
+    pub function: Option<String>,
+    /// nearest label at or before `address` and the offset from it
+    pub location: Option<(String, u16)>,
+}
+
+impl fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x{:04X}", self.address)?;
+        if let Some(function) = &self.function {
+            write!(f, " in {}", function)?;
+        }
+        match &self.location {
+            Some((label, 0)) if Some(label) != self.function.as_ref() => {
+                write!(f, " ({})", label)
+            }
+            Some((label, offset)) if *offset != 0 => write!(f, " ({}+{})", label, offset),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl CallStack {
+    /// Entry points of the active subroutines, outermost first
+    pub fn targets(&self) -> Vec<u16> {
+        self.frames.iter().map(|frame| frame.target).collect()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn diagnostics(&self) -> &[CallDiagnostic] {
+        &self.diagnostics
+    }
+
+    /// Update the stack after the instruction at `pc` ran and moved the PC
+    /// to `next_pc`
+    pub fn observe(&mut self, pc: u16, event: CallEvent, next_pc: u16) -> Option<CallStackChange> {
+        let return_address = pc.wrapping_add(1);
+        let kind = match event {
+            CallEvent::Call => FrameKind::Subroutine,
+            // traps handled by the VM itself never leave the instruction
+            CallEvent::Trap if next_pc == return_address => return None,
+            CallEvent::Trap => FrameKind::Trap,
+            CallEvent::Return => return Some(self.pop(pc, next_pc)),
+        };
+
+        self.frames.push(Frame {
+            kind,
+            call_site: pc,
+            target: next_pc,
+            return_address,
+        });
+        Some(CallStackChange::Pushed)
+    }
+
+    fn pop(&mut self, pc: u16, next_pc: u16) -> CallStackChange {
+        let Some(innermost) = self.frames.last() else {
+            self.diagnostics.push(CallDiagnostic::ReturnWithoutCall {
+                pc,
+                actual: next_pc,
+            });
+            return CallStackChange::Popped(Vec::new());
+        };
+
+        if innermost.return_address != next_pc {
+            self.diagnostics.push(CallDiagnostic::MismatchedReturn {
+                pc,
+                expected: innermost.return_address,
+                actual: next_pc,
+            });
+            // returning to an outer caller unwinds everything in between
+            if let Some(index) = self
+                .frames
+                .iter()
+                .rposition(|frame| frame.return_address == next_pc)
+            {
+                return CallStackChange::Popped(self.frames.split_off(index));
+            }
+        }
+        CallStackChange::Popped(self.frames.pop().into_iter().collect())
+    }
+
+    pub fn undo(&mut self, change: CallStackChange) {
+        match change {
+            CallStackChange::Pushed => {
+                self.frames.pop();
+            }
+            CallStackChange::Popped(frames) => self.frames.extend(frames),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Frames from the innermost outwards, starting at `pc`
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> Vec<BacktraceFrame> {
+        let location = |address: u16| {
+            symbols
+                .nearest(address)
+                .map(|(label, start)| (label.to_string(), address - start))
+        };
+        let function = |index: usize| match index {
+            0 => None,
+            _ => symbols
+                .name_at(self.frames[index - 1].target)
+                .map(str::to_string),
+        };
+
+        // frame `i` of the shadow stack runs the function entered by call `i - 1`
+        let mut backtrace = vec![BacktraceFrame {
+            address: pc,
+            function: function(self.frames.len()),
+            location: location(pc),
+        }];
+        for index in (0..self.frames.len()).rev() {
+            let call_site = self.frames[index].call_site;
+            backtrace.push(BacktraceFrame {
+                address: call_site,
+                function: function(index),
+                location: location(call_site),
+            });
+        }
+        backtrace
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::{assembler, cpu::VmCPU, memory::Memory};
+
+    fn run(source: &str, steps: usize) -> (VmCPU, SymbolTable) {
+        let assembly = assembler::assemble(source).unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; 10], memory);
+        vm.console = Box::new(BufferConsole::default());
+        for _ in 0..steps {
+            vm.step();
+        }
+        (vm, assembly.symbols)
+    }
+
+    #[test]
+    fn test_backtrace_names_subroutines() {
+        let source = "
+        .ORIG x3000
+MAIN    JSR OUTER
+        HALT
+OUTER   ST R7, SAVE
+        LEA R1, INNER
+        JSRR R1
+        LD R7, SAVE
+        RET
+INNER   ADD R0, R0, #1
+        RET
+SAVE    .FILL #0
+        .END
+        ";
+        // stop on the first instruction of INNER
+        let (vm, symbols) = run(source, 4);
+        let pc = vm.registers[8];
+        assert_eq!(pc, 0x3007);
+
+        let backtrace: Vec<String> = vm
+            .call_stack
+            .backtrace(pc, &symbols)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            backtrace,
+            vec!["x3007 in INNER", "x3004 in OUTER (OUTER+2)", "x3000 (MAIN)",]
+        );
+    }
+
+    #[test]
+    fn test_clobbered_r7_is_reported() {
+        let source = "
+        .ORIG x3000
+        JSR OUTER
+        HALT
+OUTER   JSR INNER
+        RET
+INNER   RET
+        .END
+        ";
+        let (vm, _) = run(source, 4);
+
+        assert_eq!(
+            vm.call_stack.diagnostics(),
+            &[CallDiagnostic::MismatchedReturn {
+                pc: 0x3003,
+                expected: 0x3001,
+                actual: 0x3003,
+            }]
+        );
+    }
+}
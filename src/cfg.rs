@@ -0,0 +1,580 @@
+//! Static control flow graphs
+//!
+//! [`Cfg::build`] decodes a program without running it, following every
+//! path from the entry point and from each label in the symbol table, and
+//! splits the instructions it reaches into basic blocks, entered only at the
+//! top and left only at the bottom. Blocks end at BR, JMP, JSR, JSRR, RTI
+//! and HALT; JSR targets are the subroutines. Along the way it collects
+//! [`Finding`]s: program words no path reaches, paths that run into data,
+//! jumps through registers whose targets it cannot know, and subroutines
+//! with no way back to a RET.
+//!
+//! Data is what the line table of the source marks as `.FILL`, `.BLKW` or
+//! `.STRINGZ`. Without the source, words that make no sense as instructions
+//! count as data: the reserved opcode and BR with no condition, which is
+//! what small numbers and characters decode to.
+//!
+//! [`Cfg::dot`] draws the graph with Graphviz:
+//!
+//! ```text
+//! vm cfg game.obj game.dot && dot -Tsvg game.dot -o game.svg
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::{
+    debug_info::{DebugInfo, SymbolTable},
+    disassembler,
+    instructions::{Instructions, JumpRegisterType, JumpType},
+    trap::TrapType,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// on to the following instruction, also where a call returns to
+    Next,
+    /// a BR, taken or always
+    Branch,
+    /// JSR to a subroutine
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start: u16,
+    /// number of instructions
+    pub size: u16,
+    /// none after HALT, RET, RTI and JMP through a register
+    pub successors: Vec<Edge>,
+}
+
+impl Block {
+    /// Address of the last instruction
+    pub fn end(&self) -> u16 {
+        self.start.wrapping_add(self.size - 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finding {
+    /// program words from the first to the last address that no path
+    /// reaches
+    Unreachable(u16, u16),
+    /// a path runs into data: the address and the word there
+    DataAsCode(u16, u16),
+    /// JMP or JSRR through a register at this address, whose targets
+    /// have to be given as labels to be followed
+    IndirectJump(u16),
+    /// the subroutine entered here has no path to a RET
+    NoReturn(u16),
+}
+
+impl Finding {
+    pub fn address(&self) -> u16 {
+        match *self {
+            Finding::Unreachable(address, _)
+            | Finding::DataAsCode(address, _)
+            | Finding::IndirectJump(address)
+            | Finding::NoReturn(address) => address,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub entry: u16,
+    origin: u16,
+    words: Vec<u16>,
+    /// by first address
+    pub blocks: BTreeMap<u16, Block>,
+    /// JSR targets inside the program
+    pub subroutines: BTreeSet<u16>,
+    /// in address order
+    pub findings: Vec<Finding>,
+}
+
+/// Where control goes after `word` at `address`, and whether the block
+/// ends with it
+fn flow(word: u16, address: u16) -> (Vec<Edge>, bool) {
+    let next = address.wrapping_add(1);
+    let edge = |kind, target| Edge { kind, target };
+    match Instructions::from(word) {
+        Instructions::Branch {
+            pc_offset_9,
+            n,
+            z,
+            p,
+        } => {
+            let taken = edge(EdgeKind::Branch, next.wrapping_add(pc_offset_9));
+            match n && z && p {
+                true => (vec![taken], true),
+                false => (vec![taken, edge(EdgeKind::Next, next)], true),
+            }
+        }
+        Instructions::JumpRegister(JumpRegisterType::FromOffset { pc_offset_11 }) => {
+            let call = edge(EdgeKind::Call, next.wrapping_add(pc_offset_11));
+            (vec![call, edge(EdgeKind::Next, next)], true)
+        }
+        Instructions::JumpRegister(JumpRegisterType::FromRegister { .. }) => {
+            (vec![edge(EdgeKind::Next, next)], true)
+        }
+        Instructions::Jump(_) | Instructions::ReturnFromInterrupt => (Vec::new(), true),
+        Instructions::Trap { trap_vector }
+            if matches!(TrapType::try_from(trap_vector), Ok(TrapType::Halt)) =>
+        {
+            (Vec::new(), true)
+        }
+        _ => (vec![edge(EdgeKind::Next, next)], false),
+    }
+}
+
+fn is_indirect(word: u16) -> bool {
+    matches!(
+        Instructions::from(word),
+        Instructions::Jump(JumpType::BaseRegister(_))
+            | Instructions::JumpRegister(JumpRegisterType::FromRegister { .. })
+    )
+}
+
+fn is_return(word: u16) -> bool {
+    Instructions::from(word) == Instructions::Jump(JumpType::Return)
+}
+
+/// Whether the word at `address` holds data rather than an instruction
+fn is_data(word: u16, address: u16, info: &DebugInfo) -> bool {
+    match info.lines.entry_at(address) {
+        Some(entry) => !entry.is_code,
+        None => matches!(
+            Instructions::from(word),
+            Instructions::UnImplemented(_)
+                | Instructions::Branch {
+                    n: false,
+                    z: false,
+                    p: false,
+                    ..
+                }
+        ),
+    }
+}
+
+/// `address` by label, or label and offset
+fn location(symbols: &SymbolTable, address: u16) -> String {
+    match symbols.nearest(address) {
+        Some((label, start)) if start == address => label.to_string(),
+        Some((label, start)) => format!("{}+{}", label, address - start),
+        None => format!("x{:04X}", address),
+    }
+}
+
+impl Cfg {
+    /// Analyse the program of `words` loaded at `origin`, started at
+    /// `entry`
+    pub fn build(entry: u16, origin: u16, words: &[u16], info: &DebugInfo) -> Self {
+        let inside = |address: u16| (address.wrapping_sub(origin) as usize) < words.len();
+        let word = |address: u16| words[address.wrapping_sub(origin) as usize];
+
+        // labels are followed too, for code only reached through registers,
+        // but a label on data is no finding
+        let mut roots = vec![(entry, false)];
+        roots.extend(
+            info.symbols
+                .iter()
+                .filter(|(_, address)| inside(*address))
+                .map(|(_, address)| (address, true)),
+        );
+
+        let mut code = BTreeSet::new();
+        let mut leaders = BTreeSet::new();
+        let mut subroutines = BTreeSet::new();
+        let mut data = BTreeSet::new();
+        let mut indirect = BTreeSet::new();
+        let mut work: Vec<(u16, bool)> = roots.iter().rev().copied().collect();
+        while let Some((address, label)) = work.pop() {
+            if !inside(address) || code.contains(&address) {
+                continue;
+            }
+            if is_data(word(address), address, info) {
+                if !label {
+                    data.insert(address);
+                }
+                continue;
+            }
+            code.insert(address);
+            if is_indirect(word(address)) {
+                indirect.insert(address);
+            }
+            let (edges, ends) = flow(word(address), address);
+            for edge in edges {
+                if ends {
+                    leaders.insert(edge.target);
+                }
+                if edge.kind == EdgeKind::Call && inside(edge.target) {
+                    subroutines.insert(edge.target);
+                }
+                work.push((edge.target, false));
+            }
+        }
+        leaders.extend(roots.iter().map(|(address, _)| *address));
+
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.iter().filter(|address| code.contains(*address)) {
+            let mut address = start;
+            loop {
+                let (edges, ends) = flow(word(address), address);
+                let next = address.wrapping_add(1);
+                if ends || !code.contains(&next) || leaders.contains(&next) {
+                    let size = address.wrapping_sub(start) + 1;
+                    let block = Block {
+                        start,
+                        size,
+                        successors: edges,
+                    };
+                    blocks.insert(start, block);
+                    break;
+                }
+                address = next;
+            }
+        }
+
+        let mut cfg = Cfg {
+            entry,
+            origin,
+            words: words.to_vec(),
+            blocks,
+            subroutines: subroutines.clone(),
+            findings: Vec::new(),
+        };
+
+        let mut findings: Vec<Finding> = data
+            .into_iter()
+            .map(|address| Finding::DataAsCode(address, word(address)))
+            .collect();
+        findings.extend(indirect.into_iter().map(Finding::IndirectJump));
+        findings.extend(
+            subroutines
+                .into_iter()
+                .filter(|entry| !cfg.returns(*entry))
+                .map(Finding::NoReturn),
+        );
+        let mut unreachable: Option<(u16, u16)> = None;
+        for offset in 0..words.len() {
+            let address = origin.wrapping_add(offset as u16);
+            let dead = !code.contains(&address) && !is_data(word(address), address, info);
+            match (&mut unreachable, dead) {
+                (Some((_, end)), true) => *end = address,
+                (None, true) => unreachable = Some((address, address)),
+                (Some((start, end)), false) => {
+                    findings.push(Finding::Unreachable(*start, *end));
+                    unreachable = None;
+                }
+                (None, false) => {}
+            }
+        }
+        if let Some((start, end)) = unreachable {
+            findings.push(Finding::Unreachable(start, end));
+        }
+        findings.sort_by_key(Finding::address);
+        cfg.findings = findings;
+        cfg
+    }
+
+    fn word(&self, address: u16) -> u16 {
+        self.words[address.wrapping_sub(self.origin) as usize]
+    }
+
+    /// Whether a RET can be reached from the subroutine entered at `entry`
+    /// without going into the subroutines it calls
+    fn returns(&self, entry: u16) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut work = vec![entry];
+        while let Some(start) = work.pop() {
+            let Some(block) = self.blocks.get(&start) else {
+                continue;
+            };
+            if !seen.insert(start) {
+                continue;
+            }
+            if is_return(self.word(block.end())) {
+                return true;
+            }
+            work.extend(
+                block
+                    .successors
+                    .iter()
+                    .filter(|edge| edge.kind != EdgeKind::Call)
+                    .map(|edge| edge.target),
+            );
+        }
+        false
+    }
+
+    /// The blocks with their successors, then the findings
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut text = String::new();
+        writeln!(
+            text,
+            "{} blocks, {} subroutines, {} findings",
+            self.blocks.len(),
+            self.subroutines.len(),
+            self.findings.len()
+        )
+        .unwrap();
+
+        writeln!(text, "\nBlocks").unwrap();
+        for block in self.blocks.values() {
+            let successors: Vec<String> = block
+                .successors
+                .iter()
+                .map(|edge| {
+                    let target = location(symbols, edge.target);
+                    match edge.kind {
+                        EdgeKind::Next => target,
+                        EdgeKind::Branch => format!("{} (branch)", target),
+                        EdgeKind::Call => format!("{} (call)", target),
+                    }
+                })
+                .collect();
+            writeln!(
+                text,
+                "x{:04X}-x{:04X}  {:<16}  -> {}",
+                block.start,
+                block.end(),
+                location(symbols, block.start),
+                match successors.is_empty() {
+                    true => "end".to_string(),
+                    false => successors.join(", "),
+                }
+            )
+            .unwrap();
+        }
+
+        if !self.findings.is_empty() {
+            writeln!(text, "\nFindings").unwrap();
+        }
+        for finding in &self.findings {
+            let address = finding.address();
+            let at = location(symbols, address);
+            let line = match *finding {
+                Finding::Unreachable(start, end) => {
+                    format!("x{:04X}-x{:04X}  {} is unreachable", start, end, at)
+                }
+                Finding::DataAsCode(_, word) => {
+                    format!(
+                        "x{:04X}  data x{:04X} at {} reached as code",
+                        address, word, at
+                    )
+                }
+                Finding::IndirectJump(_) => format!(
+                    "x{:04X}  {} at {} needs its targets labelled",
+                    address,
+                    disassembler::disassemble(self.word(address), address, symbols),
+                    at
+                ),
+                Finding::NoReturn(_) => {
+                    format!("x{:04X}  subroutine {} never returns", address, at)
+                }
+            };
+            writeln!(text, "{}", line).unwrap();
+        }
+        text
+    }
+
+    /// Graphviz source: a box per block listing its instructions, dashed
+    /// edges for calls. Targets outside the blocks, like data or an
+    /// operating system routine, are plain text nodes.
+    pub fn dot(&self, symbols: &SymbolTable) -> String {
+        let node = |address: u16| format!("\"x{:04X}\"", address);
+        let mut text =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if let Some(name) = symbols.name_at(block.start) {
+                write!(label, "{}:\\l", name).unwrap();
+            }
+            for offset in 0..block.size {
+                let address = block.start.wrapping_add(offset);
+                let instruction = disassembler::disassemble(self.word(address), address, symbols);
+                write!(
+                    label,
+                    "x{:04X}  {}\\l",
+                    address,
+                    instruction.replace('"', "\\\"")
+                )
+                .unwrap();
+            }
+            let style = match block.start == self.entry {
+                true => ", penwidth=2",
+                false => "",
+            };
+            writeln!(
+                text,
+                "    {} [label=\"{}\"{}];",
+                node(block.start),
+                label,
+                style
+            )
+            .unwrap();
+        }
+
+        let mut outside = BTreeSet::new();
+        for block in self.blocks.values() {
+            let conditional = block.successors.len() > 1;
+            for edge in &block.successors {
+                if !self.blocks.contains_key(&edge.target) {
+                    outside.insert(edge.target);
+                }
+                let attributes = match edge.kind {
+                    EdgeKind::Call => " [style=dashed, label=\"call\"]",
+                    EdgeKind::Branch if conditional => " [label=\"taken\"]",
+                    _ => "",
+                };
+                writeln!(
+                    text,
+                    "    {} -> {}{};",
+                    node(block.start),
+                    node(edge.target),
+                    attributes
+                )
+                .unwrap();
+            }
+        }
+        for address in outside {
+            writeln!(
+                text,
+                "    {} [shape=plaintext, label=\"{}\"];",
+                node(address),
+                location(symbols, address)
+            )
+            .unwrap();
+        }
+        text.push_str("}\n");
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler;
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+MAIN    LEA R0, HELLO
+        JSR PRINT
+        JSR SPIN
+        LD R3, TABLE
+        JMP R3
+        ADD R1, R1, #1
+PRINT   PUTS
+        BRz SKIP
+        ADD R2, R2, #1
+SKIP    RET
+SPIN    BRnzp SPIN
+TABLE   .FILL DONE
+DONE    HALT
+HELLO   .STRINGZ \"hi\"
+        .END
+";
+
+    fn info(assembly: &assembler::Assembly) -> DebugInfo {
+        DebugInfo {
+            source: None,
+            symbols: assembly.symbols.clone(),
+            lines: assembly.lines.clone(),
+        }
+    }
+
+    #[test]
+    fn test_blocks_and_findings() {
+        let assembly = assembler::assemble(PROGRAM).unwrap();
+        let symbols = &assembly.symbols;
+        let address = |name| symbols.address_of(name).unwrap();
+        let cfg = Cfg::build(
+            assembly.origin,
+            assembly.origin,
+            &assembly.words,
+            &info(&assembly),
+        );
+
+        // MAIN is cut after each call
+        assert_eq!(cfg.blocks[&0x3000].size, 2);
+        assert_eq!(
+            cfg.blocks[&0x3000].successors,
+            [
+                Edge {
+                    kind: EdgeKind::Call,
+                    target: address("PRINT")
+                },
+                Edge {
+                    kind: EdgeKind::Next,
+                    target: 0x3002
+                }
+            ]
+        );
+        assert_eq!(cfg.blocks[&address("PRINT")].size, 2);
+        assert_eq!(cfg.blocks[&address("SKIP")].size, 1);
+        assert!(cfg.blocks[&address("DONE")].successors.is_empty());
+        assert_eq!(
+            cfg.subroutines,
+            BTreeSet::from([address("PRINT"), address("SPIN")])
+        );
+
+        // DONE is only reached through the label, the ADD after JMP R3 not
+        // at all
+        assert_eq!(
+            cfg.findings,
+            [
+                Finding::IndirectJump(0x3004),
+                Finding::Unreachable(0x3005, 0x3005),
+                Finding::NoReturn(address("SPIN")),
+            ]
+        );
+
+        let report = cfg.report(symbols);
+        assert!(report.contains("x3004  JMP R3 at MAIN+4 needs its targets labelled"));
+        assert!(report.contains("subroutine SPIN never returns"));
+
+        let dot = cfg.dot(symbols);
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("\"x3000\" -> \"x3006\" [style=dashed, label=\"call\"];"));
+        assert!(dot.contains("\"x3006\" -> \"x3009\" [label=\"taken\"];"));
+        assert!(dot.contains("PRINT:\\lx3006  PUTS\\lx3007  BRz SKIP\\l"));
+        assert!(dot.trim_end().ends_with('}'));
+    }
+
+    #[test]
+    fn test_data_without_source() {
+        // falls through into the string, and an .obj with only symbols
+        // still tells the characters from code
+        let assembly = assembler::assemble(
+            "
+        .ORIG x3000
+        LEA R0, TEXT
+        PUTS
+TEXT    .STRINGZ \"ok\"
+        .END
+",
+        )
+        .unwrap();
+        for info in [
+            info(&assembly),
+            DebugInfo {
+                symbols: assembly.symbols.clone(),
+                ..DebugInfo::default()
+            },
+        ] {
+            let cfg = Cfg::build(0x3000, 0x3000, &assembly.words, &info);
+            assert_eq!(cfg.findings, [Finding::DataAsCode(0x3002, b'o' as u16)]);
+            assert_eq!(cfg.blocks[&0x3000].size, 2);
+        }
+    }
+}
@@ -0,0 +1,659 @@
+//! Command line of the `vm` binary
+//!
+//! ```text
+//! vm run <program> [options]       run a program on the terminal
+//! vm trace <program> [options]     run, printing each instruction to stderr
+//! vm debug <program> [options]     line based debugger
+//! vm asm <source> [-o <obj>]       assemble to .obj and .sym
+//! vm disasm <obj> [--sym <file>]   list a program as assembly
+//! vm test <case>... [--update]     golden output tests
+//! vm diff <program> [options]      check the execution engines agree
+//! vm cfg <program> [dot]           control flow graph without running
+//! vm profile <program> [folded]    run, then show where the time went
+//! vm coverage <program> <lcov> [transcript...]
+//!                                  line coverage over input transcripts
+//! vm video <program> <every> [dir] show or save the framebuffer
+//! vm dap                           debug adapter on stdio
+//! ```
+//!
+//! `vm <program>` is short for `vm run <program>`. Programs are `.obj`
+//! files or `.asm` sources, which are assembled on the fly. The exit
+//! status tells how a run ended, see the `EXIT_` constants. [`USAGE`] lists
+//! the options.
+
+use std::{
+    fmt::Write as _,
+    fs, io,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use vm::{
+    assembler, console,
+    debug_info::{DebugInfo, SymbolTable},
+    disassembler,
+    error::Fault,
+    memory,
+    register::Registers,
+    unstable::{
+        cfg::Cfg,
+        coverage,
+        cpu::VmCPU,
+        debugger,
+        differential::Lockstep,
+        profile,
+        testing::{self, End, Script},
+        video,
+    },
+    Engine, ExecutionPolicy, FileSystem, MachineBuilder, StepResult,
+};
+
+use crate::{dap, repl::Repl};
+
+/// The program executed HALT
+pub const EXIT_HALTED: i32 = 0;
+/// A file could not be read or written, or a test failed
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+/// An instruction or trap the VM cannot execute
+pub const EXIT_FAULT: i32 = 3;
+/// `--max-instructions` ran out before the program halted
+pub const EXIT_BUDGET: i32 = 4;
+/// The program waits for a key and no more input will come
+pub const EXIT_NO_INPUT: i32 = 5;
+
+pub const USAGE: &str = "\
+usage: vm <command> [options]
+
+commands:
+  run <program>             run a program, the default with just a program
+  trace <program>           run, printing each instruction to stderr
+  debug <program>           line based debugger, `help` lists its commands
+  asm <source> [-o <obj>]   assemble to .obj and .sym
+  disasm <obj>              list a program as assembly
+  test <case>... [--update] golden output tests
+  diff <program>            run the execution engines in lockstep
+  cfg <program> [dot]       control flow graph and what looks wrong in it
+  profile <program> [folded]
+  coverage <program> <lcov> [transcript...]
+  video <program> <every> [dir]
+  dap                       debug adapter on stdio
+
+options:
+  --entry <loc>             start at an address like x3000 or a label
+  --max-instructions <n>    stop after n instructions
+  --input-script <file>     type a keystroke script instead of the keyboard
+  --os-image <obj>          load an operating system and run traps through it
+  --sym <file>              symbol table for labels
+  --seed <n>                seed the random number device
+  --quiet                   leave out the \"Exiting\" line of HALT
+  --engine <name>           interpreter, or blocks for faster long runs
+  --device-page <policy>    executing the device page: wrap, fault or acv
+  --files <dir>             let the file traps x30-x34 use the files in dir
+  --png                     video frames as PNG rather than PPM files
+
+exit status: 0 halted, 1 error, 2 usage, 3 fault, 4 instruction budget
+exhausted, 5 waiting for input that will not come
+";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub entry: Option<String>,
+    pub max_instructions: Option<u64>,
+    pub input_script: Option<PathBuf>,
+    pub os_image: Option<PathBuf>,
+    pub sym: Option<PathBuf>,
+    pub seed: Option<u64>,
+    pub quiet: bool,
+    /// `video` writes PNG files
+    pub png: bool,
+    pub engine: Engine,
+    pub device_page: ExecutionPolicy,
+    /// sandbox of the file traps
+    pub files: Option<PathBuf>,
+    /// `test --update`
+    pub update: bool,
+    /// `asm -o`
+    pub output: Option<PathBuf>,
+    /// everything that is not an option, the command first
+    pub arguments: Vec<String>,
+}
+
+/// A program as read by [`Options::read`], to build machines from
+struct Image {
+    origin: u16,
+    words: Vec<u16>,
+    os_image: Option<(u16, Vec<u16>)>,
+    entry: Option<u16>,
+}
+
+impl Options {
+    /// Options may come anywhere, as `--name value` or `--name=value`
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                options.arguments.push(arg);
+                continue;
+            }
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+            let number = |value: String| {
+                value
+                    .parse()
+                    .map_err(|_| format!("{} needs a number, not `{}`", name, value))
+            };
+            match name.as_str() {
+                "--entry" => options.entry = Some(value()?),
+                "--max-instructions" => options.max_instructions = Some(number(value()?)?),
+                "--input-script" => options.input_script = Some(value()?.into()),
+                "--os-image" => options.os_image = Some(value()?.into()),
+                "--sym" => options.sym = Some(value()?.into()),
+                "--files" => options.files = Some(value()?.into()),
+                "--seed" => options.seed = Some(number(value()?)?),
+                "-o" | "--output" => options.output = Some(value()?.into()),
+                "--quiet" | "-q" => options.quiet = true,
+                "--png" => options.png = true,
+                "--engine" => {
+                    options.engine = match value()?.as_str() {
+                        "interpreter" => Engine::Interpreter,
+                        "blocks" => Engine::Blocks,
+                        other => {
+                            return Err(format!(
+                                "--engine is interpreter or blocks, not `{}`",
+                                other
+                            ))
+                        }
+                    }
+                }
+                "--device-page" => {
+                    options.device_page = match value()?.as_str() {
+                        "wrap" => ExecutionPolicy::Wrap,
+                        "fault" => ExecutionPolicy::Fault,
+                        "acv" => ExecutionPolicy::AccessViolation,
+                        other => {
+                            return Err(format!(
+                                "--device-page is wrap, fault or acv, not `{}`",
+                                other
+                            ))
+                        }
+                    }
+                }
+                "--update" => options.update = true,
+                "--help" | "-h" => options.arguments.insert(0, "help".to_string()),
+                _ => return Err(format!("unknown option {}", name)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Load `program` and apply every option that shapes the machine
+    fn load(&self, program: &str) -> io::Result<(VmCPU, DebugInfo)> {
+        let (image, debug_info) = self.read(program)?;
+        let files = self.files.as_ref().map(FileSystem::new).transpose()?;
+        Ok((self.machine(&image, files), debug_info))
+    }
+
+    /// Read `program`, the operating system image and the entry point
+    fn read(&self, program: &str) -> io::Result<(Image, DebugInfo)> {
+        let (origin, words, debug_info) =
+            debugger::read_program(Path::new(program), self.sym.as_deref())?;
+        let os_image = match &self.os_image {
+            Some(os_image) => Some(memory::parse_obj(&fs::read(os_image)?)?),
+            None => None,
+        };
+        let entry = match &self.entry {
+            Some(entry) => Some(debug_info.symbols.resolve(entry).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--entry: no address or label `{}`", entry),
+                )
+            })?),
+            None => None,
+        };
+        let image = Image {
+            origin,
+            words,
+            os_image,
+            entry,
+        };
+        Ok((image, debug_info))
+    }
+
+    /// The machine on `image` with what [`Options::load`] read from files
+    /// already in hand, so it cannot fail
+    fn machine(&self, image: &Image, files: Option<FileSystem>) -> VmCPU {
+        let mut builder = MachineBuilder::new()
+            .image(image.origin, &image.words)
+            .engine(self.engine)
+            .execution_policy(self.device_page);
+        if let Some((origin, words)) = &image.os_image {
+            builder = builder.operating_system(*origin, words);
+        }
+        if let Some(entry) = image.entry {
+            builder = builder.entry(entry);
+        }
+        if let Some(seed) = self.seed {
+            builder = builder.seed(seed);
+        }
+        if let Some(files) = files {
+            builder = builder.files(files);
+        }
+        if self.quiet {
+            builder = builder.quiet();
+        }
+        builder.build().into_cpu()
+    }
+}
+
+/// Run the command line `args`, without the program name, and return the
+/// exit status
+pub fn main(args: Vec<String>) -> i32 {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let arguments: Vec<&str> = options.arguments.iter().map(String::as_str).collect();
+    let result = match arguments.as_slice() {
+        ["help", ..] => {
+            print!("{}", USAGE);
+            Ok(EXIT_HALTED)
+        }
+        ["run", program] => run(&options, program, false),
+        ["trace", program] => run(&options, program, true),
+        ["debug", program] => debug(&options, program),
+        ["asm", source] => asm(&options, source),
+        ["disasm", program] => disasm(&options, program),
+        ["test", cases @ ..] if !cases.is_empty() => test(cases, options.update),
+        ["diff", program] => diff(&options, program),
+        ["cfg", program, dot @ ..] if dot.len() <= 1 => {
+            cfg(&options, program, dot.first().copied())
+        }
+        ["profile", program, folded @ ..] if folded.len() <= 1 => {
+            profile(&options, program, folded.first().copied())
+        }
+        ["coverage", program, lcov, transcripts @ ..] => {
+            coverage(&options, program, lcov, transcripts)
+        }
+        ["video", program, every, directory @ ..] if directory.len() <= 1 => match every.parse() {
+            Ok(every) => video(&options, program, every, directory.first().copied()),
+            Err(_) => {
+                eprintln!("video: <every> is a number of instructions");
+                return EXIT_USAGE;
+            }
+        },
+        ["dap"] => dap::run_stdio().map(|_| EXIT_HALTED),
+        [program] if is_program(program) => run(&options, program, false),
+        _ => {
+            eprint!("{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("vm: {}", e);
+        EXIT_ERROR
+    })
+}
+
+fn is_program(path: &str) -> bool {
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+    matches!(extension, Some(ext) if ext.eq_ignore_ascii_case("obj") || ext.eq_ignore_ascii_case("asm"))
+}
+
+fn fault(fault: Fault) -> i32 {
+    eprintln!("fault: {}", fault);
+    EXIT_FAULT
+}
+
+/// One instruction, printing it and the registers it changed to stderr
+fn trace_step(vm: &mut VmCPU, symbols: &SymbolTable) -> StepResult {
+    let pc = vm.read_register(Registers::ProgramCounter);
+    let instruction = disassembler::disassemble(vm.peek(pc), pc, symbols);
+    let before = vm.registers;
+    let result = vm.step();
+
+    let mut line = format!("x{:04X}  {:<24}", pc, instruction);
+    let general = vm.registers.iter().zip(&before).take(8);
+    for (index, (after, before)) in general.enumerate() {
+        if after != before {
+            write!(line, " R{}=x{:04X}", index, after).unwrap();
+        }
+    }
+    eprintln!("{}", line.trim_end());
+    result
+}
+
+fn run(options: &Options, program: &str, trace: bool) -> io::Result<i32> {
+    let (mut vm, debug_info) = options.load(program)?;
+    let budget = options.max_instructions.unwrap_or(u64::MAX);
+    let step = |vm: &mut VmCPU, limit| match trace {
+        true => (trace_step(vm, &debug_info.symbols), 1),
+        false => vm.advance(limit),
+    };
+
+    if let Some(path) = &options.input_script {
+        let script = Script::parse(&fs::read_to_string(path)?)?;
+        let run = testing::type_script(&mut vm, &script, budget, None, step);
+        io::stdout().write_all(&run.output)?;
+        return Ok(match run.end {
+            End::Halted => EXIT_HALTED,
+            End::WaitingForInput | End::AfterInput => EXIT_NO_INPUT,
+            End::OutOfBudget => EXIT_BUDGET,
+            End::Fault(error) => fault(error),
+        });
+    }
+
+    // single keys without Enter until the VM is dropped
+    vm.console = Box::new(console::StdioConsole::interactive()?);
+    let mut instructions = 0;
+    while instructions < budget {
+        let (result, count) = step(&mut vm, budget - instructions);
+        instructions += count;
+        match result {
+            StepResult::Continue => {}
+            StepResult::Halted => return Ok(EXIT_HALTED),
+            // stdin is closed
+            StepResult::WaitingForInput => return Ok(EXIT_NO_INPUT),
+            StepResult::Fault(error) => return Ok(fault(error)),
+        }
+    }
+    Ok(EXIT_BUDGET)
+}
+
+fn diff(options: &Options, program: &str) -> io::Result<i32> {
+    // every machine starts from what is read here, whatever happens to the
+    // files
+    let (image, _) = options.read(program)?;
+    let files = options.files.as_ref().map(FileSystem::new).transpose()?;
+    let machine = || options.machine(&image, files.as_ref().map(FileSystem::fresh));
+    let keys = match &options.input_script {
+        Some(path) => Script::parse(&fs::read_to_string(path)?)?
+            .keys
+            .iter()
+            .map(|(_, key)| *key)
+            .collect(),
+        None => Vec::new(),
+    };
+    let lockstep = Lockstep {
+        every: 1000,
+        budget: options.max_instructions.unwrap_or(testing::DEFAULT_BUDGET),
+        keys,
+    };
+    match lockstep.engines(machine) {
+        Ok(instructions) => {
+            println!("the engines agree over {} instructions", instructions);
+            Ok(EXIT_HALTED)
+        }
+        Err(divergence) => {
+            print!("{}", divergence);
+            Ok(EXIT_ERROR)
+        }
+    }
+}
+
+fn debug(options: &Options, program: &str) -> io::Result<i32> {
+    let (vm, debug_info) = options.load(program)?;
+    let budget = options.max_instructions.unwrap_or(testing::DEFAULT_BUDGET);
+    let mut repl = Repl::new(vm, debug_info, budget as usize);
+    repl.run(io::stdin().lock(), io::stdout())?;
+    Ok(EXIT_HALTED)
+}
+
+fn asm(options: &Options, source: &str) -> io::Result<i32> {
+    let assembly = match assembler::assemble(&fs::read_to_string(source)?) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            eprintln!("{}:{}", source, e);
+            return Ok(EXIT_ERROR);
+        }
+    };
+    let obj = options
+        .output
+        .clone()
+        .unwrap_or_else(|| Path::new(source).with_extension("obj"));
+    fs::write(&obj, assembly.to_obj_bytes())?;
+    fs::write(obj.with_extension("sym"), assembly.symbols.to_sym_string())?;
+    Ok(EXIT_HALTED)
+}
+
+fn disasm(options: &Options, program: &str) -> io::Result<i32> {
+    let (origin, words) = memory::parse_obj(&fs::read(program)?)?;
+    let sym = options
+        .sym
+        .clone()
+        .or_else(|| Some(Path::new(program).with_extension("sym")).filter(|p| p.exists()));
+    let symbols = match sym {
+        Some(sym) => SymbolTable::load_from_file(sym)?,
+        None => SymbolTable::default(),
+    };
+    print!("{}", disassembler::listing(origin, &words, &symbols));
+    Ok(EXIT_HALTED)
+}
+
+fn test(cases: &[&str], update: bool) -> io::Result<i32> {
+    let mut failures = 0;
+    for path in cases {
+        let case = testing::TestCase::load(path)?;
+        let outcome = case.check(update)?;
+        match &outcome {
+            testing::Outcome::Passed => println!("ok      {}", case.name),
+            testing::Outcome::Updated => println!("updated {}", case.name),
+            testing::Outcome::NoGolden => println!(
+                "FAILED  {}: no golden file {}, run with --update",
+                case.name,
+                case.golden.display()
+            ),
+            testing::Outcome::OutOfBudget(run) => println!(
+                "FAILED  {}: still running after {} instructions",
+                case.name, run.instructions
+            ),
+            testing::Outcome::Fault(fault) => println!("FAILED  {}: {}", case.name, fault),
+            testing::Outcome::Failed(diff) => {
+                println!(
+                    "FAILED  {}: output differs from {}",
+                    case.name,
+                    case.golden.display()
+                );
+                print!("{}", diff);
+            }
+        }
+        if !outcome.passed() {
+            failures += 1;
+        }
+    }
+    println!("{} passed, {} failed", cases.len() - failures, failures);
+    Ok(if failures == 0 {
+        EXIT_HALTED
+    } else {
+        EXIT_ERROR
+    })
+}
+
+/// Prints a profile to stderr on exit and optionally writes collapsed
+/// stacks for flamegraph tools
+fn profile(options: &Options, program: &str, folded: Option<&str>) -> io::Result<i32> {
+    let (mut vm, debug_info) = options.load(program)?;
+    let entry = vm.read_register(Registers::ProgramCounter);
+
+    vm.console = Box::new(console::StdioConsole::interactive()?);
+    vm.profiler = Some(profile::Profiler::new(entry));
+    let status = match vm.execute() {
+        Ok(()) => EXIT_HALTED,
+        Err(error) => fault(error),
+    };
+
+    let profiler = vm.profiler.take().unwrap();
+    drop(vm);
+    eprint!("{}", profiler.report(&debug_info.symbols, 20));
+    if let Some(folded) = folded {
+        fs::write(folded, profiler.collapsed_stacks(&debug_info.symbols))?;
+    }
+    Ok(status)
+}
+
+/// Prints the control flow graph of a program and what the analysis
+/// found, and optionally writes the graph as Graphviz source
+fn cfg(options: &Options, program: &str, dot: Option<&str>) -> io::Result<i32> {
+    let (image, debug_info) = options.read(program)?;
+    let entry = image.entry.unwrap_or(image.origin);
+
+    let graph = Cfg::build(entry, image.origin, &image.words, &debug_info);
+    print!("{}", graph.report(&debug_info.symbols));
+    if let Some(dot) = dot {
+        fs::write(dot, graph.dot(&debug_info.symbols))?;
+    }
+    Ok(EXIT_HALTED)
+}
+
+/// Shows the framebuffer every `every` instructions on the terminal, or
+/// writes it to `directory` as PPM or, with `--png`, PNG files
+fn video(options: &Options, program: &str, every: u64, directory: Option<&str>) -> io::Result<i32> {
+    let (mut vm, _) = options.load(program)?;
+    let sink = match directory {
+        Some(directory) => {
+            fs::create_dir_all(directory)?;
+            match options.png {
+                true => video::FrameSink::Png(directory.into()),
+                false => video::FrameSink::Ppm(directory.into()),
+            }
+        }
+        None => {
+            // clear the screen once, frames are drawn from the top left
+            print!("\x1b[2J");
+            video::FrameSink::Ansi(Box::new(io::stdout()))
+        }
+    };
+
+    vm.console = Box::new(console::StdioConsole::interactive()?);
+    vm.frames = Some(video::FrameRecorder::new(every, sink));
+    let status = match vm.execute() {
+        Ok(()) => EXIT_HALTED,
+        Err(error) => fault(error),
+    };
+    if let Some(error) = vm.frames.as_ref().and_then(video::FrameRecorder::error) {
+        eprintln!("vm: recording stopped: {}", error);
+        return Ok(EXIT_ERROR);
+    }
+    Ok(status)
+}
+
+// instructions per transcript, for programs that poll KBSR forever
+const TRANSCRIPT_LIMIT: usize = 10_000_000;
+
+/// Runs the program once per input transcript and reports the combined
+/// coverage
+fn coverage(options: &Options, program: &str, lcov: &str, transcripts: &[&str]) -> io::Result<i32> {
+    let mut total = coverage::Coverage::default();
+    let mut image = Vec::new();
+    let mut debug_info = Default::default();
+
+    // without transcripts run once with no input
+    let inputs: Vec<Vec<u8>> = match transcripts {
+        [] => vec![Vec::new()],
+        _ => transcripts
+            .iter()
+            .map(fs::read)
+            .collect::<io::Result<_>>()?,
+    };
+    for input in inputs {
+        let (mut vm, info) = options.load(program)?;
+        image = vm.memory.words().to_vec();
+        debug_info = info;
+
+        // no key ready once the transcript runs out
+        vm.console = Box::new(console::BufferConsole::new(&input));
+        vm.coverage = Some(coverage::Coverage::default());
+        for _ in 0..TRANSCRIPT_LIMIT {
+            if vm.step() != StepResult::Continue {
+                break;
+            }
+        }
+        total.merge(vm.coverage.as_ref().unwrap());
+    }
+
+    match total.lcov(&debug_info, &image) {
+        Some(text) => fs::write(lcov, text)?,
+        None => eprintln!("no source for {}, skipping LCOV", program),
+    }
+    print!("{}", total.annotated_listing(&debug_info)?);
+    Ok(EXIT_HALTED)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_options() {
+        let options = Options::parse(args(
+            "run --seed=7 game.obj --entry START --quiet --max-instructions 9 --engine blocks \
+             --device-page=fault --files sandbox --png",
+        ))
+        .unwrap();
+        assert_eq!(options.arguments, ["run", "game.obj"]);
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.entry.as_deref(), Some("START"));
+        assert_eq!(options.max_instructions, Some(9));
+        assert!(options.quiet);
+        assert_eq!(options.engine, Engine::Blocks);
+        assert_eq!(options.device_page, ExecutionPolicy::Fault);
+        assert_eq!(options.files, Some(PathBuf::from("sandbox")));
+        assert!(options.png);
+
+        assert!(Options::parse(args("run --seed x")).is_err());
+        assert!(Options::parse(args("run --sym")).is_err());
+        assert!(Options::parse(args("run --colour")).is_err());
+        assert!(Options::parse(args("run --engine jit")).is_err());
+        assert!(Options::parse(args("run --device-page trap")).is_err());
+    }
+
+    #[test]
+    fn test_os_image_traps() {
+        // a HALT routine that stops the machine through MCR
+        let os = assembler::assemble(
+            "
+        .ORIG x0025
+        .FILL ROUTINE
+ROUTINE AND R0, R0, #0
+        STI R0, MCR
+MCR     .FILL xFFFE
+        .END
+",
+        )
+        .unwrap();
+        let directory = std::env::temp_dir().join(format!("cli-os-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let image = directory.join("os.obj");
+        fs::write(&image, os.to_obj_bytes()).unwrap();
+        let program = directory.join("program.asm");
+        fs::write(&program, ".ORIG x3000\nADD R1, R1, #2\nHALT\n.END\n").unwrap();
+
+        let options = Options {
+            os_image: Some(image),
+            ..Default::default()
+        };
+        let (mut vm, _) = options.load(&program.to_string_lossy()).unwrap();
+        vm.console = Box::new(console::BufferConsole::default());
+        vm.execute().unwrap();
+        assert_eq!(vm.registers[1], 2);
+        // R7 holds the return address of the TRAP
+        assert_eq!(vm.registers[7], 0x3002);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+//! ISA conformance tests
+//!
+//! Every word has to decode the way the ISA encoding tables say, and single
+//! instructions have to do what the LC-3 reference says, checked on hand
+//! picked edge cases and on random instructions against [`Model`]. Both are
+//! straight transcriptions of the ISA tables that share no code with the
+//! decoder or the CPU.
+
+use crate::{
+    cpu::{StepResult, VmCPU, FL_NEG, FL_POS, FL_ZRO, PSR_USER},
+    debug_info::SymbolTable,
+    devices::{Random, DEVICE_PAGE},
+    disassembler,
+    instructions::{Instructions, JumpRegisterType, JumpType, LoadType},
+    machine::MachineBuilder,
+};
+
+const PC: usize = 8;
+const CONDITION: usize = 9;
+
+/// Bits [high:low] of `word`
+fn field(word: u16, high: u16, low: u16) -> u16 {
+    (word >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+/// Bits [high:0] of `word` sign extended to 16 bits
+fn sext(word: u16, high: u16) -> u16 {
+    let shift = 15 - high;
+    (((word << shift) as i16) >> shift) as u16
+}
+
+fn condition(value: u16) -> u16 {
+    match value {
+        0 => FL_ZRO,
+        value if value & 0x8000 != 0 => FL_NEG,
+        _ => FL_POS,
+    }
+}
+
+/// The architectural state one user mode instruction can change
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Model {
+    registers: [u16; 8],
+    pc: u16,
+    condition: u16,
+    memory: Vec<u16>,
+}
+
+impl Model {
+    /// RAM only, `None` on the device page
+    fn read(&self, address: u16) -> Option<u16> {
+        (address < DEVICE_PAGE).then(|| self.memory[address as usize])
+    }
+
+    fn write(&mut self, address: u16, value: u16) -> Option<()> {
+        (address < DEVICE_PAGE).then(|| self.memory[address as usize] = value)
+    }
+
+    fn set(&mut self, register: u16, value: u16) {
+        self.registers[register as usize] = value;
+        self.condition = condition(value);
+    }
+
+    /// Execute the instruction at the PC, `None` for what the model leaves
+    /// out: TRAP, RTI, the reserved opcode and device page accesses
+    fn step(&mut self) -> Option<()> {
+        let word = self.read(self.pc)?;
+        self.pc = self.pc.wrapping_add(1);
+        let r = |high: u16| field(word, high, high - 2);
+        let value = |register: u16| self.registers[register as usize];
+        match word >> 12 {
+            0b0000 => {
+                if field(word, 11, 9) & self.condition != 0 {
+                    self.pc = self.pc.wrapping_add(sext(word, 8));
+                }
+            }
+            0b0001 | 0b0101 => {
+                let operand = match field(word, 5, 5) {
+                    1 => sext(word, 4),
+                    _ => value(r(2)),
+                };
+                let result = match word >> 12 {
+                    0b0001 => value(r(8)).wrapping_add(operand),
+                    _ => value(r(8)) & operand,
+                };
+                self.set(r(11), result);
+            }
+            0b0010 => {
+                let loaded = self.read(self.pc.wrapping_add(sext(word, 8)))?;
+                self.set(r(11), loaded);
+            }
+            0b0011 => self.write(self.pc.wrapping_add(sext(word, 8)), value(r(11)))?,
+            0b0100 => {
+                let target = match field(word, 11, 11) {
+                    1 => self.pc.wrapping_add(sext(word, 10)),
+                    _ => value(r(8)),
+                };
+                self.registers[7] = self.pc;
+                self.pc = target;
+            }
+            0b0110 => {
+                let loaded = self.read(value(r(8)).wrapping_add(sext(word, 5)))?;
+                self.set(r(11), loaded);
+            }
+            0b0111 => self.write(value(r(8)).wrapping_add(sext(word, 5)), value(r(11)))?,
+            0b1001 => self.set(r(11), !value(r(8))),
+            0b1010 => {
+                let address = self.read(self.pc.wrapping_add(sext(word, 8)))?;
+                let loaded = self.read(address)?;
+                self.set(r(11), loaded);
+            }
+            0b1011 => {
+                let address = self.read(self.pc.wrapping_add(sext(word, 8)))?;
+                self.write(address, value(r(11)))?;
+            }
+            0b1100 => self.pc = value(r(8)),
+            0b1110 => {
+                // the 2nd edition ISA, which this machine follows, sets the
+                // condition codes on LEA
+                let address = self.pc.wrapping_add(sext(word, 8));
+                self.set(r(11), address);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// A machine in the state of `model`
+fn machine(model: &Model) -> VmCPU {
+    let mut machine = MachineBuilder::new()
+        .image(0, &model.memory)
+        .entry(model.pc)
+        .psr(PSR_USER | model.condition)
+        .build()
+        .into_cpu();
+    machine.registers[..8].copy_from_slice(&model.registers);
+    machine
+}
+
+fn assert_conforms(before: &Model, after: &Model) {
+    let mut vm = machine(before);
+    let word = before.memory[before.pc as usize];
+    let context = format!(
+        "x{:04X} {} from {:X?}",
+        word,
+        disassembler::disassemble(word, before.pc, &SymbolTable::default()),
+        before.registers
+    );
+    assert_eq!(vm.step(), StepResult::Continue, "{}", context);
+    assert_eq!(vm.registers[..8], after.registers, "{}", context);
+    assert_eq!(vm.registers[PC], after.pc, "PC {}", context);
+    assert_eq!(vm.registers[CONDITION], after.condition, "CC {}", context);
+    assert!(vm.memory.words() == &after.memory[..], "memory {}", context);
+}
+
+/// One instruction at x3000 with R1 = `r1`, R2 = `r2` and the condition
+/// codes at `condition`, followed by what [`Model`] makes of it
+fn case(word: u16, r1: u16, r2: u16, condition: u16) -> (Model, Model) {
+    let mut memory = vec![0; 1 << 16];
+    memory[0x3000] = word;
+    let before = Model {
+        registers: [0, r1, r2, 0, 0, 0, 0, 0],
+        pc: 0x3000,
+        condition,
+        memory,
+    };
+    let mut after = before.clone();
+    after.step().expect("instruction outside the model");
+    (before, after)
+}
+
+/// What the ISA encoding tables make of `word`, field by field
+fn decode(word: u16) -> Instructions {
+    let r = |high: u16| field(word, high, high - 2);
+    let operand = || match field(word, 5, 5) {
+        1 => LoadType::Immediate {
+            value: sext(word, 4),
+        },
+        _ => LoadType::Register { src_register: r(2) },
+    };
+    match word >> 12 {
+        0b0000 => Instructions::Branch {
+            pc_offset_9: sext(word, 8),
+            n: field(word, 11, 11) == 1,
+            z: field(word, 10, 10) == 1,
+            p: field(word, 9, 9) == 1,
+        },
+        0b0001 => Instructions::Add {
+            dest_register: r(11),
+            src_register: r(8),
+            add_type: operand(),
+        },
+        0b0101 => Instructions::And {
+            dest_register: r(11),
+            src_register: r(8),
+            add_type: operand(),
+        },
+        0b0010 => Instructions::LoadDirect {
+            pc_offset_9: sext(word, 8),
+            dest_register: r(11),
+        },
+        0b0011 => Instructions::StoreDirect {
+            pc_offset_9: sext(word, 8),
+            src_register: r(11),
+        },
+        0b0100 if field(word, 11, 11) == 1 => {
+            Instructions::JumpRegister(JumpRegisterType::FromOffset {
+                pc_offset_11: sext(word, 10),
+            })
+        }
+        0b0100 => Instructions::JumpRegister(JumpRegisterType::FromRegister {
+            base_register: r(8),
+        }),
+        0b0110 => Instructions::LoadRegister {
+            offset6: sext(word, 5),
+            base_register: r(8),
+            dest_register: r(11),
+        },
+        0b0111 => Instructions::StoreRegister {
+            offset6: sext(word, 5),
+            base_register: r(8),
+            src_register: r(11),
+        },
+        0b1000 => Instructions::ReturnFromInterrupt,
+        0b1001 => Instructions::Not {
+            dest_register: r(11),
+            src_register: r(8),
+        },
+        0b1010 => Instructions::LoadIndirect {
+            pc_offset_9: sext(word, 8),
+            dest_register: r(11),
+        },
+        0b1011 => Instructions::StoreIndirect {
+            pc_offset_9: sext(word, 8),
+            src_register: r(11),
+        },
+        // RET is JMP R7
+        0b1100 if r(8) == 7 => Instructions::Jump(JumpType::Return),
+        0b1100 => Instructions::Jump(JumpType::BaseRegister(r(8))),
+        0b1101 => Instructions::UnImplemented(0b1101),
+        0b1110 => Instructions::LoadEffectiveAddress {
+            pc_offset_9: sext(word, 8),
+            dest_register: r(11),
+        },
+        _ => Instructions::Trap {
+            trap_vector: field(word, 7, 0),
+        },
+    }
+}
+
+#[test]
+fn test_every_word_decodes() {
+    let symbols = SymbolTable::default();
+    for word in 0..=u16::MAX {
+        assert_eq!(Instructions::from(word), decode(word), "x{:04X}", word);
+        assert!(!disassembler::disassemble(word, 0x3000, &symbols).is_empty());
+    }
+}
+
+#[test]
+fn test_add_edges() {
+    // ADD R0, R0, #-16 and #15, then ADD R1, R1, R2 at the ends of the range
+    let (before, after) = case(0x1030, 0, 0, FL_ZRO);
+    assert_eq!((after.registers[0], after.condition), (0xFFF0, FL_NEG));
+    assert_conforms(&before, &after);
+    let (before, after) = case(0x102F, 0, 0, FL_ZRO);
+    assert_eq!((after.registers[0], after.condition), (15, FL_POS));
+    assert_conforms(&before, &after);
+
+    for (r1, r2, sum, flag) in [
+        (0x7FFF, 1, 0x8000, FL_NEG),
+        (0xFFFF, 1, 0, FL_ZRO),
+        (0x8000, 0x8000, 0, FL_ZRO),
+        (0xFFFF, 0xFFFF, 0xFFFE, FL_NEG),
+    ] {
+        let (before, after) = case(0x1242, r1, r2, FL_ZRO);
+        assert_eq!((after.registers[1], after.condition), (sum, flag));
+        assert_conforms(&before, &after);
+    }
+}
+
+#[test]
+fn test_logic() {
+    // AND R1, R1, R2; AND R1, R1, #15, #-1 and #-16; NOT R1, R1
+    for word in [0x5242, 0x526F, 0x527F, 0x5270, 0x927F] {
+        for (r1, r2) in [(0xF0F0, 0x0FF0), (0xFFFF, 0x8000), (0, 0xFFFF), (0x1234, 0)] {
+            let (before, after) = case(word, r1, r2, FL_ZRO);
+            assert_conforms(&before, &after);
+        }
+    }
+}
+
+#[test]
+fn test_branch_conditions() {
+    for nzp in 0..8 {
+        // BR with offset #5
+        let word = nzp << 9 | 5;
+        for flag in [FL_NEG, FL_ZRO, FL_POS] {
+            let (before, after) = case(word, 0, 0, flag);
+            // nzp = 000 never branches, nzp = 111 always does
+            let taken = nzp & flag != 0;
+            assert_eq!(after.pc, if taken { 0x3006 } else { 0x3001 });
+            assert_conforms(&before, &after);
+        }
+    }
+}
+
+#[test]
+fn test_offsets_sign_extend() {
+    // LD R0 and LEA R0 at the ends of PCoffset9, LDR R0, R1 at the ends of
+    // offset6, JSR at the ends of PCoffset11, JSRR R1 and JMP R1
+    for word in [
+        0x2100, 0x20FF, 0xE100, 0xE0FF, 0x6060, 0x605F, 0x4C00, 0x4BFF, 0x4040, 0xC040,
+    ] {
+        let (mut before, _) = case(word, 0x4000, 0, FL_ZRO);
+        for (offset, address) in (0x2F00..0x3100).enumerate() {
+            before.memory[address] = offset as u16 | 0x8000;
+        }
+        before.memory[0x3000] = word;
+        let mut after = before.clone();
+        after.step().unwrap();
+        assert_conforms(&before, &after);
+    }
+}
+
+#[test]
+fn test_double_indirection() {
+    // LDI R0, #2 and STI R1, #2, pointing through x3003 to x4000
+    for word in [0xA002, 0xB202] {
+        let (mut before, _) = case(word, 0xBEEF, 0, FL_ZRO);
+        before.memory[0x3003] = 0x4000;
+        before.memory[0x4000] = 0x8001;
+        let mut after = before.clone();
+        after.step().unwrap();
+        assert_conforms(&before, &after);
+    }
+    let (mut before, _) = case(0xA002, 0, 0, FL_ZRO);
+    before.memory[0x3003] = 0x4000;
+    before.memory[0x4000] = 0x8001;
+    let mut vm = machine(&before);
+    vm.step();
+    assert_eq!(vm.registers[0], 0x8001);
+    assert_eq!(vm.registers[CONDITION], FL_NEG);
+}
+
+#[test]
+fn test_random_instructions() {
+    let mut random = Random::new(47);
+    let mut checked = 0;
+    while checked < 2000 {
+        let mut memory: Vec<u16> = (0..1 << 16).map(|_| random.next_word()).collect();
+        let pc = 0x0200 + random.next_word() % 0xFA00;
+        let word = memory[pc as usize];
+        let mut registers = [0; 8];
+        registers.iter_mut().for_each(|r| *r = random.next_word());
+        // pointers into RAM now and then, or every access would miss
+        if random.next_word() & 1 == 0 {
+            let address = 0x3000 + random.next_word() % 0x1000;
+            registers[(word >> 6 & 7) as usize] = address;
+            let pointer = pc.wrapping_add(1).wrapping_add(sext(word, 8));
+            if pointer < DEVICE_PAGE {
+                memory[pointer as usize] = address;
+            }
+        }
+        let flags = [FL_NEG, FL_ZRO, FL_POS];
+        let before = Model {
+            registers,
+            pc,
+            condition: flags[random.next_word() as usize % 3],
+            memory,
+        };
+        let mut after = before.clone();
+        if after.step().is_none() {
+            continue;
+        }
+        assert_conforms(&before, &after);
+        checked += 1;
+    }
+}
@@ -0,0 +1,210 @@
+//! Keyboard and display of the VM
+//!
+//! GETC, OUT, PUTS, HALT and the keyboard registers at xFE00/xFE02 all go
+//! through the [`Console`] held by [`crate::cpu::VmCPU`], so the VM can run
+//! on a terminal, inside a debugger or under test without touching stdio.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::terminal::{self, RawMode};
+
+pub trait Console {
+    /// Next input byte. `Ok(None)` means no byte is ready; consoles that can
+    /// wait for one, like a terminal, block instead.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+
+    /// Whether [`Console::read_byte`] has a byte ready
+    fn poll(&mut self) -> io::Result<bool>;
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Lets a front end keep a handle on the console it gave to the VM
+impl<C: Console> Console for Rc<RefCell<C>> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.borrow_mut().read_byte()
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        self.borrow_mut().poll()
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.borrow_mut().write_byte(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.borrow_mut().flush()
+    }
+}
+
+/// The process's stdin and stdout
+#[derive(Debug, Default)]
+pub struct StdioConsole {
+    // only held, dropping it puts the terminal back
+    _raw_mode: Option<RawMode>,
+}
+
+impl StdioConsole {
+    /// Keys arrive one at a time without Enter or echo for as long as the
+    /// console lives, when stdin is a terminal
+    pub fn interactive() -> io::Result<Self> {
+        Ok(Self {
+            _raw_mode: RawMode::enter()?,
+        })
+    }
+}
+
+impl Console for StdioConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        terminal::read_key()
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        terminal::key_ready()
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        io::stdout().write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Input and output in memory. Reads report no byte ready once the input
+/// runs out.
+#[derive(Debug, Default, Clone)]
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Take everything written so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        Ok(!self.input.is_empty())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Replies typed in answer to prompts: each step's input only becomes
+/// readable once the program has written the text the step waits for.
+#[derive(Debug, Default, Clone)]
+pub struct ScriptedConsole {
+    steps: VecDeque<(String, Vec<u8>)>,
+    input: VecDeque<u8>,
+    pub output: Vec<u8>,
+    // output already matched by earlier steps
+    matched: usize,
+}
+
+impl ScriptedConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// After the program prints `prompt`, type `input`
+    pub fn expect(mut self, prompt: &str, input: &[u8]) -> Self {
+        self.steps.push_back((prompt.to_string(), input.to_vec()));
+        self.release();
+        self
+    }
+
+    /// Steps whose prompt has not been printed yet
+    pub fn remaining(&self) -> usize {
+        self.steps.len()
+    }
+
+    fn release(&mut self) {
+        while let Some((prompt, _)) = self.steps.front() {
+            let unmatched = &self.output[self.matched..];
+            let position = match prompt.is_empty() {
+                true => Some(0),
+                false => unmatched
+                    .windows(prompt.len())
+                    .position(|window| window == prompt.as_bytes()),
+            };
+            let Some(position) = position else {
+                break;
+            };
+            self.matched += position + prompt.len();
+            let (_, input) = self.steps.pop_front().unwrap();
+            self.input.extend(input);
+        }
+    }
+}
+
+impl Console for ScriptedConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        Ok(!self.input.is_empty())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        self.release();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scripted_console_waits_for_prompt() {
+        let mut console = ScriptedConsole::new()
+            .expect("", b"a")
+            .expect("name? ", b"bob\n");
+
+        assert_eq!(console.read_byte().unwrap(), Some(b'a'));
+        assert!(!console.poll().unwrap());
+
+        for byte in b"your name? " {
+            console.write_byte(*byte).unwrap();
+        }
+        assert_eq!(console.remaining(), 0);
+        assert_eq!(console.read_byte().unwrap(), Some(b'b'));
+    }
+}
@@ -0,0 +1,306 @@
+//! Line and branch coverage
+//!
+//! While a [`Coverage`] is attached to a [`crate::cpu::VmCPU`] it counts how
+//! often each address ran and, for every conditional `BR`, how often the
+//! branch was taken and not taken. Results from several runs, e.g. one per
+//! input transcript, can be combined with [`Coverage::merge`].
+//!
+//! The assembler's line table maps addresses back to source lines for
+//! [`Coverage::lcov`] and [`Coverage::annotated_listing`]. With only a
+//! `.sym` file the listing falls back to one line per label.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    fs, io,
+};
+
+use crate::{
+    debug_info::{DebugInfo, LineEntry},
+    instructions::Instructions,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    addresses: Vec<u64>,
+    branches: BTreeMap<u16, BranchCounts>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self {
+            addresses: vec![0; 1 << 16],
+            branches: BTreeMap::new(),
+        }
+    }
+}
+
+/// Whether a conditional branch will be taken given the condition codes.
+/// `None` for anything else, including `BRnzp` and the `BR` with no
+/// condition bits, which always and never branch.
+pub fn branch_outcome(instruction: &Instructions, condition: u16) -> Option<bool> {
+    let Instructions::Branch { n, z, p, .. } = instruction else {
+        return None;
+    };
+    let mask = (*n as u16) << 2 | (*z as u16) << 1 | *p as u16;
+    if mask == 0 || mask == 0b111 {
+        return None;
+    }
+    Some(mask & condition != 0)
+}
+
+impl Coverage {
+    /// Count the instruction at `pc`, with the outcome if it was a
+    /// conditional branch
+    pub fn record(&mut self, pc: u16, branch: Option<bool>) {
+        self.addresses[pc as usize] += 1;
+        if let Some(taken) = branch {
+            let counts = self.branches.entry(pc).or_default();
+            if taken {
+                counts.taken += 1;
+            } else {
+                counts.not_taken += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (count, other) in self.addresses.iter_mut().zip(&other.addresses) {
+            *count += other;
+        }
+        for (address, other) in &other.branches {
+            let counts = self.branches.entry(*address).or_default();
+            counts.taken += other.taken;
+            counts.not_taken += other.not_taken;
+        }
+    }
+
+    pub fn count_at(&self, address: u16) -> u64 {
+        self.addresses[address as usize]
+    }
+
+    pub fn branch_at(&self, address: u16) -> Option<BranchCounts> {
+        self.branches.get(&address).copied()
+    }
+
+    /// LCOV tracefile for the source in `info`, `None` without a line
+    /// table. `words` is the memory image the program was loaded from.
+    pub fn lcov(&self, info: &DebugInfo, words: &[u16]) -> Option<String> {
+        let source = info.source.as_ref()?;
+        let mut text = String::new();
+        writeln!(text, "TN:").unwrap();
+        writeln!(text, "SF:{}", source.display()).unwrap();
+
+        // labels on code lines stand in for functions
+        let functions: Vec<(&str, usize, u16)> = info
+            .symbols
+            .iter()
+            .filter_map(|(name, address)| {
+                let entry = info.lines.entry_at(address)?;
+                (entry.is_code && entry.address == address).then_some((name, entry.line, address))
+            })
+            .collect();
+        for (name, line, _) in &functions {
+            writeln!(text, "FN:{},{}", line, name).unwrap();
+        }
+        for (name, _, address) in &functions {
+            writeln!(text, "FNDA:{},{}", self.count_at(*address), name).unwrap();
+        }
+        writeln!(text, "FNF:{}", functions.len()).unwrap();
+        let hit = functions
+            .iter()
+            .filter(|(_, _, address)| self.count_at(*address) > 0)
+            .count();
+        writeln!(text, "FNH:{}", hit).unwrap();
+
+        let code: Vec<_> = info.lines.entries().filter(|entry| entry.is_code).collect();
+
+        let (mut found, mut hit) = (0, 0);
+        for entry in &code {
+            // decoded from the image so branches that never ran are missed
+            let word = words[entry.address as usize];
+            if branch_outcome(&Instructions::from(word), 0).is_none() {
+                continue;
+            }
+            let counts = self.branch_at(entry.address).unwrap_or_default();
+            let ran = self.count_at(entry.address) > 0;
+            for (branch, count) in [(0, counts.taken), (1, counts.not_taken)] {
+                let count = if ran { count.to_string() } else { "-".into() };
+                writeln!(text, "BRDA:{},0,{},{}", entry.line, branch, count).unwrap();
+            }
+            found += 2;
+            hit += (counts.taken > 0) as usize + (counts.not_taken > 0) as usize;
+        }
+        writeln!(text, "BRF:{}", found).unwrap();
+        writeln!(text, "BRH:{}", hit).unwrap();
+
+        for entry in &code {
+            writeln!(text, "DA:{},{}", entry.line, self.count_at(entry.address)).unwrap();
+        }
+        writeln!(text, "LF:{}", code.len()).unwrap();
+        let hit = code
+            .iter()
+            .filter(|entry| self.count_at(entry.address) > 0)
+            .count();
+        writeln!(text, "LH:{}", hit).unwrap();
+        writeln!(text, "end_of_record").unwrap();
+        Some(text)
+    }
+
+    /// The source with execution counts in front of each line, `#####` for
+    /// code that never ran and `-` for lines without code. Conditional
+    /// branches are marked `T`/`N` for the outcomes seen.
+    pub fn annotated_listing(&self, info: &DebugInfo) -> io::Result<String> {
+        let Some(source) = &info.source else {
+            return Ok(self.label_listing(info));
+        };
+        let source = fs::read_to_string(source)?;
+
+        let code: HashMap<usize, &LineEntry> = info
+            .lines
+            .entries()
+            .filter(|entry| entry.is_code)
+            .map(|entry| (entry.line, entry))
+            .collect();
+
+        let mut text = String::new();
+        for (index, line) in source.lines().enumerate() {
+            let (count, branch) = match code.get(&(index + 1)) {
+                None => ("-".to_string(), "  "),
+                Some(entry) => {
+                    let count = match self.count_at(entry.address) {
+                        0 => "#####".to_string(),
+                        count => count.to_string(),
+                    };
+                    let branch = match self.branch_at(entry.address) {
+                        Some(BranchCounts { taken, not_taken }) => {
+                            match (taken > 0, not_taken > 0) {
+                                (true, true) => "TN",
+                                (true, false) => "T ",
+                                (false, true) => " N",
+                                (false, false) => "  ",
+                            }
+                        }
+                        None => "  ",
+                    };
+                    (count, branch)
+                }
+            };
+            writeln!(text, "{:>9} {} | {}", count, branch, line).unwrap();
+        }
+        Ok(text)
+    }
+
+    fn label_listing(&self, info: &DebugInfo) -> String {
+        let mut text = String::new();
+        for (name, address) in info.symbols.iter() {
+            let count = match self.count_at(address) {
+                0 => "#####".to_string(),
+                count => count.to_string(),
+            };
+            writeln!(text, "{:>9} | x{:04X} {}", count, address, name).unwrap();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::{assembler, cpu::VmCPU, memory::Memory};
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        AND R0, R0, #0
+        ADD R1, R0, #2
+LOOP    ADD R0, R0, #1
+        ADD R1, R1, #-1
+        BRp LOOP
+        BRn NEVER
+        HALT
+NEVER   HALT
+        .END
+";
+
+    fn run() -> (Coverage, DebugInfo, Vec<u16>) {
+        let assembly = assembler::assemble(PROGRAM).unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; 10], memory);
+        vm.console = Box::new(BufferConsole::default());
+        vm.coverage = Some(Coverage::default());
+        vm.execute().unwrap();
+
+        let info = DebugInfo {
+            source: Some("loop.asm".into()),
+            symbols: assembly.symbols,
+            lines: assembly.lines,
+        };
+        (vm.coverage.unwrap(), info, vm.memory.words().to_vec())
+    }
+
+    #[test]
+    fn test_branch_outcomes() {
+        let (coverage, _, _) = run();
+
+        assert_eq!(coverage.count_at(0x3002), 2);
+        assert_eq!(
+            coverage.branch_at(0x3004),
+            Some(BranchCounts {
+                taken: 1,
+                not_taken: 1,
+            })
+        );
+        assert_eq!(
+            coverage.branch_at(0x3005),
+            Some(BranchCounts {
+                taken: 0,
+                not_taken: 1,
+            })
+        );
+        assert_eq!(coverage.count_at(0x3007), 0);
+
+        let mut merged = coverage.clone();
+        merged.merge(&coverage);
+        assert_eq!(merged.branch_at(0x3004).unwrap().taken, 2);
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (coverage, info, words) = run();
+        let lcov = coverage.lcov(&info, &words).unwrap();
+
+        assert!(lcov.starts_with("TN:\nSF:loop.asm\n"));
+        assert!(lcov.contains("FN:5,LOOP\nFN:10,NEVER\n"));
+        assert!(lcov.contains("FNDA:2,LOOP\nFNDA:0,NEVER\nFNF:2\nFNH:1\n"));
+        assert!(lcov.contains("BRDA:7,0,0,1\nBRDA:7,0,1,1\nBRDA:8,0,0,0\nBRDA:8,0,1,1\n"));
+        assert!(lcov.contains("BRF:4\nBRH:3\n"));
+        assert!(lcov.contains("DA:10,0\nLF:8\nLH:7\n"));
+        assert!(lcov.ends_with("end_of_record\n"));
+    }
+
+    #[test]
+    fn test_annotated_listing() {
+        let (coverage, mut info, _) = run();
+        let source = std::env::temp_dir().join(format!("coverage-{}.asm", std::process::id()));
+        fs::write(&source, PROGRAM).unwrap();
+        info.source = Some(source.clone());
+
+        let listing = coverage.annotated_listing(&info).unwrap();
+        fs::remove_file(source).unwrap();
+
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), PROGRAM.lines().count());
+        assert_eq!(lines[1], "        -    |         .ORIG x3000");
+        assert_eq!(lines[3], "        1    |         ADD R1, R0, #2");
+        assert_eq!(lines[6], "        2 TN |         BRp LOOP");
+        assert_eq!(lines[7], "        1  N |         BRn NEVER");
+        assert_eq!(lines[9], "    #####    | NEVER   HALT");
+    }
+}
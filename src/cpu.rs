@@ -1,26 +1,170 @@
-use std::io::Read;
+use std::{collections::VecDeque, fmt, io};
 
 use crate::{
+    blocks::Blocks,
+    callstack::{CallEvent, CallStack, CallStackChange},
+    console::{Console, StdioConsole},
+    coverage::{self, Coverage},
+    devices::{Bus, DeviceContext, DEVICE_PAGE, KEY_BOARD_DATA, KEY_BOARD_STATUS, KEY_READY},
+    error::{Fault, FaultKind},
+    files::{FileError, FileSystem},
+    history::History,
     instructions::{Instructions, JumpRegisterType, JumpType, LoadType},
     memory::Memory,
+    profile::Profiler,
     register::{General, Registers, REGISTER_COUNT},
+    snapshot::Snapshot,
     trap::TrapType,
+    video::FrameRecorder,
 };
 
-#[derive(Debug)]
 pub struct VmCPU {
     pub registers: [u16; REGISTER_COUNT],
     pub memory: Memory,
+    /// devices on the page from xFE00, in front of `memory`
+    pub devices: Bus,
+    /// keyboard and display
+    pub console: Box<dyn Console>,
+    // keys handed back by reverse execution, read before the console
+    replay: VecDeque<u8>,
+    /// undo log, reverse execution is only possible while this is set
+    pub history: Option<History>,
+    /// subroutines entered and not yet returned from
+    pub call_stack: CallStack,
+    /// execution counts, collected only while this is set
+    pub profiler: Option<Profiler>,
+    /// executed addresses and branch outcomes, recorded while this is set
+    pub coverage: Option<Coverage>,
+    /// framebuffer captures, taken while this is set
+    pub frames: Option<FrameRecorder>,
+    /// sandbox for the file traps, which are unknown while this is unset
+    pub files: Option<FileSystem>,
+    // old register values of the current instruction while history is on
+    register_journal: Vec<(usize, u16)>,
+    // keys read by the current instruction while history is on
+    input_journal: Vec<u8>,
+    // a device asked to stop during the current instruction
+    pub(crate) halt_requested: bool,
+    // privilege and priority bits of the PSR, the condition codes live in
+    // the condition register
+    status: u16,
+    /// supervisor stack pointer while running in user mode
+    pub saved_ssp: u16,
+    /// user stack pointer while running in supervisor mode
+    pub saved_usp: u16,
+    /// leave out the "Exiting" line HALT prints
+    pub quiet: bool,
+    /// TRAP jumps to the service routine in the trap vector table, like
+    /// real hardware with an operating system image loaded, instead of
+    /// running the built in routines. The file traps stay built in while
+    /// `files` is set.
+    pub os_traps: bool,
+    /// fetching from the device page
+    pub execution_policy: ExecutionPolicy,
+    // translated basic blocks while the block engine is on
+    pub(crate) blocks: Option<Blocks>,
 }
 
-const FL_POS: u16 = 1 << 0; /* P */
-const FL_ZRO: u16 = 1 << 1; /* Z */
-const FL_NEG: u16 = 1 << 2; /* N */
+/// The console as devices see it: replayed keys come first and keys read
+/// are journaled for reverse execution
+struct Input<'a> {
+    console: &'a mut dyn Console,
+    replay: &'a mut VecDeque<u8>,
+    journal: Option<&'a mut Vec<u8>>,
+}
+
+impl Console for Input<'_> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let key = match self.replay.pop_front() {
+            Some(key) => Some(key),
+            None => self.console.read_byte()?,
+        };
+        if let (Some(journal), Some(key)) = (self.journal.as_mut(), key) {
+            journal.push(key);
+        }
+        Ok(key)
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        Ok(!self.replay.is_empty() || self.console.poll()?)
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.console.write_byte(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.console.flush()
+    }
+}
+
+impl fmt::Debug for VmCPU {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VmCPU")
+            .field("registers", &self.registers)
+            .field("memory", &self.memory)
+            .field("devices", &self.devices)
+            .field("history", &self.history)
+            .field("call_stack", &self.call_stack)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Continue,
+    Halted,
+    /// GETC found no input; the trap was rolled back and will run again
+    WaitingForInput,
+    /// the instruction cannot be executed; it was rolled back
+    Fault(Fault),
+}
+
+/// What fetching an instruction from the device page does. Execution
+/// only gets past xFFFF and around to x0000 through it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecutionPolicy {
+    /// read the word through the bus like any other and carry on, the PC
+    /// wrapping from xFFFF to x0000
+    #[default]
+    Wrap,
+    /// stop with [`FaultKind::DevicePage`]
+    Fault,
+    /// take the access control violation exception, with the PC of the
+    /// fetch pushed for the handler
+    AccessViolation,
+}
+
+pub const FL_POS: u16 = 1 << 0; /* P */
+pub const FL_ZRO: u16 = 1 << 1; /* Z */
+pub const FL_NEG: u16 = 1 << 2; /* N */
+
+/// PSR bit set while running in user mode
+pub const PSR_USER: u16 = 1 << 15;
+const PSR_PRIORITY_SHIFT: u16 = 8;
+const PSR_PRIORITY: u16 = 0b111 << PSR_PRIORITY_SHIFT;
+
+/// Handler addresses for interrupts and exceptions, indexed by vector
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+/// Raised by RTI in user mode
+pub const PRIVILEGE_MODE_VECTOR: u8 = 0x00;
+/// Raised by fetching from the device page under
+/// [`ExecutionPolicy::AccessViolation`]
+pub const ACCESS_VIOLATION_VECTOR: u8 = 0x02;
+/// Where the supervisor stack starts, growing down below the user program
+pub const DEFAULT_SSP: u16 = 0x3000;
 
 impl VmCPU {
+    /// The PC starts at `memory.pc_start` whatever `registers` holds, and
+    /// the condition codes at Z unless `registers` sets exactly one. See
+    /// [`crate::machine::MachineBuilder`] for more control.
     pub fn new(mut registers: [u16; REGISTER_COUNT], memory: Memory) -> Self {
         let pc_register: usize = Registers::ProgramCounter.into();
         registers[pc_register] = memory.pc_start as u16;
+        let condition_register: usize = Registers::Condition.into();
+        if (registers[condition_register] & 0b111).count_ones() != 1 {
+            registers[condition_register] = FL_ZRO;
+        }
 
         // for elem in memory.data[memory.pc_start..memory.pc_end].iter() {
         //     let mut instruction_bits = [false; 16];
@@ -35,7 +179,29 @@ impl VmCPU {
         //     instructions.push(Instructions::parse_instruction(&instruction_bits));
         // }
 
-        Self { registers, memory }
+        Self {
+            registers,
+            memory,
+            devices: Bus::standard(),
+            console: Box::new(StdioConsole::default()),
+            replay: VecDeque::new(),
+            history: None,
+            call_stack: CallStack::default(),
+            profiler: None,
+            coverage: None,
+            frames: None,
+            files: None,
+            register_journal: Vec::new(),
+            input_journal: Vec::new(),
+            halt_requested: false,
+            status: PSR_USER,
+            saved_ssp: DEFAULT_SSP,
+            saved_usp: 0,
+            quiet: false,
+            os_traps: false,
+            execution_policy: ExecutionPolicy::default(),
+            blocks: None,
+        }
     }
 
     pub fn read_register(&self, register: Registers) -> u16 {
@@ -45,28 +211,192 @@ impl VmCPU {
 
     pub fn update_register(&mut self, register: Registers, value: u16) {
         let register_index: usize = register.into();
+        if self.history.is_some() {
+            self.register_journal
+                .push((register_index, self.registers[register_index]));
+        }
         self.registers[register_index] = value;
     }
 
     pub fn get_instruction(&mut self) -> Instructions {
-        // let elem = self.memory.data[];
         let memory_location: u16 = self.read_register(Registers::ProgramCounter);
 
-        let elem = self.memory.read_memory(memory_location);
+        // device registers are read through the bus and never cached
+        let instruction = if memory_location < DEVICE_PAGE {
+            self.memory.fetch(memory_location)
+        } else {
+            Instructions::from(self.read_memory(memory_location))
+        };
+
+        self.update_register(Registers::ProgramCounter, memory_location.wrapping_add(1));
+        instruction
+    }
+
+    /// Run `f` with the device bus and what devices may touch
+    pub(crate) fn with_devices<T>(
+        &mut self,
+        f: impl FnOnce(&mut Bus, &mut DeviceContext) -> T,
+    ) -> T {
+        let mut input = Input {
+            console: self.console.as_mut(),
+            replay: &mut self.replay,
+            journal: self.history.is_some().then_some(&mut self.input_journal),
+        };
+        let mut context = DeviceContext {
+            console: &mut input,
+            halt: false,
+        };
+        let result = f(&mut self.devices, &mut context);
+        self.halt_requested |= context.halt;
+        result
+    }
+
+    /// Read `location` as the program sees it, through the device mapped
+    /// there if any
+    pub fn read_memory(&mut self, location: u16) -> u16 {
+        self.with_devices(|bus, context| bus.read(location, context))
+            .unwrap_or_else(|| self.memory.peek(location))
+    }
+
+    pub fn write_memory(&mut self, location: u16, value: u16) {
+        if !self.with_devices(|bus, context| bus.write(location, value, context)) {
+            self.memory.write_memory(location as usize, value);
+        }
+    }
+
+    /// Read `location` without side effects, for debuggers
+    pub fn peek(&self, location: u16) -> u16 {
+        self.devices
+            .peek(location)
+            .unwrap_or_else(|| self.memory.peek(location))
+    }
+
+    /// Next key, replayed keys first, waiting for one if the console can
+    fn read_input(&mut self) -> Option<u8> {
+        self.with_devices(|_, context| context.console.read_byte())
+            .expect("Failed to read keyboard")
+    }
+
+    /// Push keys back so the next reads return them, in order
+    pub fn unread_input(&mut self, keys: &[u8]) {
+        for key in keys.iter().rev() {
+            self.replay.push_front(*key);
+        }
+    }
 
-        let mut instruction_slice = [false; 16];
+    /// Keys waiting to be replayed
+    pub fn pending_input(&self) -> Vec<u8> {
+        self.replay.iter().copied().collect()
+    }
 
-        let mut i = 0;
-        let mut n = elem;
-        while n > 0 {
-            instruction_slice[i] = n % 2 == 1;
-            n = n / 2;
-            i += 1;
+    fn write_output(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.console
+                .write_byte(*byte)
+                .expect("Failed to write output");
         }
+        self.console.flush().expect("Failed to write output");
+    }
+
+    /// Processor status register: privilege in bit 15, priority in bits
+    /// [10:8] and the condition codes in bits [2:0]. Programs start in user
+    /// mode at priority 0. User mode only matters for RTI and the stack
+    /// switch on interrupts; memory is not protected.
+    pub fn psr(&self) -> u16 {
+        self.status | self.read_register(Registers::Condition) & 0b111
+    }
+
+    pub(crate) fn set_psr(&mut self, psr: u16) {
+        self.status = psr & (PSR_USER | PSR_PRIORITY);
+        self.update_register(Registers::Condition, psr & 0b111);
+    }
 
-        self.update_register(Registers::ProgramCounter, memory_location + 1);
-        let i = Instructions::parse_instruction(&instruction_slice);
-        i
+    fn system_state(&self) -> [u16; 3] {
+        [self.status, self.saved_ssp, self.saved_usp]
+    }
+
+    fn set_system_state(&mut self, [status, saved_ssp, saved_usp]: [u16; 3]) {
+        self.status = status;
+        self.saved_ssp = saved_ssp;
+        self.saved_usp = saved_usp;
+    }
+
+    fn priority(&self) -> u8 {
+        ((self.status & PSR_PRIORITY) >> PSR_PRIORITY_SHIFT) as u8
+    }
+
+    /// Switch to supervisor mode, push PSR and PC on the supervisor stack
+    /// and jump through the vector table. `priority` is `None` for
+    /// exceptions, which keep the current one.
+    fn enter_interrupt(&mut self, vector: u8, priority: Option<u8>) {
+        let psr = self.psr();
+        let stack = Registers::GeneralRegister(General::R6);
+        if psr & PSR_USER != 0 {
+            self.saved_usp = self.read_register(stack);
+            self.update_register(stack, self.saved_ssp);
+        }
+
+        let pc = self.read_register(Registers::ProgramCounter);
+        let sp = self.read_register(stack).wrapping_sub(1);
+        self.write_memory(sp, psr);
+        let sp = sp.wrapping_sub(1);
+        self.write_memory(sp, pc);
+        self.update_register(stack, sp);
+
+        self.status &= !PSR_USER;
+        if let Some(priority) = priority {
+            self.status = (self.status & !PSR_PRIORITY) | (priority as u16) << PSR_PRIORITY_SHIFT;
+        }
+        let handler = self.read_memory(INTERRUPT_VECTOR_TABLE + vector as u16);
+        self.update_register(Registers::ProgramCounter, handler);
+    }
+
+    /// Whether a device interrupt outranks the running priority
+    pub(crate) fn interrupt_due(&self) -> bool {
+        matches!(
+            self.devices.pending_interrupt(),
+            Some(interrupt) if interrupt.priority > self.priority()
+        )
+    }
+
+    /// Take the most urgent device interrupt if it outranks the running
+    /// priority
+    fn service_interrupt(&mut self) {
+        match self.devices.pending_interrupt() {
+            Some(interrupt) if interrupt.priority > self.priority() => {
+                self.enter_interrupt(interrupt.vector, Some(interrupt.priority));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            psr: self.psr(),
+            saved_stack_pointers: [self.saved_ssp, self.saved_usp],
+            pc_start: self.memory.pc_start as u16,
+            memory: self.memory.words().to_vec(),
+            pending_input: self.pending_input(),
+            devices: self.devices.save(),
+        }
+    }
+
+    /// Put the machine back into the state of `snapshot`. Undo history and
+    /// the call stack from before the restore are dropped.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers = snapshot.registers;
+        self.registers[usize::from(Registers::Condition)] = snapshot.psr & 0b111;
+        self.status = snapshot.psr & (PSR_USER | PSR_PRIORITY);
+        [self.saved_ssp, self.saved_usp] = snapshot.saved_stack_pointers;
+        self.memory.pc_start = snapshot.pc_start as usize;
+        self.memory.load_image(&snapshot.memory);
+        self.replay = snapshot.pending_input.iter().copied().collect();
+        self.devices.restore(&snapshot.devices);
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        self.call_stack.clear();
     }
 
     pub fn update_flag(&mut self, register_index: u16) {
@@ -81,267 +411,813 @@ impl VmCPU {
         }
     }
 
-    #[allow(unused_variables)]
-    pub fn execute(&mut self) {
+    /// Run until HALT or a fault
+    pub fn execute(&mut self) -> Result<(), Fault> {
         loop {
-            let instruction = self.get_instruction();
-
-            match instruction {
-                Instructions::UnImplemented(_) => todo!("UnImplemented"),
-                Instructions::Branch {
-                    pc_offset_9,
-                    p,
-                    z,
-                    n,
-                } => {
-                    let mut condition_flag = 0u16;
-                    if n {
-                        condition_flag |= 0b100;
-                    }
-                    if z {
-                        condition_flag |= 0b010;
-                    }
-                    if p {
-                        condition_flag |= 0b001;
-                    }
+            match self.step() {
+                StepResult::Halted => return Ok(()),
+                StepResult::Fault(fault) => return Err(fault),
+                _ => {}
+            }
+        }
+    }
 
-                    if condition_flag & self.read_register(Registers::Condition) != 0 {
-                        let pc_value = self.read_register(Registers::ProgramCounter);
-                        self.update_register(
-                            Registers::ProgramCounter,
-                            pc_value.wrapping_add(pc_offset_9),
-                        );
-                    }
-                }
-                Instructions::Add {
-                    dest_register,
-                    src_register,
-                    add_type,
-                } => match add_type {
-                    LoadType::Register {
-                        src_register: src_register_2,
-                    } => {
-                        let operand1 = self.read_register(src_register.into());
-                        let operand2 = self.read_register(src_register_2.into());
-
-                        self.update_register(dest_register.into(), operand1.wrapping_add(operand2));
-
-                        self.update_flag(dest_register.into());
-                    }
-                    LoadType::Immediate { value } => {
-                        //
-                        let operand = self.read_register(src_register.into());
+    /// Fetch, decode and execute a single instruction
+    pub fn step(&mut self) -> StepResult {
+        let registers = self.registers;
+        let system = self.system_state();
+        let devices = self.history.is_some().then(|| self.devices.save());
+        if self.history.is_some() {
+            self.register_journal.clear();
+            self.input_journal.clear();
+            self.memory.begin_journal();
+        }
+        self.halt_requested = false;
+        self.service_interrupt();
 
-                        self.update_register(dest_register.into(), operand.wrapping_add(value));
-                        self.update_flag(dest_register.into());
-                    }
-                },
-                Instructions::LoadDirect {
-                    pc_offset_9,
-                    dest_register,
-                } => {
-                    let pc_value = self.read_register(Registers::ProgramCounter);
+        let pc = self.read_register(Registers::ProgramCounter);
+        // instructions count towards the subroutines active when they start
+        let profiled = self
+            .profiler
+            .is_some()
+            .then(|| (self.memory.peek(pc), self.call_stack.targets()));
+        // outside the Wrap policy the device page is not even read, which
+        // would run the side effects of the device there
+        let contained = pc < DEVICE_PAGE || self.execution_policy == ExecutionPolicy::Wrap;
+        let instruction = match contained {
+            true => self.get_instruction(),
+            false => {
+                self.update_register(Registers::ProgramCounter, pc.wrapping_add(1));
+                Instructions::from(self.peek(pc))
+            }
+        };
+        let event = CallEvent::of(&instruction).filter(|_| contained);
+        let branch = match self.coverage {
+            Some(_) => {
+                coverage::branch_outcome(&instruction, self.read_register(Registers::Condition))
+            }
+            None => None,
+        };
+        let mut result = match contained {
+            true => self.execute_instruction(instruction),
+            false => self.fetch_violation(pc),
+        };
+        if matches!(result, StepResult::WaitingForInput | StepResult::Fault(_)) {
+            self.registers = registers;
+            self.set_system_state(system);
+            if let Some(devices) = &devices {
+                self.devices.restore(devices);
+            }
+            self.memory.take_journal();
+            return result;
+        }
 
-                    let wrapping_add = pc_value.wrapping_add(pc_offset_9);
-                    let value = self.memory.read_memory(wrapping_add);
+        self.with_devices(|bus, context| bus.tick(context));
+        if self.halt_requested {
+            result = StepResult::Halted;
+        }
+        let journal = self.memory.take_journal();
 
-                    self.update_register(dest_register.into(), value);
+        let next_pc = self.read_register(Registers::ProgramCounter);
+        let call_stack = event.and_then(|event| self.call_stack.observe(pc, event, next_pc));
+        if let (Some(profiler), Some((word, targets))) = (self.profiler.as_mut(), profiled) {
+            profiler.record(pc, word, &targets);
+            if call_stack == Some(CallStackChange::Pushed) {
+                profiler.enter(next_pc);
+            }
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(pc, branch);
+        }
+        if let Some(frames) = self.frames.as_mut() {
+            frames.tick(self.memory.words());
+        }
+        let current_system = self.system_state();
+        if let (Some(history), Some(mut record)) = (self.history.as_mut(), journal) {
+            record.registers = std::mem::take(&mut self.register_journal);
+            record.input = std::mem::take(&mut self.input_journal);
+            record.call_stack = call_stack;
+            record.devices = self.devices.changed_since(devices.as_ref().unwrap());
+            record.system = (system != current_system).then_some(system);
+            history.push(record);
+        }
+        result
+    }
 
-                    self.update_flag(dest_register);
+    /// Undo the most recent instruction recorded in `history`.
+    /// Returns false when there is nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(record) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+
+        for (location, value) in record.memory.iter().rev() {
+            self.memory.restore(*location, *value);
+        }
+        for (register_index, value) in record.registers.iter().rev() {
+            self.registers[*register_index] = *value;
+        }
+        // running forward again reads the same keys
+        self.unread_input(&record.input);
+        self.devices.restore(&record.devices);
+        if let Some(system) = record.system {
+            self.set_system_state(system);
+        }
+        if let Some(change) = record.call_stack {
+            self.call_stack.undo(change);
+        }
+        true
+    }
+
+    #[allow(unused_variables)]
+    fn execute_instruction(&mut self, instruction: Instructions) -> StepResult {
+        match instruction {
+            Instructions::UnImplemented(_) => return self.fault(FaultKind::ReservedOpcode),
+            Instructions::ReturnFromInterrupt => {
+                if self.psr() & PSR_USER != 0 {
+                    self.enter_interrupt(PRIVILEGE_MODE_VECTOR, None);
+                    return StepResult::Continue;
                 }
-                Instructions::StoreDirect {
-                    pc_offset_9,
-                    src_register,
-                } => {
-                    let memory_location = self
-                        .read_register(Registers::ProgramCounter)
-                        .wrapping_add(pc_offset_9);
-
-                    self.memory.write_memory(
-                        memory_location as usize,
-                        self.read_register(src_register.into()),
-                    )
+
+                let stack = Registers::GeneralRegister(General::R6);
+                let sp = self.read_register(stack);
+                let pc = self.read_memory(sp);
+                let psr = self.read_memory(sp.wrapping_add(1));
+                self.update_register(stack, sp.wrapping_add(2));
+                self.update_register(Registers::ProgramCounter, pc);
+                self.set_psr(psr);
+                if psr & PSR_USER != 0 {
+                    self.saved_ssp = self.read_register(stack);
+                    self.update_register(stack, self.saved_usp);
                 }
-                Instructions::JumpRegister(register_type) => {
-                    match register_type {
-                        JumpRegisterType::FromOffset { pc_offset_11 } => {
-                            //
-                            let pc_value = self.read_register(Registers::ProgramCounter);
-                            self.update_register(Registers::GeneralRegister(General::R7), pc_value);
-
-                            self.update_register(
-                                Registers::ProgramCounter,
-                                pc_value.wrapping_add(pc_offset_11),
-                            );
-                        }
-                        JumpRegisterType::FromRegister { base_register } => {
-                            todo!("register")
-                        }
-                    }
+            }
+            Instructions::Branch {
+                pc_offset_9,
+                p,
+                z,
+                n,
+            } => {
+                let mut condition_flag = 0u16;
+                if n {
+                    condition_flag |= 0b100;
                 }
-                Instructions::And {
-                    dest_register,
-                    src_register,
-                    add_type,
-                } => {
-                    let base: u16 = self.read_register(src_register.into());
-                    match add_type {
-                        LoadType::Register { src_register } => {
-                            let value = self.read_register(src_register.into());
-                            self.update_register(dest_register.into(), base & value);
-                            self.update_flag(dest_register.into());
-                        }
-                        LoadType::Immediate { value } => {
-                            self.update_register(dest_register.into(), base & value);
-                            self.update_flag(dest_register.into())
-                        }
-                    }
+                if z {
+                    condition_flag |= 0b010;
                 }
-                Instructions::LoadRegister {
-                    offset6,
-                    base_register,
-                    dest_register,
-                } => {
-                    //
-                    let base = self.read_register(base_register.into());
-                    let memory_location = base.wrapping_add(offset6);
-
-                    let value = self.memory.read_memory(memory_location);
-
-                    self.update_register(dest_register.into(), value);
-                    self.update_flag(dest_register.into());
+                if p {
+                    condition_flag |= 0b001;
                 }
-                Instructions::StoreRegister {
-                    offset6,
-                    base_register,
-                    src_register: dest_register,
-                } => {
-                    //
-                    let base = self.read_register(base_register.into());
 
-                    self.memory.write_memory(
-                        base.wrapping_add(offset6) as usize,
-                        self.read_register(dest_register.into()),
-                    )
-                }
-                Instructions::Not {
-                    dest_register,
-                    src_register,
-                } => {
+                if condition_flag & self.read_register(Registers::Condition) != 0 {
+                    let pc_value = self.read_register(Registers::ProgramCounter);
                     self.update_register(
-                        dest_register.into(),
-                        !self.read_register(src_register.into()),
+                        Registers::ProgramCounter,
+                        pc_value.wrapping_add(pc_offset_9),
                     );
-                    self.update_flag(dest_register.into());
                 }
-                Instructions::LoadIndirect {
-                    pc_offset_9,
-                    dest_register,
+            }
+            Instructions::Add {
+                dest_register,
+                src_register,
+                add_type,
+            } => match add_type {
+                LoadType::Register {
+                    src_register: src_register_2,
                 } => {
-                    //
-                    let pc_value = self.read_register(Registers::ProgramCounter);
-                    let indirect_memory_location = pc_value.wrapping_add(pc_offset_9);
-                    let location = self.memory.read_memory(indirect_memory_location);
-                    let direct_value = self.memory.read_memory(location);
+                    let operand1 = self.read_register(src_register.into());
+                    let operand2 = self.read_register(src_register_2.into());
+
+                    self.update_register(dest_register.into(), operand1.wrapping_add(operand2));
 
-                    self.update_register(dest_register.into(), direct_value);
                     self.update_flag(dest_register);
                 }
-                Instructions::StoreIndirect {
-                    pc_offset_9,
-                    src_register,
-                } => {
-                    let pc_value = self.read_register(Registers::ProgramCounter);
-                    let indirect_memory_location = pc_value.wrapping_add(pc_offset_9);
-                    let location = self.memory.read_memory(indirect_memory_location);
+                LoadType::Immediate { value } => {
+                    //
+                    let operand = self.read_register(src_register.into());
 
-                    self.memory
-                        .write_memory(location as usize, self.read_register(src_register.into()));
+                    self.update_register(dest_register.into(), operand.wrapping_add(value));
+                    self.update_flag(dest_register);
                 }
-                Instructions::Jump(jump_type) => match jump_type {
-                    JumpType::BaseRegister(register) => {
+            },
+            Instructions::LoadDirect {
+                pc_offset_9,
+                dest_register,
+            } => {
+                let pc_value = self.read_register(Registers::ProgramCounter);
+
+                let wrapping_add = pc_value.wrapping_add(pc_offset_9);
+                let value = self.read_memory(wrapping_add);
+
+                self.update_register(dest_register.into(), value);
+
+                self.update_flag(dest_register);
+            }
+            Instructions::StoreDirect {
+                pc_offset_9,
+                src_register,
+            } => {
+                let memory_location = self
+                    .read_register(Registers::ProgramCounter)
+                    .wrapping_add(pc_offset_9);
+
+                self.write_memory(memory_location, self.read_register(src_register.into()))
+            }
+            Instructions::JumpRegister(register_type) => {
+                match register_type {
+                    JumpRegisterType::FromOffset { pc_offset_11 } => {
+                        //
+                        let pc_value = self.read_register(Registers::ProgramCounter);
+                        self.update_register(Registers::GeneralRegister(General::R7), pc_value);
+
                         self.update_register(
                             Registers::ProgramCounter,
-                            self.read_register(Registers::from(register)),
+                            pc_value.wrapping_add(pc_offset_11),
                         );
                     }
-                    JumpType::Return => {
-                        self.update_register(
-                            Registers::ProgramCounter,
-                            self.read_register(Registers::GeneralRegister(General::R7)),
-                        );
+                    JumpRegisterType::FromRegister { base_register } => {
+                        // read the target first, JSRR R7 jumps to the old R7
+                        let target = self.read_register(base_register.into());
+                        let pc_value = self.read_register(Registers::ProgramCounter);
+                        self.update_register(Registers::GeneralRegister(General::R7), pc_value);
+
+                        self.update_register(Registers::ProgramCounter, target);
                     }
-                },
-                Instructions::LoadEffectiveAddress {
-                    pc_offset_9,
-                    dest_register,
-                } => {
-                    //
+                }
+            }
+            Instructions::And {
+                dest_register,
+                src_register,
+                add_type,
+            } => {
+                let base: u16 = self.read_register(src_register.into());
+                match add_type {
+                    LoadType::Register { src_register } => {
+                        let value = self.read_register(src_register.into());
+                        self.update_register(dest_register.into(), base & value);
+                        self.update_flag(dest_register);
+                    }
+                    LoadType::Immediate { value } => {
+                        self.update_register(dest_register.into(), base & value);
+                        self.update_flag(dest_register)
+                    }
+                }
+            }
+            Instructions::LoadRegister {
+                offset6,
+                base_register,
+                dest_register,
+            } => {
+                //
+                let base = self.read_register(base_register.into());
+                let memory_location = base.wrapping_add(offset6);
+
+                let value = self.read_memory(memory_location);
+
+                self.update_register(dest_register.into(), value);
+                self.update_flag(dest_register);
+            }
+            Instructions::StoreRegister {
+                offset6,
+                base_register,
+                src_register: dest_register,
+            } => {
+                //
+                let base = self.read_register(base_register.into());
+
+                self.write_memory(
+                    base.wrapping_add(offset6),
+                    self.read_register(dest_register.into()),
+                )
+            }
+            Instructions::Not {
+                dest_register,
+                src_register,
+            } => {
+                self.update_register(
+                    dest_register.into(),
+                    !self.read_register(src_register.into()),
+                );
+                self.update_flag(dest_register);
+            }
+            Instructions::LoadIndirect {
+                pc_offset_9,
+                dest_register,
+            } => {
+                //
+                let pc_value = self.read_register(Registers::ProgramCounter);
+                let indirect_memory_location = pc_value.wrapping_add(pc_offset_9);
+                let location = self.read_memory(indirect_memory_location);
+                let direct_value = self.read_memory(location);
+
+                self.update_register(dest_register.into(), direct_value);
+                self.update_flag(dest_register);
+            }
+            Instructions::StoreIndirect {
+                pc_offset_9,
+                src_register,
+            } => {
+                let pc_value = self.read_register(Registers::ProgramCounter);
+                let indirect_memory_location = pc_value.wrapping_add(pc_offset_9);
+                let location = self.read_memory(indirect_memory_location);
+
+                self.write_memory(location, self.read_register(src_register.into()));
+            }
+            Instructions::Jump(jump_type) => match jump_type {
+                JumpType::BaseRegister(register) => {
                     self.update_register(
-                        dest_register.into(),
-                        self.read_register(Registers::ProgramCounter)
-                            .wrapping_add(pc_offset_9),
+                        Registers::ProgramCounter,
+                        self.read_register(Registers::from(register)),
                     );
-                    self.update_flag(dest_register);
                 }
-                Instructions::Trap { trap_vector } => {
-                    let trap: TrapType = trap_vector.into();
-
+                JumpType::Return => {
                     self.update_register(
-                        Registers::GeneralRegister(General::R7),
-                        self.read_register(Registers::ProgramCounter),
+                        Registers::ProgramCounter,
+                        self.read_register(Registers::GeneralRegister(General::R7)),
                     );
+                }
+            },
+            Instructions::LoadEffectiveAddress {
+                pc_offset_9,
+                dest_register,
+            } => {
+                //
+                self.update_register(
+                    dest_register.into(),
+                    self.read_register(Registers::ProgramCounter)
+                        .wrapping_add(pc_offset_9),
+                );
+                self.update_flag(dest_register);
+            }
+            Instructions::Trap { trap_vector } => {
+                self.update_register(
+                    Registers::GeneralRegister(General::R7),
+                    self.read_register(Registers::ProgramCounter),
+                );
 
-                    // read from R_R0
-                    match trap {
-                        TrapType::Put => {
-                            let mut memory_start =
-                                self.read_register(Registers::GeneralRegister(General::R0));
-                            let mut character = self.memory.read_memory(memory_start);
+                let file_trap = self.files.is_some() && (0x30..=0x34).contains(&trap_vector);
+                if self.os_traps && !file_trap {
+                    let routine = self.read_memory(trap_vector);
+                    self.update_register(Registers::ProgramCounter, routine);
+                    return StepResult::Continue;
+                }
+                let Ok(trap) = TrapType::try_from(trap_vector) else {
+                    return self.fault(FaultKind::UnsupportedTrap(trap_vector as u8));
+                };
 
-                            let mut from_u32 = std::char::from_u32(character as u32).unwrap();
+                // read from R_R0
+                match trap {
+                    TrapType::Put => {
+                        let mut memory_start =
+                            self.read_register(Registers::GeneralRegister(General::R0));
+                        let mut character = self.read_memory(memory_start);
 
-                            let mut chars = Vec::new();
+                        let mut bytes = Vec::new();
 
-                            while from_u32 != '\0' {
-                                chars.push(from_u32);
+                        while character != 0 {
+                            bytes.push(character as u8);
 
-                                memory_start = memory_start + 1;
-                                character = self.memory.read_memory(memory_start);
+                            memory_start = memory_start.wrapping_add(1);
+                            character = self.read_memory(memory_start);
+                        }
 
-                                from_u32 = std::char::from_u32(character as u32).unwrap();
+                        self.write_output(&bytes);
+                    }
+                    TrapType::PutSp => {
+                        // two characters per word, low byte first
+                        let mut address =
+                            self.read_register(Registers::GeneralRegister(General::R0));
+                        let mut bytes = Vec::new();
+                        'string: loop {
+                            let word = self.read_memory(address);
+                            for byte in [word as u8, (word >> 8) as u8] {
+                                if byte == 0 {
+                                    break 'string;
+                                }
+                                bytes.push(byte);
                             }
-
-                            let string: String = chars.into_iter().collect();
-                            print!("{}", string);
-                        }
-                        TrapType::Out => {
-                            let character = std::char::from_u32(
-                                self.read_register(Registers::GeneralRegister(General::R0)) as u32,
-                            )
-                            .unwrap();
-                            print!("{}", character);
+                            address = address.wrapping_add(1);
                         }
-                        TrapType::Get => {
-                            let mut buffer = [0 as u8; 1];
-                            std::io::stdin().read_exact(&mut buffer).unwrap();
-
-                            self.update_register(
-                                Registers::GeneralRegister(General::R0),
-                                buffer[0] as u16,
-                            );
-                            let register_index: usize =
-                                Registers::GeneralRegister(General::R0).into();
-                            self.update_flag(register_index as u16);
-                        }
-                        TrapType::Halt => {
-                            println!("Exiting");
-                            break;
+
+                        self.write_output(&bytes);
+                    }
+                    TrapType::Out => {
+                        let character =
+                            self.read_register(Registers::GeneralRegister(General::R0)) as u8;
+                        self.write_output(&[character]);
+                    }
+                    TrapType::Get => {
+                        // a key already seen through KBSR comes first
+                        let key = if self.peek(KEY_BOARD_STATUS) & KEY_READY != 0 {
+                            self.read_memory(KEY_BOARD_DATA) as u8
+                        } else {
+                            let Some(key) = self.read_input() else {
+                                return StepResult::WaitingForInput;
+                            };
+                            key
+                        };
+
+                        self.update_register(Registers::GeneralRegister(General::R0), key as u16);
+                        let register_index: usize = Registers::GeneralRegister(General::R0).into();
+                        self.update_flag(register_index as u16);
+                    }
+                    TrapType::Halt => {
+                        if !self.quiet {
+                            self.write_output(b"Exiting\n");
                         }
-                        _ => todo!("Trap {:?}", trap),
+                        return StepResult::Halted;
+                    }
+                    TrapType::FileOpen
+                    | TrapType::FileRead
+                    | TrapType::FileWrite
+                    | TrapType::FileClose
+                    | TrapType::FileSeek
+                        if self.files.is_some() =>
+                    {
+                        let result = self.file_trap(&trap).unwrap_or_else(FileError::code);
+                        self.update_register(Registers::GeneralRegister(General::R0), result);
+                        self.update_flag(0);
                     }
+                    _ => return self.fault(FaultKind::UnsupportedTrap(trap_vector as u8)),
                 }
             }
         }
+
+        StepResult::Continue
+    }
+
+    /// Execution reached `pc` on the device page under a policy other than
+    /// [`ExecutionPolicy::Wrap`]
+    fn fetch_violation(&mut self, pc: u16) -> StepResult {
+        match self.execution_policy {
+            ExecutionPolicy::AccessViolation => {
+                self.update_register(Registers::ProgramCounter, pc);
+                self.enter_interrupt(ACCESS_VIOLATION_VECTOR, None);
+                StepResult::Continue
+            }
+            _ => self.fault(FaultKind::DevicePage),
+        }
+    }
+
+    /// Stop at the instruction just fetched
+    fn fault(&self, kind: FaultKind) -> StepResult {
+        let pc = self
+            .read_register(Registers::ProgramCounter)
+            .wrapping_sub(1);
+        StepResult::Fault(Fault {
+            pc,
+            word: self.peek(pc),
+            kind,
+        })
+    }
+
+    /// One of the file traps, the result goes to R0
+    fn file_trap(&mut self, trap: &TrapType) -> Result<u16, FileError> {
+        let [r0, r1, r2] = [0, 1, 2].map(|index: u16| self.read_register(index.into()));
+        match trap {
+            TrapType::FileOpen => {
+                let mut path = String::new();
+                let mut address = r0;
+                loop {
+                    match self.read_memory(address) {
+                        0 => break,
+                        character => path.push(character as u8 as char),
+                    }
+                    address = address.wrapping_add(1);
+                }
+                self.files.as_mut().unwrap().open(&path, r1)
+            }
+            TrapType::FileRead => {
+                let bytes = self.files.as_mut().unwrap().read(r0, r2)?;
+                for (offset, byte) in bytes.iter().enumerate() {
+                    self.write_memory(r1.wrapping_add(offset as u16), *byte as u16);
+                }
+                Ok(bytes.len() as u16)
+            }
+            TrapType::FileWrite => {
+                let bytes: Vec<u8> = (0..r2)
+                    .map(|offset| self.read_memory(r1.wrapping_add(offset)) as u8)
+                    .collect();
+                self.files.as_mut().unwrap().write(r0, &bytes)
+            }
+            TrapType::FileClose => self.files.as_mut().unwrap().close(r0).map(|_| 0),
+            TrapType::FileSeek => {
+                let position = self.files.as_mut().unwrap().seek(r0, r1 as i16, r2)?;
+                self.update_register(
+                    Registers::GeneralRegister(General::R1),
+                    (position >> 16) as u16,
+                );
+                Ok(position as u16)
+            }
+            _ => unreachable!("{:?} is not a file trap", trap),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        assembler, console::BufferConsole, devices::Bus, history::History, machine::MachineBuilder,
+    };
+
+    const POLLING: &str = "
+        .ORIG x3000
+POLL    ADD R1, R1, #1
+        LDI R0, KBSR
+        BRzp POLL
+        LDI R2, KBDR
+        LDI R3, KBSR
+        HALT
+KBSR    .FILL xFE00
+KBDR    .FILL xFE02
+        .END
+";
+
+    #[test]
+    fn test_kbsr_polling_does_not_block() {
+        let assembly = assembler::assemble(POLLING).unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let console = Rc::new(RefCell::new(BufferConsole::default()));
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(console.clone());
+
+        for _ in 0..30 {
+            assert_eq!(vm.step(), StepResult::Continue);
+        }
+        assert_eq!(vm.registers[1], 10);
+
+        console.borrow_mut().input.extend(b"ab");
+        vm.execute().unwrap();
+        assert_eq!(vm.registers[2], b'a' as u16);
+        // reading KBDR consumed `a`, so KBSR latched the next key
+        assert_eq!(vm.registers[3], KEY_READY);
+        assert_eq!(vm.peek(KEY_BOARD_DATA), b'b' as u16);
+    }
+
+    #[test]
+    fn test_kbdr_read_clears_ready() {
+        let assembly = assembler::assemble(POLLING).unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(BufferConsole::new(b"a"));
+
+        vm.execute().unwrap();
+        assert_eq!(vm.registers[2], b'a' as u16);
+        assert_eq!(vm.registers[3], 0);
+    }
+
+    #[test]
+    fn test_step_back_restores_keyboard() {
+        let assembly = assembler::assemble(POLLING).unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(BufferConsole::new(b"a"));
+        vm.history = Some(History::default());
+
+        // poll latches `a`, reading KBDR releases it
+        for _ in 0..4 {
+            vm.step();
+        }
+        assert_eq!(vm.peek(KEY_BOARD_STATUS), 0);
+        assert!(vm.step_back());
+        assert_eq!(vm.peek(KEY_BOARD_STATUS), KEY_READY);
+        vm.step();
+        assert_eq!(vm.registers[2], b'a' as u16);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let assembly = assembler::assemble(
+            "
+        .ORIG x3000
+        LD R0, HANDLER_ADDR
+        STI R0, VECTOR
+        LD R0, RELOAD
+        STI R0, TRR
+        LD R0, CONTROL
+        STI R0, TCR
+LOOP    ADD R2, R2, #1
+        ADD R3, R1, #-3
+        BRn LOOP
+        HALT
+HANDLER ADD R1, R1, #1
+        STI R1, TSR
+        RTI
+HANDLER_ADDR .FILL HANDLER
+VECTOR  .FILL x0181
+RELOAD  .FILL #20
+TRR     .FILL xFE0A
+CONTROL .FILL xC000
+TCR     .FILL xFE09
+TSR     .FILL xFE08
+        .END
+",
+        )
+        .unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(BufferConsole::default());
+        vm.history = Some(History::default());
+
+        // run until the first interrupt is taken
+        let handler = assembly.symbols.address_of("HANDLER").unwrap();
+        while vm.read_register(Registers::ProgramCounter) != handler + 1 {
+            vm.step();
+        }
+        assert_eq!(vm.psr() & PSR_USER, 0);
+        assert_eq!(vm.registers[6], DEFAULT_SSP - 2);
+        assert!(vm.step_back());
+        assert_eq!(vm.psr() & PSR_USER, PSR_USER);
+        assert_eq!(vm.registers[6], 0);
+
+        vm.execute().unwrap();
+        assert_eq!(vm.registers[1], 3);
+        assert_eq!(vm.psr() & PSR_USER, PSR_USER);
+        assert_eq!(vm.registers[6], 0);
+        assert_eq!(vm.saved_ssp, DEFAULT_SSP);
+    }
+
+    #[test]
+    fn test_file_traps() {
+        let root = std::env::temp_dir().join(format!("file-traps-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("level.txt"), "#@#").unwrap();
+        let assembly = assembler::assemble(
+            "
+        .ORIG x3000
+        LEA R0, NAME
+        AND R1, R1, #0
+        TRAP x30
+        ST R0, HANDLE
+        LEA R1, BUFFER
+        ADD R2, R1, #10
+        TRAP x31
+        ADD R4, R0, #0
+        LD R0, HANDLE
+        TRAP x33
+        TRAP x33
+        HALT
+NAME    .STRINGZ \"level.txt\"
+HANDLE  .BLKW 1
+BUFFER  .BLKW 4
+        .END
+",
+        )
+        .unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(BufferConsole::default());
+        vm.files = Some(FileSystem::new(&root).unwrap());
+        vm.execute().unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+
+        assert_eq!(vm.registers[4], 3);
+        let buffer = assembly.symbols.address_of("BUFFER").unwrap();
+        assert_eq!(vm.peek(buffer + 1), b'@' as u16);
+        // closing twice fails
+        assert_eq!(vm.registers[0], FileError::BadHandle.code());
+        assert_eq!(vm.psr() & 0b111, FL_NEG);
+    }
+
+    #[test]
+    fn test_clearing_mcr_halts() {
+        let assembly = assembler::assemble(
+            "
+        .ORIG x3000
+        AND R0, R0, #0
+        STI R0, MCR
+        ADD R1, R1, #1
+MCR     .FILL xFFFE
+        .END
+",
+        )
+        .unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(BufferConsole::default());
+
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::Halted);
+        assert_eq!(vm.peek(0xFFFE), 0);
+        assert_eq!(vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // the second pass runs the patched instruction
+        let assembly = assembler::assemble(
+            "
+        .ORIG x3000
+LOOP    ADD R1, R1, #1
+        LD R2, PATCH
+        ST R2, LOOP
+        ADD R3, R3, #1
+        ADD R4, R3, #-2
+        BRn LOOP
+        HALT
+PATCH   ADD R1, R1, #5
+        .END
+",
+        )
+        .unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(BufferConsole::default());
+        vm.quiet = true;
+        vm.history = Some(History::default());
+        vm.execute().unwrap();
+        assert_eq!(vm.registers[1], 6);
+
+        // undoing the store brings the original instruction back
+        while vm.step_back() {}
+        assert_eq!(vm.peek(0x3000), assembly.words[0]);
+        vm.execute().unwrap();
+        assert_eq!(vm.registers[1], 6);
+    }
+
+    #[test]
+    fn test_pc_wraps_past_xffff() {
+        // ADD R1, R1, #1 at xFFFF and x0000, with nothing on the device page
+        let mut vm = MachineBuilder::new()
+            .image(0xFFFF, &[0x1261])
+            .image(0x0000, &[0x1261])
+            .bus(Bus::default())
+            .build()
+            .into_cpu();
+        assert_eq!(vm.registers[8], 0xFFFF);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.registers[8], 0x0000);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!((vm.registers[1], vm.registers[8]), (2, 0x0001));
+    }
+
+    #[test]
+    fn test_puts_wraps_past_xffff() {
+        let console = Rc::new(RefCell::new(BufferConsole::default()));
+        let mut vm = MachineBuilder::new()
+            .image(0x3000, &[0xF022])
+            .image(0xFFFE, &[b'a' as u16, b'b' as u16, b'c' as u16, 0])
+            .register(General::R0, 0xFFFE)
+            .bus(Bus::default())
+            .console(console.clone())
+            .build()
+            .into_cpu();
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(console.borrow().output, b"abc");
+    }
+
+    #[test]
+    fn test_execution_policy() {
+        let machine = |policy| {
+            MachineBuilder::new()
+                .image(
+                    INTERRUPT_VECTOR_TABLE + ACCESS_VIOLATION_VECTOR as u16,
+                    &[0x1000],
+                )
+                .entry(KEY_BOARD_STATUS)
+                .execution_policy(policy)
+                .console(BufferConsole::default())
+                .build()
+                .into_cpu()
+        };
+
+        // stopped in front of the fetch
+        let mut vm = machine(ExecutionPolicy::Fault);
+        match vm.step() {
+            StepResult::Fault(fault) => {
+                assert_eq!(
+                    (fault.pc, fault.kind),
+                    (KEY_BOARD_STATUS, FaultKind::DevicePage)
+                )
+            }
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(vm.registers[8], KEY_BOARD_STATUS);
+
+        // into the handler in supervisor mode, the PC of the fetch pushed
+        let mut vm = machine(ExecutionPolicy::AccessViolation);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.registers[8], 0x1000);
+        assert_eq!(vm.psr() & PSR_USER, 0);
+        assert_eq!(vm.registers[6], DEFAULT_SSP - 2);
+        assert_eq!(vm.peek(DEFAULT_SSP - 2), KEY_BOARD_STATUS);
+        assert_eq!(vm.peek(DEFAULT_SSP - 1), PSR_USER | FL_ZRO);
+    }
+
+    #[test]
+    fn test_faulting_fetch_leaves_devices_alone() {
+        let mut vm = MachineBuilder::new()
+            .entry(KEY_BOARD_DATA)
+            .execution_policy(ExecutionPolicy::Fault)
+            .console(BufferConsole::new(b"k"))
+            .build()
+            .into_cpu();
+        // latch the key, so fetching KBDR would release it
+        vm.read_memory(KEY_BOARD_STATUS);
+        assert!(matches!(vm.step(), StepResult::Fault(_)));
+        assert_eq!(vm.peek(KEY_BOARD_STATUS), KEY_READY);
+        assert_eq!(vm.peek(KEY_BOARD_DATA), b'k' as u16);
     }
 }
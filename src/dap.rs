@@ -0,0 +1,849 @@
+//! Debug Adapter Protocol server over stdio
+//!
+//! Messages are read on a separate thread and handed to the main loop
+//! through a channel. While the program runs, the main loop executes it in
+//! slices and checks for new requests (pause, breakpoints, input) between
+//! slices.
+//!
+//! Memory references name word addresses (`x3000`); the bytes returned by
+//! `readMemory` are the words starting there in big endian order.
+//!
+//! Keyboard input is typed into the debug console prefixed with `>`,
+//! e.g. `>w`, `.save <path>` writes a snapshot of the machine and
+//! `.frame <path>` writes the framebuffer as `.png` or `.ppm`. Anything
+//! else is evaluated as a register, label or address.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use serde_json::{json, Value};
+
+use vm::{
+    console::BufferConsole,
+    debug_info::parse_address,
+    register::{Registers, REGISTER_COUNT},
+    snapshot::Snapshot,
+    unstable::{
+        cpu::{FL_NEG, FL_POS, FL_ZRO},
+        debugger::{self, Debugger, RunMode, StopReason},
+        video::Frame,
+    },
+    MachineBuilder,
+};
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+// instructions executed between checks for new requests
+const RUN_SLICE: usize = 10_000;
+
+const REGISTER_NAMES: [&str; REGISTER_COUNT] =
+    ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "PC", "COND"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Stopped,
+    Running,
+    WaitingForInput,
+}
+
+pub struct DapServer<W: Write> {
+    writer: W,
+    seq: i64,
+    debugger: Option<Debugger>,
+    /// keys typed in the debug console and program output waiting to be
+    /// sent as `output` events
+    console: Rc<RefCell<BufferConsole>>,
+    state: State,
+    stop_on_entry: bool,
+    // requested lines per source path, re-resolved on launch
+    source_breakpoints: HashMap<PathBuf, Vec<i64>>,
+    function_breakpoints: Vec<String>,
+    watchpoints: Vec<u16>,
+    // call stack diagnostics already sent to the console
+    reported_diagnostics: usize,
+    exit: bool,
+}
+
+/// Serve a single debug session on stdin/stdout
+pub fn run_stdio() -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut reader = BufReader::new(io::stdin());
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    DapServer::new(io::stdout()).serve(receiver)
+}
+
+/// Read one `Content-Length` framed message
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; content_length.unwrap_or_default()];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            seq: 0,
+            debugger: None,
+            console: Rc::default(),
+            state: State::Stopped,
+            stop_on_entry: false,
+            source_breakpoints: HashMap::new(),
+            function_breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            reported_diagnostics: 0,
+            exit: false,
+        }
+    }
+
+    pub fn serve(&mut self, receiver: Receiver<Value>) -> io::Result<()> {
+        while !self.exit {
+            let message = if self.state == State::Running {
+                match receiver.try_recv() {
+                    Ok(message) => message,
+                    Err(TryRecvError::Empty) => {
+                        self.run_slice()?;
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                }
+            };
+            self.handle(&message)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.writer,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.writer.flush()
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn console(&mut self, text: &str) -> io::Result<()> {
+        self.event("output", json!({ "category": "console", "output": text }))
+    }
+
+    fn handle(&mut self, request: &Value) -> io::Result<()> {
+        if request["type"] != "request" {
+            return Ok(());
+        }
+        let arguments = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+
+        if self.debugger.is_none()
+            && !matches!(
+                command,
+                "initialize"
+                    | "launch"
+                    | "disconnect"
+                    | "terminate"
+                    | "setBreakpoints"
+                    | "setFunctionBreakpoints"
+                    | "setExceptionBreakpoints"
+                    | "dataBreakpointInfo"
+                    | "setDataBreakpoints"
+                    | "configurationDone"
+                    | "threads"
+            )
+        {
+            return self.respond_error(request, "no program launched");
+        }
+
+        match command {
+            "initialize" => {
+                self.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsTerminateRequest": true,
+                        "supportsStepBack": true,
+                        "supportsDataBreakpoints": true,
+                    }),
+                )?;
+                self.event("initialized", json!({}))
+            }
+            "launch" => self.launch(request),
+            "setBreakpoints" => self.set_breakpoints(request),
+            "setFunctionBreakpoints" => {
+                self.function_breakpoints = arguments["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|b| b["name"].as_str().map(str::to_string))
+                    .collect();
+                let breakpoints = self.resolve_breakpoints().1;
+                self.respond(request, json!({ "breakpoints": breakpoints }))
+            }
+            "setExceptionBreakpoints" => self.respond(request, json!({ "breakpoints": [] })),
+            "dataBreakpointInfo" => self.data_breakpoint_info(request),
+            "setDataBreakpoints" => {
+                self.watchpoints = arguments["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|b| b["dataId"].as_str().and_then(parse_address))
+                    .collect();
+                self.resolve_breakpoints();
+                let breakpoints: Vec<Value> = self
+                    .watchpoints
+                    .iter()
+                    .map(|_| json!({ "verified": self.debugger.is_some() }))
+                    .collect();
+                self.respond(request, json!({ "breakpoints": breakpoints }))
+            }
+            "configurationDone" => {
+                self.respond(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.stopped("entry")
+                } else {
+                    self.start(RunMode::Continue)
+                }
+            }
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "LC-3" }] }),
+            ),
+            "stackTrace" => {
+                let frames = self.stack_frames();
+                let total = frames.len();
+                self.respond(
+                    request,
+                    json!({ "stackFrames": frames, "totalFrames": total }),
+                )
+            }
+            "scopes" => self.respond(
+                request,
+                json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                ]}),
+            ),
+            "variables" => {
+                let variables = self.variables(arguments["variablesReference"].as_i64());
+                self.respond(request, json!({ "variables": variables }))
+            }
+            "readMemory" => self.read_memory(request),
+            "evaluate" => self.evaluate(request),
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }))?;
+                self.start(RunMode::Continue)
+            }
+            "next" => {
+                self.respond(request, json!({}))?;
+                let mode = self.debugger.as_ref().unwrap().step_over_mode();
+                self.start(mode)
+            }
+            "stepIn" => {
+                self.respond(request, json!({}))?;
+                self.start(RunMode::StepIn)
+            }
+            "stepOut" => {
+                self.respond(request, json!({}))?;
+                let mode = self.debugger.as_ref().unwrap().step_out_mode();
+                self.start(mode)
+            }
+            "stepBack" => {
+                self.respond(request, json!({}))?;
+                self.start(RunMode::ReverseStep)
+            }
+            "reverseContinue" => {
+                self.respond(request, json!({}))?;
+                self.start(RunMode::ReverseContinue)
+            }
+            "pause" => {
+                self.respond(request, json!({}))?;
+                self.flush_output()?;
+                self.stopped("pause")
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}))?;
+                if command == "terminate" {
+                    self.event("terminated", json!({}))?;
+                }
+                self.exit = true;
+                Ok(())
+            }
+            _ => self.respond_error(request, &format!("unsupported request `{}`", command)),
+        }
+    }
+
+    fn launch(&mut self, request: &Value) -> io::Result<()> {
+        let arguments = &request["arguments"];
+        let Some(program) = arguments["program"].as_str() else {
+            return self.respond_error(request, "launch requires `program`");
+        };
+        let sym = arguments["sym"].as_str().map(Path::new);
+
+        let (origin, words, debug_info) = match debugger::read_program(Path::new(program), sym) {
+            Ok(loaded) => loaded,
+            Err(e) => return self.respond_error(request, &format!("{}: {}", program, e)),
+        };
+
+        let mut builder = MachineBuilder::new()
+            .image(origin, &words)
+            .console(self.console.clone());
+        if let Some(seed) = arguments["seed"].as_u64() {
+            builder = builder.seed(seed);
+        }
+        let mut vm = builder.build().into_cpu();
+
+        // start from a saved mid-program state
+        if let Some(path) = arguments["snapshot"].as_str() {
+            match Snapshot::load_from_file(path) {
+                Ok(snapshot) => vm.restore(&snapshot),
+                Err(e) => return self.respond_error(request, &format!("{}: {}", path, e)),
+            }
+        }
+
+        let mut debugger = Debugger::new(vm, debug_info);
+        if let Some(depth) = arguments["historyDepth"].as_u64() {
+            debugger.set_history_depth(depth as usize);
+        }
+        self.debugger = Some(debugger);
+        self.reported_diagnostics = 0;
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.resolve_breakpoints();
+
+        self.respond(request, json!({}))
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) -> io::Result<()> {
+        let arguments = &request["arguments"];
+        let Some(path) = arguments["source"]["path"].as_str() else {
+            return self.respond_error(request, "setBreakpoints requires `source.path`");
+        };
+        let lines = arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|b| b["line"].as_i64())
+            .collect();
+        let path = PathBuf::from(path);
+        self.source_breakpoints.insert(path.clone(), lines);
+
+        let breakpoints = self
+            .resolve_breakpoints()
+            .0
+            .remove(&path)
+            .unwrap_or_default();
+        self.respond(request, json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Map requested source lines and function names to addresses.
+    /// Returns the DAP breakpoint objects for sources and functions.
+    fn resolve_breakpoints(&mut self) -> (HashMap<PathBuf, Vec<Value>>, Vec<Value>) {
+        let mut sources = HashMap::new();
+        let mut functions = Vec::new();
+
+        let Some(debugger) = self.debugger.as_mut() else {
+            for (path, lines) in &self.source_breakpoints {
+                let unverified = lines
+                    .iter()
+                    .map(|line| json!({ "verified": false, "line": line }))
+                    .collect();
+                sources.insert(path.clone(), unverified);
+            }
+            for _ in &self.function_breakpoints {
+                functions.push(json!({ "verified": false }));
+            }
+            return (sources, functions);
+        };
+
+        let info = &debugger.debug_info;
+        debugger.breakpoints.clear();
+        debugger.watchpoints = self.watchpoints.iter().copied().collect();
+
+        for (path, lines) in &self.source_breakpoints {
+            let is_source = info
+                .source
+                .as_deref()
+                .is_some_and(|source| same_file(source, path));
+
+            let mut resolved = Vec::new();
+            for &line in lines {
+                // move breakpoints on labels, blank lines and comments to
+                // the next instruction
+                let found = (line.max(1) as usize..line.max(1) as usize + 20)
+                    .filter(|_| is_source)
+                    .find_map(|line| Some((line, info.lines.address_of_line(line)?)));
+                match found {
+                    Some((actual, address)) => {
+                        debugger.breakpoints.insert(address);
+                        resolved.push(json!({
+                            "verified": true,
+                            "line": actual,
+                            "instructionReference": format!("x{:04X}", address),
+                        }));
+                    }
+                    None => resolved.push(json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "no instruction at this line",
+                    })),
+                }
+            }
+            sources.insert(path.clone(), resolved);
+        }
+
+        for name in &self.function_breakpoints {
+            match info.symbols.address_of(name) {
+                Some(address) => {
+                    debugger.breakpoints.insert(address);
+                    functions.push(json!({
+                        "verified": true,
+                        "instructionReference": format!("x{:04X}", address),
+                    }));
+                }
+                None => functions.push(json!({
+                    "verified": false,
+                    "message": format!("unknown label `{}`", name),
+                })),
+            }
+        }
+
+        (sources, functions)
+    }
+
+    fn start(&mut self, mode: RunMode) -> io::Result<()> {
+        self.debugger.as_mut().unwrap().resume(mode);
+        self.state = State::Running;
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.state = State::Stopped;
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        let bytes = self.console.borrow_mut().take_output();
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+        self.event("output", json!({ "category": "stdout", "output": text }))
+    }
+
+    fn run_slice(&mut self) -> io::Result<()> {
+        let reason = self.debugger.as_mut().unwrap().run(RUN_SLICE);
+        self.flush_output()?;
+        self.report_diagnostics()?;
+
+        match reason {
+            None => Ok(()),
+            Some(StopReason::Step) => self.stopped("step"),
+            Some(StopReason::Breakpoint(_)) => self.stopped("breakpoint"),
+            Some(StopReason::Watchpoint(_)) => self.stopped("data breakpoint"),
+            Some(StopReason::StartOfHistory) => {
+                self.console("Reached the start of the recorded history\n")?;
+                self.stopped("step")
+            }
+            Some(StopReason::Fault(fault)) => {
+                self.console(&format!("{}\n", fault))?;
+                self.stopped("exception")
+            }
+            Some(StopReason::WaitingForInput) => {
+                self.state = State::WaitingForInput;
+                self.console("Program is waiting for input, type `>text` in the debug console\n")
+            }
+            Some(StopReason::Halted) => {
+                self.state = State::Stopped;
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                self.event("terminated", json!({}))
+            }
+        }
+    }
+
+    fn stack_frames(&self) -> Vec<Value> {
+        let debugger = self.debugger.as_ref().unwrap();
+        let info = &debugger.debug_info;
+
+        debugger
+            .backtrace()
+            .into_iter()
+            .enumerate()
+            .map(|(id, backtrace_frame)| {
+                let pc = backtrace_frame.address;
+                let name = match (&backtrace_frame.function, &backtrace_frame.location) {
+                    (Some(function), _) => function.clone(),
+                    (None, Some((label, 0))) => label.clone(),
+                    (None, Some((label, offset))) => format!("{}+{}", label, offset),
+                    (None, None) => format!("x{:04X}", pc),
+                };
+
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("x{:04X}", pc),
+                });
+                if let (Some(source), Some(line)) = (&info.source, info.lines.line_of_address(pc)) {
+                    frame["source"] = json!({
+                        "name": source.file_name().map(|n| n.to_string_lossy()),
+                        "path": source,
+                    });
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect()
+    }
+
+    /// Print call stack problems found since the last report
+    fn report_diagnostics(&mut self) -> io::Result<()> {
+        let diagnostics = self.debugger.as_ref().unwrap().vm.call_stack.diagnostics();
+        let text: String = diagnostics[self.reported_diagnostics..]
+            .iter()
+            .map(|diagnostic| format!("warning: {}\n", diagnostic))
+            .collect();
+        self.reported_diagnostics = diagnostics.len();
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.console(&text)
+    }
+
+    fn variables(&self, reference: Option<i64>) -> Vec<Value> {
+        let vm = &self.debugger.as_ref().unwrap().vm;
+        match reference {
+            Some(REGISTERS_REFERENCE) => REGISTER_NAMES
+                .iter()
+                .zip(vm.registers)
+                .map(|(name, value)| {
+                    json!({
+                        "name": name,
+                        "value": format!("x{:04X} ({})", value, value as i16),
+                        "variablesReference": 0,
+                        "memoryReference": format!("x{:04X}", value),
+                    })
+                })
+                .collect(),
+            Some(FLAGS_REFERENCE) => {
+                let condition = vm.read_register(Registers::Condition);
+                [("N", FL_NEG), ("Z", FL_ZRO), ("P", FL_POS)]
+                    .iter()
+                    .map(|(name, flag)| {
+                        json!({
+                            "name": name,
+                            "value": (condition & flag != 0).to_string(),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn read_memory(&mut self, request: &Value) -> io::Result<()> {
+        let arguments = &request["arguments"];
+        let Some(base) = arguments["memoryReference"]
+            .as_str()
+            .and_then(parse_address)
+        else {
+            return self.respond_error(request, "invalid memory reference");
+        };
+        let offset = arguments["offset"].as_i64().unwrap_or(0);
+        let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+
+        let vm = &self.debugger.as_ref().unwrap().vm;
+        let start = (base as i64 * 2 + offset).rem_euclid(1 << 17) as usize;
+        let bytes: Vec<u8> = (start..start + count)
+            .map(|byte| {
+                let word = vm.peek(((byte >> 1) & 0xFFFF) as u16);
+                if byte % 2 == 0 {
+                    (word >> 8) as u8
+                } else {
+                    word as u8
+                }
+            })
+            .collect();
+
+        self.respond(
+            request,
+            json!({
+                "address": format!("x{:04X}", (start >> 1) & 0xFFFF),
+                "data": base64(&bytes),
+            }),
+        )
+    }
+
+    /// Memory words can be watched by label or address
+    fn data_breakpoint_info(&mut self, request: &Value) -> io::Result<()> {
+        let arguments = &request["arguments"];
+        let name = arguments["name"].as_str().unwrap_or_default().trim();
+
+        let address = match (&self.debugger, arguments["variablesReference"].as_i64()) {
+            // registers are not memory
+            (_, Some(reference)) if reference != 0 => None,
+            (Some(debugger), _) => debugger
+                .debug_info
+                .symbols
+                .address_of(name)
+                .or_else(|| parse_address(name)),
+            (None, _) => parse_address(name),
+        };
+
+        let body = match address {
+            Some(address) => json!({
+                "dataId": format!("x{:04X}", address),
+                "description": format!("[x{:04X}] changes", address),
+                "accessTypes": ["write"],
+            }),
+            None => json!({
+                "dataId": null,
+                "description": format!("`{}` is not a memory address or label", name),
+            }),
+        };
+        self.respond(request, body)
+    }
+
+    fn evaluate(&mut self, request: &Value) -> io::Result<()> {
+        let expression = request["arguments"]["expression"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        if let Some(text) = expression.strip_prefix('>') {
+            self.console.borrow_mut().input.extend(text.bytes());
+            if self.state == State::WaitingForInput {
+                self.state = State::Running;
+            }
+            return self.respond(request, json!({ "result": "", "variablesReference": 0 }));
+        }
+
+        if let Some(path) = expression.strip_prefix(".save ") {
+            let path = path.trim();
+            let saved = self.debugger.as_ref().unwrap().vm.snapshot().save(path);
+            return match saved {
+                Ok(()) => self.respond(
+                    request,
+                    json!({ "result": format!("saved {}", path), "variablesReference": 0 }),
+                ),
+                Err(e) => self.respond_error(request, &format!("{}: {}", path, e)),
+            };
+        }
+
+        if let Some(path) = expression.strip_prefix(".frame ") {
+            let path = path.trim();
+            let frame = Frame::capture(self.debugger.as_ref().unwrap().vm.memory.words());
+            let bytes = match path.ends_with(".png") {
+                true => frame.to_png(),
+                false => frame.to_ppm(),
+            };
+            return match std::fs::write(path, bytes) {
+                Ok(()) => self.respond(
+                    request,
+                    json!({ "result": format!("saved {}", path), "variablesReference": 0 }),
+                ),
+                Err(e) => self.respond_error(request, &format!("{}: {}", path, e)),
+            };
+        }
+
+        let debugger = self.debugger.as_ref().unwrap();
+        let name = expression.trim();
+        let register = REGISTER_NAMES
+            .iter()
+            .position(|r| r.eq_ignore_ascii_case(name));
+
+        let result = if let Some(index) = register {
+            let value = debugger.vm.registers[index];
+            Some(format!("x{:04X} ({})", value, value as i16))
+        } else {
+            debugger
+                .debug_info
+                .symbols
+                .address_of(name)
+                .or_else(|| parse_address(name))
+                .map(|address| {
+                    let value = debugger.vm.peek(address);
+                    format!("[x{:04X}] = x{:04X} ({})", address, value, value as i16)
+                })
+        };
+
+        match result {
+            Some(result) => self.respond(
+                request,
+                json!({ "result": result, "variablesReference": 0 }),
+            ),
+            None => self.respond_error(request, &format!("cannot evaluate `{}`", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(seq: i64, command: &str, arguments: Value) -> Value {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    fn messages(bytes: &[u8]) -> Vec<Value> {
+        let mut reader = io::BufReader::new(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(&[0x30, 0x00, 0xFF]), "MAD/");
+    }
+
+    #[test]
+    fn test_session_stops_on_source_breakpoint() {
+        let (sender, receiver) = mpsc::channel();
+        let source = std::fs::canonicalize("./resources/2048.asm").unwrap();
+        for message in [
+            request(1, "initialize", json!({ "adapterID": "lc3" })),
+            request(2, "launch", json!({ "program": "./resources/2048.asm" })),
+            // line 38 is the blank line before `LEA R0, PROMPT_TYPE_MESSAGE`
+            request(
+                3,
+                "setBreakpoints",
+                json!({ "source": { "path": source }, "breakpoints": [{ "line": 38 }] }),
+            ),
+            request(4, "configurationDone", json!({})),
+        ] {
+            sender.send(message).unwrap();
+        }
+
+        let mut server = DapServer::new(Vec::new());
+        // serve until the breakpoint is hit, then close the channel
+        let stopped_after = {
+            while server.state != State::Running {
+                let message = receiver.recv().unwrap();
+                server.handle(&message).unwrap();
+            }
+            while server.state == State::Running {
+                server.run_slice().unwrap();
+            }
+            server.state
+        };
+        assert_eq!(stopped_after, State::Stopped);
+
+        let pc = server.debugger.as_ref().unwrap().pc();
+        assert_eq!(pc, 0x3004);
+
+        let sent = messages(&server.writer);
+        assert!(sent
+            .iter()
+            .any(|m| m["event"] == "stopped" && m["body"]["reason"] == "breakpoint"));
+        assert!(sent.iter().any(|m| m["event"] == "output"
+            && m["body"]["output"]
+                .as_str()
+                .unwrap()
+                .contains("Control the game using WASD keys.")));
+    }
+}
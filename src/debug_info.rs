@@ -0,0 +1,178 @@
+//! Symbols and source lines for a loaded program
+//!
+//! Symbols come either from the assembler or from the `.sym` file written
+//! next to an `.obj` by `lc3as`:
+//!
+//! ```text
+//! //    Symbol Name       Page Address
+//! //    ----------------  ------------
+//! //    MAIN              3000
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// `x3000`, `0x3000`, `#12288` or `12288`
+pub fn parse_address(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(hex) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix(['x', 'X']))
+    {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    text.strip_prefix('#').unwrap_or(text).parse().ok()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    names: HashMap<String, u16>,
+    // first label defined at each address
+    addresses: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn load_from_file<P: AsRef<Path>>(file_path: P) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(file_path)?))
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut table = Self::default();
+
+        for line in text.lines() {
+            let line = line.trim_start_matches('/').trim();
+            let mut parts = line.split_whitespace();
+            let (Some(name), Some(address), None) = (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            if let Ok(address) = u16::from_str_radix(address, 16) {
+                table.insert(name, address);
+            }
+        }
+        table
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.names.insert(name.to_string(), address);
+        self.addresses
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.names.get(name).copied()
+    }
+
+    /// An address or a label
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        parse_address(text).or_else(|| self.address_of(text.trim()))
+    }
+
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.addresses.get(&address).map(String::as_str)
+    }
+
+    /// Closest label at or before `address`
+    pub fn nearest(&self, address: u16) -> Option<(&str, u16)> {
+        self.addresses
+            .range(..=address)
+            .next_back()
+            .map(|(address, name)| (name.as_str(), *address))
+    }
+
+    /// Symbols ordered by address
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        let mut symbols: Vec<(&str, u16)> = self
+            .names
+            .iter()
+            .map(|(name, address)| (name.as_str(), *address))
+            .collect();
+        symbols.sort_by_key(|(name, address)| (*address, *name));
+        symbols.into_iter()
+    }
+
+    /// Render in the `.sym` format understood by [`SymbolTable::parse`]
+    pub fn to_sym_string(&self) -> String {
+        let mut text = String::from(
+            "// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n//\t----------------  ------------\n",
+        );
+        for (name, address) in self.iter() {
+            text.push_str(&format!("//\t{:<16}  {:04X}\n", name, address));
+        }
+        text
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEntry {
+    pub line: usize,
+    pub address: u16,
+    pub size: u16,
+    /// false for `.FILL`, `.BLKW` and `.STRINGZ`
+    pub is_code: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    // keyed by first address of each line
+    entries: BTreeMap<u16, LineEntry>,
+    lines: HashMap<usize, u16>,
+}
+
+impl LineTable {
+    pub fn insert(&mut self, line: usize, address: u16, size: u16, is_code: bool) {
+        if size == 0 {
+            return;
+        }
+        self.entries.insert(
+            address,
+            LineEntry {
+                line,
+                address,
+                size,
+                is_code,
+            },
+        );
+        self.lines.insert(line, address);
+    }
+
+    /// Address of the instruction on `line`, if it holds one
+    pub fn address_of_line(&self, line: usize) -> Option<u16> {
+        let address = *self.lines.get(&line)?;
+        self.entries[&address].is_code.then_some(address)
+    }
+
+    pub fn line_of_address(&self, address: u16) -> Option<usize> {
+        self.entry_at(address).map(|entry| entry.line)
+    }
+
+    pub fn entry_at(&self, address: u16) -> Option<&LineEntry> {
+        let (_, entry) = self.entries.range(..=address).next_back()?;
+        ((address as u32) < entry.address as u32 + entry.size as u32).then_some(entry)
+    }
+
+    /// Entries in address order
+    pub fn entries(&self) -> impl Iterator<Item = &LineEntry> {
+        self.entries.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    /// `.asm` file the line table refers to
+    pub source: Option<PathBuf>,
+    pub symbols: SymbolTable,
+    pub lines: LineTable,
+}
@@ -0,0 +1,416 @@
+//! Breakpoints and stepping on top of [`VmCPU`]
+//!
+//! The debugger never blocks: [`Debugger::run`] executes at most `budget`
+//! instructions and returns, so a front end can keep servicing its own
+//! requests while a program is running.
+
+use std::{collections::BTreeSet, fs, io, path::Path};
+
+use crate::{
+    assembler,
+    callstack::BacktraceFrame,
+    cpu::{StepResult, VmCPU},
+    debug_info::{DebugInfo, SymbolTable},
+    error::Fault,
+    history::History,
+    instructions::Instructions,
+    memory::{self, Memory},
+    register::Registers,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    /// a watched address changed value
+    Watchpoint(u16),
+    Halted,
+    WaitingForInput,
+    /// reverse execution ran out of recorded history
+    StartOfHistory,
+    /// the next instruction cannot be executed
+    Fault(Fault),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    Continue,
+    /// one instruction
+    StepIn,
+    /// run until the instruction after the current one, skipping calls
+    StepOver {
+        return_address: u16,
+        /// call stack depth at the call
+        depth: usize,
+    },
+    /// run until the call stack is shallower than `depth`
+    StepOut {
+        depth: usize,
+    },
+    /// undo one instruction
+    ReverseStep,
+    /// undo instructions until a breakpoint or watchpoint
+    ReverseContinue,
+}
+
+#[derive(Debug)]
+pub struct Debugger {
+    pub vm: VmCPU,
+    pub debug_info: DebugInfo,
+    pub breakpoints: BTreeSet<u16>,
+    /// addresses that stop execution when their value changes
+    pub watchpoints: BTreeSet<u16>,
+    mode: RunMode,
+    // instructions executed since the last resume
+    executed: usize,
+    halted: bool,
+}
+
+/// Load an `.obj` or `.asm` program together with whatever debug information
+/// sits next to it (`.sym`, `.asm`).
+pub fn load_program(program: &Path, sym: Option<&Path>) -> io::Result<(Memory, DebugInfo)> {
+    let (origin, words, debug_info) = read_program(program, sym)?;
+    Ok((Memory::load_from_words(origin, &words), debug_info))
+}
+
+/// Origin and words of an `.obj` or `.asm` program, with its debug
+/// information as for [`load_program`]
+pub fn read_program(program: &Path, sym: Option<&Path>) -> io::Result<(u16, Vec<u16>, DebugInfo)> {
+    let is_asm = program
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("asm"));
+
+    if is_asm {
+        let assembly = assembler::assemble_file(program)?;
+        let debug_info = DebugInfo {
+            source: Some(program.to_path_buf()),
+            symbols: assembly.symbols,
+            lines: assembly.lines,
+        };
+        return Ok((assembly.origin, assembly.words, debug_info));
+    }
+
+    let (origin, words) = memory::parse_obj(&fs::read(program)?)?;
+    let mut debug_info = DebugInfo::default();
+
+    let sym = sym
+        .map(Path::to_path_buf)
+        .or_else(|| Some(program.with_extension("sym")).filter(|p| p.exists()));
+    if let Some(sym) = sym {
+        debug_info.symbols = SymbolTable::load_from_file(sym)?;
+    }
+
+    // only trust the source if it assembles to the image we loaded
+    let source = program.with_extension("asm");
+    if let Ok(assembly) = assembler::assemble_file(&source) {
+        if assembly.origin == origin && words.starts_with(&assembly.words) {
+            if debug_info.symbols.is_empty() {
+                debug_info.symbols = assembly.symbols;
+            }
+            debug_info.lines = assembly.lines;
+            debug_info.source = Some(source);
+        }
+    }
+
+    Ok((origin, words, debug_info))
+}
+
+impl Debugger {
+    /// Attaches a [`History`] to `vm` if it has none, so the program can be
+    /// run backwards.
+    pub fn new(mut vm: VmCPU, debug_info: DebugInfo) -> Self {
+        vm.history.get_or_insert_with(History::default);
+        Self {
+            vm,
+            debug_info,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            mode: RunMode::Continue,
+            executed: 0,
+            halted: false,
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.vm.read_register(Registers::ProgramCounter)
+    }
+
+    /// Number of instructions that can be undone
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.vm
+            .history
+            .get_or_insert_with(History::default)
+            .set_depth(depth);
+    }
+
+    fn watched_values(&self) -> Vec<u16> {
+        self.watchpoints
+            .iter()
+            .map(|address| self.vm.peek(*address))
+            .collect()
+    }
+
+    fn changed_watchpoint(&self, before: &[u16]) -> Option<u16> {
+        self.watchpoints
+            .iter()
+            .zip(before)
+            .find(|(address, value)| self.vm.peek(**address) != **value)
+            .map(|(address, _)| *address)
+    }
+
+    /// Instruction at the PC, decoded without touching devices
+    pub fn current_instruction(&self) -> Instructions {
+        Instructions::from(self.vm.peek(self.pc()))
+    }
+
+    /// Prepare the next [`Debugger::run`] call
+    pub fn resume(&mut self, mode: RunMode) {
+        self.mode = mode;
+        self.executed = 0;
+    }
+
+    pub fn step_over_mode(&self) -> RunMode {
+        match self.current_instruction() {
+            Instructions::JumpRegister(_) => RunMode::StepOver {
+                return_address: self.pc().wrapping_add(1),
+                depth: self.vm.call_stack.depth(),
+            },
+            _ => RunMode::StepIn,
+        }
+    }
+
+    pub fn step_out_mode(&self) -> RunMode {
+        RunMode::StepOut {
+            depth: self.vm.call_stack.depth(),
+        }
+    }
+
+    /// Active frames, innermost first, named from the symbol table
+    pub fn backtrace(&self) -> Vec<BacktraceFrame> {
+        self.vm
+            .call_stack
+            .backtrace(self.pc(), &self.debug_info.symbols)
+    }
+
+    /// Execute up to `budget` instructions. Returns `None` when the budget
+    /// ran out before anything worth stopping for happened.
+    pub fn run(&mut self, budget: usize) -> Option<StopReason> {
+        if matches!(self.mode, RunMode::ReverseStep | RunMode::ReverseContinue) {
+            return self.run_backwards(budget);
+        }
+        if self.halted {
+            return Some(StopReason::Halted);
+        }
+
+        for _ in 0..budget {
+            let pc = self.pc();
+            if self.executed > 0 && self.breakpoints.contains(&pc) {
+                return Some(StopReason::Breakpoint(pc));
+            }
+
+            let watched = self.watched_values();
+            match self.vm.step() {
+                StepResult::Continue => {}
+                StepResult::Halted => {
+                    self.halted = true;
+                    return Some(StopReason::Halted);
+                }
+                StepResult::WaitingForInput => return Some(StopReason::WaitingForInput),
+                StepResult::Fault(fault) => return Some(StopReason::Fault(fault)),
+            }
+            self.executed += 1;
+
+            if let Some(address) = self.changed_watchpoint(&watched) {
+                return Some(StopReason::Watchpoint(address));
+            }
+
+            let depth = self.vm.call_stack.depth();
+            let stop = match self.mode {
+                RunMode::StepIn => true,
+                RunMode::StepOver {
+                    return_address,
+                    depth: call_depth,
+                } => self.pc() == return_address && depth <= call_depth,
+                RunMode::StepOut { depth: call_depth } => depth < call_depth,
+                _ => false,
+            };
+            if stop {
+                return Some(StopReason::Step);
+            }
+        }
+        None
+    }
+
+    fn run_backwards(&mut self, budget: usize) -> Option<StopReason> {
+        for _ in 0..budget {
+            let watched = self.watched_values();
+            if !self.vm.step_back() {
+                return Some(StopReason::StartOfHistory);
+            }
+            self.halted = false;
+            self.executed += 1;
+
+            if let Some(address) = self.changed_watchpoint(&watched) {
+                return Some(StopReason::Watchpoint(address));
+            }
+            let pc = self.pc();
+            if self.mode == RunMode::ReverseStep {
+                return Some(StopReason::Step);
+            }
+            if self.breakpoints.contains(&pc) {
+                return Some(StopReason::Breakpoint(pc));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    fn debugger(source: &str) -> Debugger {
+        let assembly = assembler::assemble(source).unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; 10], memory);
+        vm.console = Box::new(BufferConsole::default());
+        Debugger::new(vm, DebugInfo::default())
+    }
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+        AND R0, R0, #0
+        JSR SUB
+        ADD R0, R0, #1
+        HALT
+SUB     ADD R0, R0, #2
+        RET
+        .END
+    ";
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let mut debugger = debugger(PROGRAM);
+        debugger.breakpoints.insert(0x3004);
+
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Breakpoint(0x3004)));
+
+        // resuming from a breakpoint does not stop on it again
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Halted));
+        assert_eq!(debugger.vm.registers[0], 3);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut debugger = debugger(PROGRAM);
+
+        debugger.resume(RunMode::StepIn);
+        assert_eq!(debugger.run(100), Some(StopReason::Step));
+        assert_eq!(debugger.pc(), 0x3001);
+
+        let mode = debugger.step_over_mode();
+        debugger.resume(mode);
+        assert_eq!(debugger.run(100), Some(StopReason::Step));
+        assert_eq!(debugger.pc(), 0x3002);
+        assert_eq!(debugger.vm.registers[0], 2);
+
+        let mut debugger = self::debugger(PROGRAM);
+        debugger.breakpoints.insert(0x3004);
+        debugger.resume(RunMode::Continue);
+        debugger.run(100);
+
+        let mode = debugger.step_out_mode();
+        debugger.resume(mode);
+        assert_eq!(debugger.run(100), Some(StopReason::Step));
+        assert_eq!(debugger.pc(), 0x3002);
+        assert_eq!(debugger.vm.call_stack.depth(), 0);
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut debugger = debugger(PROGRAM);
+        debugger.breakpoints.insert(0x3004);
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Breakpoint(0x3004)));
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Halted));
+        assert_eq!(debugger.vm.registers[0], 3);
+
+        debugger.resume(RunMode::ReverseStep);
+        assert_eq!(debugger.run(100), Some(StopReason::Step));
+        assert_eq!(debugger.pc(), 0x3003);
+
+        debugger.resume(RunMode::ReverseContinue);
+        assert_eq!(debugger.run(100), Some(StopReason::Breakpoint(0x3004)));
+        assert_eq!(debugger.vm.registers[0], 0);
+        // the RET was undone, so SUB is active again
+        assert_eq!(debugger.vm.call_stack.depth(), 1);
+
+        debugger.resume(RunMode::ReverseContinue);
+        assert_eq!(debugger.run(100), Some(StopReason::StartOfHistory));
+        assert_eq!(debugger.pc(), 0x3000);
+
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Breakpoint(0x3004)));
+    }
+
+    #[test]
+    fn test_watchpoint_both_directions() {
+        let mut debugger = debugger(
+            "
+        .ORIG x3000
+        ADD R0, R0, #5
+        ST R0, VALUE
+        ADD R0, R0, #1
+        HALT
+VALUE   .FILL #0
+        .END
+    ",
+        );
+        debugger.watchpoints.insert(0x3004);
+
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Watchpoint(0x3004)));
+        assert_eq!(debugger.pc(), 0x3002);
+
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Halted));
+
+        debugger.resume(RunMode::ReverseContinue);
+        assert_eq!(debugger.run(100), Some(StopReason::Watchpoint(0x3004)));
+        assert_eq!(debugger.pc(), 0x3001);
+        assert_eq!(debugger.vm.memory.peek(0x3004), 0);
+    }
+
+    #[test]
+    fn test_input_is_replayed_after_reverse() {
+        let mut debugger = debugger(
+            "
+        .ORIG x3000
+        GETC
+        GETC
+        HALT
+        .END
+    ",
+        );
+        debugger.vm.console = Box::new(BufferConsole::new(b"ab"));
+
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Halted));
+        assert_eq!(debugger.vm.registers[0], b'b' as u16);
+
+        for _ in 0..2 {
+            debugger.resume(RunMode::ReverseStep);
+            debugger.run(1);
+        }
+        assert_eq!(debugger.pc(), 0x3001);
+
+        // input is exhausted, the second key has to come from the replay
+        debugger.resume(RunMode::Continue);
+        assert_eq!(debugger.run(100), Some(StopReason::Halted));
+        assert_eq!(debugger.vm.registers[0], b'b' as u16);
+    }
+}
@@ -0,0 +1,43 @@
+use super::{Device, DeviceContext};
+
+const STATUS: u16 = 0;
+const DATA: u16 = 2;
+
+/// DSR bit set when the display accepts a character
+const DISPLAY_READY: u16 = 1 << 15;
+
+/// DSR and DDR. Characters written to DDR go straight to the console, so the
+/// display is always ready.
+#[derive(Debug, Default, Clone)]
+pub struct Display;
+
+impl Device for Display {
+    fn name(&self) -> &str {
+        "display"
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn read(&mut self, offset: u16, _context: &mut DeviceContext) -> u16 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u16, context: &mut DeviceContext) {
+        if offset == DATA {
+            context
+                .console
+                .write_byte(value as u8)
+                .and_then(|_| context.console.flush())
+                .expect("Failed to write output");
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            STATUS => DISPLAY_READY,
+            _ => 0,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use super::{Device, DeviceContext, Interrupt};
+
+pub const KEY_BOARD_STATUS: u16 = 0xFE00;
+pub const KEY_BOARD_DATA: u16 = 0xFE02;
+
+const STATUS: u16 = 0;
+const DATA: u16 = KEY_BOARD_DATA - KEY_BOARD_STATUS;
+
+/// KBSR bit set while a key is waiting in KBDR
+pub const KEY_READY: u16 = 1 << 15;
+/// KBSR bit that enables the keyboard interrupt
+pub const INTERRUPT_ENABLE: u16 = 1 << 14;
+
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+const KEYBOARD_PRIORITY: u8 = 4;
+
+/// KBSR and KBDR.
+///
+/// Reading KBSR never blocks: if no key is latched yet it checks the console
+/// and latches a ready key into KBDR. Reading KBDR consumes the latched key
+/// and clears the ready bit.
+#[derive(Debug, Default, Clone)]
+pub struct Keyboard {
+    status: u16,
+    data: u16,
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn read(&mut self, offset: u16, context: &mut DeviceContext) -> u16 {
+        match offset {
+            STATUS => {
+                let ready = self.status & KEY_READY != 0
+                    || context.console.poll().expect("Failed to poll keyboard");
+                if self.status & KEY_READY == 0 && ready {
+                    if let Some(key) = context
+                        .console
+                        .read_byte()
+                        .expect("Failed to read keyboard")
+                    {
+                        self.data = key as u16;
+                        self.status |= KEY_READY;
+                    }
+                }
+                self.status
+            }
+            DATA => {
+                self.status &= !KEY_READY;
+                self.data
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u16, _context: &mut DeviceContext) {
+        if offset == STATUS {
+            self.status = (self.status & !INTERRUPT_ENABLE) | (value & INTERRUPT_ENABLE);
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            STATUS => self.status,
+            DATA => self.data,
+            _ => 0,
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let wanted = KEY_READY | INTERRUPT_ENABLE;
+        (self.status & wanted == wanted).then_some(Interrupt {
+            vector: KEYBOARD_VECTOR,
+            priority: KEYBOARD_PRIORITY,
+        })
+    }
+
+    fn save(&self) -> Vec<u8> {
+        [self.status.to_be_bytes(), self.data.to_be_bytes()].concat()
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if let [s0, s1, d0, d1] = *state {
+            self.status = u16::from_be_bytes([s0, s1]);
+            self.data = u16::from_be_bytes([d0, d1]);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use super::{Device, DeviceContext};
+
+/// MCR bit that keeps the clock running
+const CLOCK_ENABLE: u16 = 1 << 15;
+
+/// Machine control register. Clearing the clock enable bit stops the
+/// machine, which is how an OS implements HALT.
+#[derive(Debug, Clone)]
+pub struct MachineControl {
+    value: u16,
+}
+
+impl Default for MachineControl {
+    fn default() -> Self {
+        Self {
+            value: CLOCK_ENABLE,
+        }
+    }
+}
+
+impl Device for MachineControl {
+    fn name(&self) -> &str {
+        "mcr"
+    }
+
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, _offset: u16, _context: &mut DeviceContext) -> u16 {
+        self.value
+    }
+
+    fn write(&mut self, _offset: u16, value: u16, context: &mut DeviceContext) {
+        self.value = value;
+        if value & CLOCK_ENABLE == 0 {
+            context.halt = true;
+        }
+    }
+
+    fn peek(&self, _offset: u16) -> u16 {
+        self.value
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.value.to_be_bytes().to_vec()
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if let [high, low] = *state {
+            self.value = u16::from_be_bytes([high, low]);
+        }
+    }
+}
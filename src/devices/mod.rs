@@ -0,0 +1,302 @@
+//! Memory mapped devices
+//!
+//! The page from xFE00 to xFFFF belongs to devices. A [`Bus`] routes reads
+//! and writes there to the [`Device`] attached at that address; addresses
+//! no device claims behave like ordinary memory.
+//!
+//! | address | register                  | device              |
+//! |---------|---------------------------|---------------------|
+//! | xFE00   | KBSR, keyboard status     | [`Keyboard`]        |
+//! | xFE02   | KBDR, keyboard data       | [`Keyboard`]        |
+//! | xFE04   | DSR, display status       | [`Display`]         |
+//! | xFE06   | DDR, display data         | [`Display`]         |
+//! | xFE08   | TSR, timer status         | [`Timer`]           |
+//! | xFE09   | TCR, timer control        | [`Timer`]           |
+//! | xFE0A   | TRR, timer reload count   | [`Timer`]           |
+//! | xFE0B   | TVR, timer vector         | [`Timer`]           |
+//! | xFE0C   | TCNT, timer count         | [`Timer`]           |
+//! | xFE10   | RNG, random number        | [`Random`]          |
+//! | xFE11   | RNGS, random seed         | [`Random`]          |
+//! | xFFFE   | MCR, machine control      | [`MachineControl`]  |
+//!
+//! A device requests an interrupt through [`Device::pending_interrupt`];
+//! the CPU takes it before the next instruction when its priority is above
+//! the one in the PSR.
+//!
+//! Devices keep their registers themselves. [`Device::save`] and
+//! [`Device::restore`] expose that state to snapshots and reverse
+//! execution.
+
+mod display;
+mod keyboard;
+mod mcr;
+mod random;
+mod timer;
+
+use std::{collections::BTreeMap, fmt};
+
+pub use display::Display;
+pub use keyboard::{Keyboard, KEY_BOARD_DATA, KEY_BOARD_STATUS, KEY_READY};
+pub use mcr::MachineControl;
+pub use random::{Random, DEFAULT_SEED, RANDOM_BASE};
+pub use timer::{Timer, TIMER_BASE};
+
+use crate::console::Console;
+
+pub const DEVICE_PAGE: u16 = 0xFE00;
+
+/// What a device can reach while it is accessed or ticked
+pub struct DeviceContext<'a> {
+    /// keyboard and display of the VM
+    pub console: &'a mut dyn Console,
+    /// set to stop the machine after the current instruction
+    pub halt: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+    /// entry in the interrupt vector table, x0100 + vector
+    pub vector: u8,
+    /// 0 to 7, serviced only above the running priority
+    pub priority: u8,
+}
+
+pub trait Device {
+    /// Unique on a bus, identifies the state in snapshots
+    fn name(&self) -> &str;
+
+    /// Number of words the device occupies from its base address
+    fn size(&self) -> u16;
+
+    fn read(&mut self, offset: u16, context: &mut DeviceContext) -> u16;
+
+    fn write(&mut self, offset: u16, value: u16, context: &mut DeviceContext);
+
+    /// Read without side effects, for debuggers
+    fn peek(&self, offset: u16) -> u16;
+
+    /// Called after every instruction
+    fn tick(&mut self, _context: &mut DeviceContext) {}
+
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        None
+    }
+
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, _state: &[u8]) {}
+}
+
+#[derive(Default)]
+pub struct Bus {
+    // base address and device, ordered by base
+    devices: Vec<(u16, Box<dyn Device>)>,
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.devices
+                    .iter()
+                    .map(|(base, device)| (format!("x{:04X}", base), device.name())),
+            )
+            .finish()
+    }
+}
+
+impl Bus {
+    /// Keyboard, display, timer, random numbers and machine control
+    /// register at their usual addresses
+    pub fn standard() -> Self {
+        Self::seeded(DEFAULT_SEED)
+    }
+
+    /// Standard devices with the random numbers starting from `seed`
+    pub fn seeded(seed: u64) -> Self {
+        let mut bus = Self::default();
+        bus.attach(KEY_BOARD_STATUS, Box::new(Keyboard::default()));
+        bus.attach(0xFE04, Box::new(Display));
+        bus.attach(TIMER_BASE, Box::new(Timer::default()));
+        bus.attach(RANDOM_BASE, Box::new(Random::new(seed)));
+        bus.attach(0xFFFE, Box::new(MachineControl::default()));
+        bus
+    }
+
+    /// Map `device` at `base`. Panics if it does not fit in the device page
+    /// or overlaps a device already attached.
+    pub fn attach(&mut self, base: u16, device: Box<dyn Device>) {
+        let end = base as u32 + device.size() as u32;
+        assert!(
+            base >= DEVICE_PAGE && end <= 1 << 16,
+            "{} does not fit in the device page at x{:04X}",
+            device.name(),
+            base
+        );
+        for (other_base, other) in &self.devices {
+            let other_end = *other_base as u32 + other.size() as u32;
+            assert!(
+                end <= *other_base as u32 || other_end <= base as u32,
+                "{} at x{:04X} overlaps {}",
+                device.name(),
+                base,
+                other.name()
+            );
+            assert_ne!(device.name(), other.name(), "device names must be unique");
+        }
+
+        let index = self.devices.partition_point(|(other, _)| *other < base);
+        self.devices.insert(index, (base, device));
+    }
+
+    /// Device by name, e.g. to configure it after attaching
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut dyn Device> {
+        self.devices
+            .iter_mut()
+            .find(|(_, device)| device.name() == name)
+            .map(|(_, device)| device.as_mut() as &mut dyn Device)
+    }
+
+    fn find(&self, address: u16) -> Option<usize> {
+        if address < DEVICE_PAGE {
+            return None;
+        }
+        let index = self
+            .devices
+            .partition_point(|(base, _)| *base <= address)
+            .checked_sub(1)?;
+        let (base, device) = &self.devices[index];
+        (((address - base) as u32) < device.size() as u32).then_some(index)
+    }
+
+    /// `None` when no device is mapped at `address`
+    pub fn read(&mut self, address: u16, context: &mut DeviceContext) -> Option<u16> {
+        let index = self.find(address)?;
+        let (base, device) = &mut self.devices[index];
+        Some(device.read(address - *base, context))
+    }
+
+    /// False when no device is mapped at `address`
+    pub fn write(&mut self, address: u16, value: u16, context: &mut DeviceContext) -> bool {
+        let Some(index) = self.find(address) else {
+            return false;
+        };
+        let (base, device) = &mut self.devices[index];
+        device.write(address - *base, value, context);
+        true
+    }
+
+    pub fn peek(&self, address: u16) -> Option<u16> {
+        let index = self.find(address)?;
+        let (base, device) = &self.devices[index];
+        Some(device.peek(address - base))
+    }
+
+    pub fn tick(&mut self, context: &mut DeviceContext) {
+        for (_, device) in self.devices.iter_mut() {
+            device.tick(context);
+        }
+    }
+
+    /// Highest priority interrupt requested by any device
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        self.devices
+            .iter()
+            .filter_map(|(_, device)| device.pending_interrupt())
+            .max_by_key(|interrupt| interrupt.priority)
+    }
+
+    /// State of every device, keyed by name
+    pub fn save(&self) -> BTreeMap<String, Vec<u8>> {
+        self.devices
+            .iter()
+            .map(|(_, device)| (device.name().to_string(), device.save()))
+            .collect()
+    }
+
+    /// States from `before` that differ from the current ones
+    pub fn changed_since(&self, before: &BTreeMap<String, Vec<u8>>) -> BTreeMap<String, Vec<u8>> {
+        self.devices
+            .iter()
+            .filter_map(|(_, device)| {
+                let (name, state) = before.get_key_value(device.name())?;
+                (device.save() != *state).then(|| (name.clone(), state.clone()))
+            })
+            .collect()
+    }
+
+    /// Restore the devices named in `states`, others keep their state
+    pub fn restore(&mut self, states: &BTreeMap<String, Vec<u8>>) {
+        for (_, device) in self.devices.iter_mut() {
+            if let Some(state) = states.get(device.name()) {
+                device.restore(state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    // one register that counts reads
+    #[derive(Default)]
+    struct Counter(u16);
+
+    impl Device for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn size(&self) -> u16 {
+            1
+        }
+
+        fn read(&mut self, _offset: u16, _context: &mut DeviceContext) -> u16 {
+            self.0 += 1;
+            self.0
+        }
+
+        fn write(&mut self, _offset: u16, value: u16, _context: &mut DeviceContext) {
+            self.0 = value;
+        }
+
+        fn peek(&self, _offset: u16) -> u16 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_routes_device_page() {
+        let mut bus = Bus::standard();
+        bus.attach(0xFE20, Box::new(Counter::default()));
+        let mut console = BufferConsole::new(b"k");
+        let mut context = DeviceContext {
+            console: &mut console,
+            halt: false,
+        };
+
+        assert_eq!(bus.read(0xFE20, &mut context), Some(1));
+        assert_eq!(bus.read(0xFE20, &mut context), Some(2));
+        assert_eq!(bus.read(0xFE21, &mut context), None);
+        assert_eq!(bus.read(0x3000, &mut context), None);
+
+        assert_eq!(bus.read(0xFE00, &mut context), Some(1 << 15));
+        assert_eq!(bus.read(0xFE02, &mut context), Some(b'k' as u16));
+
+        assert!(bus.write(0xFE06, b'!' as u16, &mut context));
+        assert!(bus.write(0xFFFE, 0, &mut context));
+        assert!(context.halt);
+        assert_eq!(console.output, b"!");
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn test_rejects_overlap() {
+        let mut bus = Bus::standard();
+        bus.attach(0xFE02, Box::new(Counter::default()));
+    }
+}
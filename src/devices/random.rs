@@ -0,0 +1,123 @@
+use super::{Device, DeviceContext};
+
+pub const RANDOM_BASE: u16 = 0xFE10;
+
+/// RNG, each read returns the next random word
+const VALUE: u16 = 0;
+/// RNGS, writing restarts the sequence from the value written
+const SEED: u16 = 1;
+
+/// Seed used unless one is given, so runs are reproducible by default
+pub const DEFAULT_SEED: u64 = 0x4C43_335F_5345_4544;
+
+/// Pseudo-random number generator (SplitMix64). The sequence depends only
+/// on the seed and the number of reads, so the same seed and the same input
+/// give the same run. Its state is saved with snapshots.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEED)
+    }
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// The word the next read of RNG returns
+    pub fn next_word(&mut self) -> u16 {
+        self.state = Self::advance(self.state);
+        Self::output(self.state)
+    }
+
+    fn output(state: u64) -> u16 {
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        ((z ^ (z >> 31)) >> 48) as u16
+    }
+
+    fn advance(state: u64) -> u64 {
+        state.wrapping_add(0x9E37_79B9_7F4A_7C15)
+    }
+}
+
+impl Device for Random {
+    fn name(&self) -> &str {
+        "random"
+    }
+
+    fn size(&self) -> u16 {
+        2
+    }
+
+    fn read(&mut self, offset: u16, _context: &mut DeviceContext) -> u16 {
+        match offset {
+            VALUE => self.next_word(),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u16, _context: &mut DeviceContext) {
+        if offset == SEED {
+            self.state = value as u64;
+        }
+    }
+
+    /// The value the next read will return
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            VALUE => Self::output(Self::advance(self.state)),
+            _ => 0,
+        }
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.state.to_be_bytes().to_vec()
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if let Ok(state) = state.try_into() {
+            self.state = u64::from_be_bytes(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    fn sequence(random: &mut Random) -> Vec<u16> {
+        let mut console = BufferConsole::default();
+        let mut context = DeviceContext {
+            console: &mut console,
+            halt: false,
+        };
+        (0..8).map(|_| random.read(VALUE, &mut context)).collect()
+    }
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let mut first = Random::new(42);
+        let expected = first.peek(VALUE);
+        let values = sequence(&mut first);
+        assert_eq!(values[0], expected);
+        assert_eq!(sequence(&mut Random::new(42)), values);
+        assert_ne!(sequence(&mut Random::new(43)), values);
+
+        let mut reseeded = Random::default();
+        let mut console = BufferConsole::default();
+        let mut context = DeviceContext {
+            console: &mut console,
+            halt: false,
+        };
+        reseeded.write(SEED, 42, &mut context);
+        assert_eq!(sequence(&mut reseeded), values);
+    }
+}
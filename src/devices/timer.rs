@@ -0,0 +1,234 @@
+use std::time::Instant;
+
+use super::{Device, DeviceContext, Interrupt};
+
+pub const TIMER_BASE: u16 = 0xFE08;
+
+/// TSR, bit 15 is set when the count runs out. Any write clears it.
+const STATUS: u16 = 0;
+/// TCR, see the `CONTROL_` bits
+const CONTROL: u16 = 1;
+/// TRR, count loaded when the timer starts and each time it runs out
+const RELOAD: u16 = 2;
+/// TVR, interrupt vector in bits [7:0] and priority in bits [10:8]
+const VECTOR: u16 = 3;
+/// TCNT, read only, what is left of the current count
+const COUNT: u16 = 4;
+
+pub const TIMER_EXPIRED: u16 = 1 << 15;
+pub const CONTROL_ENABLE: u16 = 1 << 15;
+pub const CONTROL_INTERRUPT_ENABLE: u16 = 1 << 14;
+/// count host milliseconds instead of executed instructions
+pub const CONTROL_MILLISECONDS: u16 = 1 << 0;
+/// stop after running out once instead of reloading
+pub const CONTROL_ONE_SHOT: u16 = 1 << 1;
+
+const CONTROL_MASK: u16 =
+    CONTROL_ENABLE | CONTROL_INTERRUPT_ENABLE | CONTROL_MILLISECONDS | CONTROL_ONE_SHOT;
+
+pub const TIMER_VECTOR: u8 = 0x81;
+const TIMER_PRIORITY: u8 = 6;
+
+/// Programmable interval timer.
+///
+/// While enabled the count goes down by one per executed instruction, or
+/// per host millisecond with [`CONTROL_MILLISECONDS`]. When it reaches zero
+/// TSR bit 15 is set, an interrupt is requested if enabled, and the count
+/// starts over from TRR. Instruction counting is deterministic; wall-clock
+/// mode is not and is meant for interactive programs.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    status: u16,
+    control: u16,
+    reload: u16,
+    vector: u16,
+    count: u16,
+    // host time already counted in milliseconds mode
+    last_tick: Option<Instant>,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self {
+            status: 0,
+            control: 0,
+            reload: 0,
+            vector: (TIMER_PRIORITY as u16) << 8 | TIMER_VECTOR as u16,
+            count: 0,
+            last_tick: None,
+        }
+    }
+}
+
+impl Timer {
+    fn restart(&mut self) {
+        self.count = self.reload;
+        self.last_tick = None;
+    }
+
+    fn elapse(&mut self, mut units: u64) {
+        while units > 0 && self.control & CONTROL_ENABLE != 0 {
+            if self.count == 0 {
+                // nothing to count down from
+                self.control &= !CONTROL_ENABLE;
+                break;
+            }
+            let step = units.min(self.count as u64);
+            self.count -= step as u16;
+            units -= step;
+            if self.count == 0 {
+                self.status |= TIMER_EXPIRED;
+                if self.control & CONTROL_ONE_SHOT != 0 {
+                    self.control &= !CONTROL_ENABLE;
+                } else {
+                    self.count = self.reload;
+                }
+            }
+        }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn size(&self) -> u16 {
+        5
+    }
+
+    fn read(&mut self, offset: u16, _context: &mut DeviceContext) -> u16 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u16, _context: &mut DeviceContext) {
+        match offset {
+            STATUS => self.status &= !TIMER_EXPIRED,
+            CONTROL => {
+                let starting = self.control & CONTROL_ENABLE == 0 && value & CONTROL_ENABLE != 0;
+                self.control = value & CONTROL_MASK;
+                if starting {
+                    self.restart();
+                }
+            }
+            RELOAD => {
+                self.reload = value;
+                self.restart();
+            }
+            VECTOR => self.vector = value & 0x07FF,
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u16 {
+        match offset {
+            STATUS => self.status,
+            CONTROL => self.control,
+            RELOAD => self.reload,
+            VECTOR => self.vector,
+            COUNT => self.count,
+            _ => 0,
+        }
+    }
+
+    fn tick(&mut self, _context: &mut DeviceContext) {
+        if self.control & CONTROL_ENABLE == 0 {
+            return;
+        }
+        if self.control & CONTROL_MILLISECONDS == 0 {
+            self.elapse(1);
+            return;
+        }
+
+        let now = Instant::now();
+        let last = *self.last_tick.get_or_insert(now);
+        let elapsed = now.duration_since(last).as_millis() as u64;
+        if elapsed > 0 {
+            // keep the fraction of a millisecond for the next tick
+            self.last_tick = Some(last + std::time::Duration::from_millis(elapsed));
+            self.elapse(elapsed);
+        }
+    }
+
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        (self.status & TIMER_EXPIRED != 0 && self.control & CONTROL_INTERRUPT_ENABLE != 0)
+            .then_some(Interrupt {
+                vector: self.vector as u8,
+                priority: (self.vector >> 8) as u8,
+            })
+    }
+
+    fn save(&self) -> Vec<u8> {
+        [
+            self.status,
+            self.control,
+            self.reload,
+            self.vector,
+            self.count,
+        ]
+        .iter()
+        .flat_map(|word| word.to_be_bytes())
+        .collect()
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        if state.len() != 10 {
+            return;
+        }
+        let word = |index: usize| u16::from_be_bytes([state[2 * index], state[2 * index + 1]]);
+        self.status = word(0);
+        self.control = word(1);
+        self.reload = word(2);
+        self.vector = word(3);
+        self.count = word(4);
+        self.last_tick = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn test_counts_instructions() {
+        let mut timer = Timer::default();
+        let mut console = BufferConsole::default();
+        let mut context = DeviceContext {
+            console: &mut console,
+            halt: false,
+        };
+        timer.write(RELOAD, 3, &mut context);
+        timer.write(
+            CONTROL,
+            CONTROL_ENABLE | CONTROL_INTERRUPT_ENABLE,
+            &mut context,
+        );
+
+        for _ in 0..2 {
+            timer.tick(&mut context);
+        }
+        assert_eq!(timer.peek(COUNT), 1);
+        assert_eq!(timer.pending_interrupt(), None);
+
+        timer.tick(&mut context);
+        assert_eq!(timer.peek(STATUS), TIMER_EXPIRED);
+        assert_eq!(timer.peek(COUNT), 3);
+        assert_eq!(
+            timer.pending_interrupt(),
+            Some(Interrupt {
+                vector: TIMER_VECTOR,
+                priority: TIMER_PRIORITY,
+            })
+        );
+
+        timer.write(STATUS, 0, &mut context);
+        assert_eq!(timer.pending_interrupt(), None);
+
+        timer.write(CONTROL, CONTROL_ENABLE | CONTROL_ONE_SHOT, &mut context);
+        for _ in 0..5 {
+            timer.tick(&mut context);
+        }
+        assert_eq!(timer.peek(CONTROL) & CONTROL_ENABLE, 0);
+    }
+}
@@ -0,0 +1,446 @@
+//! Differential testing of execution engines
+//!
+//! [`Lockstep`] runs two machines side by side, the same program with the
+//! same keys on different engines, and compares registers, PSR, memory,
+//! device state and console output every so many instructions. When they
+//! disagree it goes back to the last point of agreement and steps one
+//! instruction at a time, so a [`Divergence`] names the first instruction
+//! after which the two differ, with a listing of the code around it.
+//!
+//! Machines that take different paths by design, like the built in trap
+//! routines against an operating system image, cannot be compared this way.
+
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use crate::{
+    blocks::Engine,
+    console::BufferConsole,
+    cpu::{StepResult, VmCPU},
+    debug_info::SymbolTable,
+    devices::Random,
+    disassembler,
+    register::Registers,
+    snapshot::Snapshot,
+};
+
+// what a report shows at most, of memory words and console output
+const MAX_MEMORY_DIFFERENCES: usize = 8;
+const OUTPUT_EXCERPT: usize = 20;
+
+/// Names of the registers in [`VmCPU::registers`]
+const REGISTER_NAMES: [&str; 10] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "PC", "CC"];
+
+#[derive(Debug, Clone)]
+pub struct Lockstep {
+    /// instructions between comparisons
+    pub every: u64,
+    pub budget: u64,
+    /// keys waiting for the program before it starts
+    pub keys: Vec<u8>,
+}
+
+impl Default for Lockstep {
+    fn default() -> Self {
+        Self {
+            every: 1,
+            budget: 1_000_000,
+            keys: Vec::new(),
+        }
+    }
+}
+
+/// What sets the two machines apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Register(&'static str, u16, u16),
+    Psr(u16, u16),
+    /// first differing words only
+    Memory(u16, u16, u16),
+    /// a device by name
+    Device(String),
+    /// what both printed from the first byte that differs
+    Output(String, String),
+    /// keys still unread
+    Input(usize, usize),
+    /// how the last stretch ended and how many instructions it took
+    Ended((StepResult, u64), (StepResult, u64)),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Register(name, left, right) => {
+                write!(f, "{} x{:04X} and x{:04X}", name, left, right)
+            }
+            Difference::Psr(left, right) => write!(f, "PSR x{:04X} and x{:04X}", left, right),
+            Difference::Memory(address, left, right) => {
+                write!(f, "x{:04X} holds x{:04X} and x{:04X}", address, left, right)
+            }
+            Difference::Device(name) => write!(f, "state of {}", name),
+            Difference::Output(left, right) => write!(f, "output {:?} and {:?}", left, right),
+            Difference::Input(left, right) => write!(f, "{} and {} keys left", left, right),
+            Difference::Ended((left, left_count), (right, right_count)) => write!(
+                f,
+                "{:?} after {} and {:?} after {} instructions",
+                left, left_count, right, right_count
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// how each side executes
+    pub sides: [String; 2],
+    /// instructions both executed while they still agreed
+    pub agreed: u64,
+    /// the PC at that point; the instruction there makes the difference
+    pub pc: u16,
+    pub differences: Vec<Difference>,
+    /// disassembly around `pc` on the left
+    pub listing: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} and {} diverge after {} instructions, at x{:04X}:",
+            self.sides[0], self.sides[1], self.agreed, self.pc
+        )?;
+        for difference in &self.differences {
+            writeln!(f, "  {}", difference)?;
+        }
+        write!(f, "{}", self.listing)
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// One machine under comparison with the console it was given
+struct Side<'a> {
+    vm: &'a mut VmCPU,
+    console: Rc<RefCell<BufferConsole>>,
+}
+
+impl Side<'_> {
+    fn save(&self) -> (Snapshot, BufferConsole) {
+        (self.vm.snapshot(), self.console.borrow().clone())
+    }
+
+    fn restore(&mut self, (snapshot, console): &(Snapshot, BufferConsole)) {
+        self.vm.restore(snapshot);
+        *self.console.borrow_mut() = console.clone();
+    }
+
+    /// Execute `count` instructions unless the run ends sooner
+    fn advance_by(&mut self, count: u64) -> (StepResult, u64) {
+        let mut executed = 0;
+        loop {
+            let (result, n) = self.vm.advance(count - executed);
+            executed += n;
+            if result != StepResult::Continue || executed >= count {
+                return (result, executed);
+            }
+        }
+    }
+}
+
+impl Lockstep {
+    /// Run `left` and `right` for at most `budget` instructions, which
+    /// both get their own console holding `keys`. Returns how many
+    /// instructions they executed in agreement.
+    pub fn run(&self, left: &mut VmCPU, right: &mut VmCPU) -> Result<u64, Divergence> {
+        let mut sides = [left, right].map(|vm| {
+            let console = Rc::new(RefCell::new(BufferConsole::new(&self.keys)));
+            vm.console = Box::new(console.clone());
+            Side { vm, console }
+        });
+        let differences = differences(&sides);
+        if !differences.is_empty() {
+            let pc = sides[0].vm.read_register(Registers::ProgramCounter);
+            return Err(divergence(&sides, 0, pc, differences));
+        }
+        self.compare(&mut sides, 0, self.budget, self.every.max(1))
+    }
+
+    /// The program `machine` builds on the reference interpreter, which
+    /// decodes every instruction afresh, against the decode cache and
+    /// against the block engine
+    pub fn engines(&self, machine: impl Fn() -> VmCPU) -> Result<u64, Divergence> {
+        let mut instructions = 0;
+        for engine in [Engine::Interpreter, Engine::Blocks] {
+            let mut reference = machine();
+            reference.set_engine(Engine::Interpreter);
+            reference.memory.set_decode_cache(false);
+            let mut other = machine();
+            other.set_engine(engine);
+            instructions = self.run(&mut reference, &mut other)?;
+        }
+        Ok(instructions)
+    }
+
+    fn compare(
+        &self,
+        sides: &mut [Side; 2],
+        start: u64,
+        budget: u64,
+        every: u64,
+    ) -> Result<u64, Divergence> {
+        let mut executed = start;
+        while executed < start + budget {
+            let saved = (every > 1).then(|| [sides[0].save(), sides[1].save()]);
+            let pc = sides[0].vm.read_register(Registers::ProgramCounter);
+            let count = every.min(start + budget - executed);
+            let ends = [sides[0].advance_by(count), sides[1].advance_by(count)];
+
+            let mut differences = differences(sides);
+            if ends[0] != ends[1] {
+                differences.push(Difference::Ended(ends[0], ends[1]));
+            }
+            if !differences.is_empty() {
+                return Err(match saved {
+                    // find the instruction one at a time
+                    Some(saved) => {
+                        sides[0].restore(&saved[0]);
+                        sides[1].restore(&saved[1]);
+                        let replay = self.compare(sides, executed, count, 1);
+                        replay
+                            .err()
+                            .unwrap_or_else(|| divergence(sides, executed, pc, differences))
+                    }
+                    None => divergence(sides, executed, pc, differences),
+                });
+            }
+            executed += ends[0].1;
+            if ends[0].0 != StepResult::Continue {
+                break;
+            }
+        }
+        Ok(executed)
+    }
+}
+
+fn differences(sides: &[Side; 2]) -> Vec<Difference> {
+    let [left, right] = [&sides[0], &sides[1]];
+    let mut differences = Vec::new();
+
+    let pairs = left.vm.registers.iter().zip(&right.vm.registers);
+    for (name, (l, r)) in REGISTER_NAMES.iter().zip(pairs) {
+        if l != r {
+            differences.push(Difference::Register(name, *l, *r));
+        }
+    }
+    if left.vm.psr() != right.vm.psr() {
+        differences.push(Difference::Psr(left.vm.psr(), right.vm.psr()));
+    }
+    let stacks = [
+        ("SSP", left.vm.saved_ssp, right.vm.saved_ssp),
+        ("USP", left.vm.saved_usp, right.vm.saved_usp),
+    ];
+    for (name, l, r) in stacks {
+        if l != r {
+            differences.push(Difference::Register(name, l, r));
+        }
+    }
+
+    let words = [left.vm.memory.words(), right.vm.memory.words()];
+    if words[0] != words[1] {
+        differences.extend(
+            (0..=u16::MAX)
+                .zip(words[0].iter().zip(words[1]))
+                .filter(|(_, (l, r))| l != r)
+                .take(MAX_MEMORY_DIFFERENCES)
+                .map(|(address, (l, r))| Difference::Memory(address, *l, *r)),
+        );
+    }
+
+    let devices = left.vm.devices.save();
+    let other = right.vm.devices.save();
+    for (name, state) in &devices {
+        if other.get(name) != Some(state) {
+            differences.push(Difference::Device(name.clone()));
+        }
+    }
+
+    let (left, right) = (left.console.borrow(), right.console.borrow());
+    if left.output != right.output {
+        let same = left
+            .output
+            .iter()
+            .zip(&right.output)
+            .take_while(|(l, r)| l == r)
+            .count();
+        let excerpt = |output: &[u8]| {
+            let end = output.len().min(same + OUTPUT_EXCERPT);
+            String::from_utf8_lossy(&output[same..end]).into_owned()
+        };
+        differences.push(Difference::Output(
+            excerpt(&left.output),
+            excerpt(&right.output),
+        ));
+    }
+    if left.input.len() != right.input.len() {
+        differences.push(Difference::Input(left.input.len(), right.input.len()));
+    }
+    differences
+}
+
+fn divergence(sides: &[Side; 2], agreed: u64, pc: u16, differences: Vec<Difference>) -> Divergence {
+    let vm = &sides[0].vm;
+    let origin = pc.wrapping_sub(4);
+    let words: Vec<u16> = (0..9)
+        .map(|offset| vm.peek(origin.wrapping_add(offset)))
+        .collect();
+    let listing = disassembler::listing(origin, &words, &SymbolTable::default())
+        .lines()
+        .zip(0..)
+        .map(|(line, offset)| match offset == 4 {
+            true => format!("-> {}\n", line),
+            false => format!("   {}\n", line),
+        })
+        .collect();
+    Divergence {
+        sides: [describe(sides[0].vm), describe(sides[1].vm)],
+        agreed,
+        pc,
+        differences,
+        listing,
+    }
+}
+
+fn describe(vm: &VmCPU) -> String {
+    let engine = match vm.engine() {
+        Engine::Interpreter => "interpreter",
+        Engine::Blocks => "block engine",
+    };
+    match vm.memory.decode_cache() {
+        true => engine.to_string(),
+        false => format!("{} without decode cache", engine),
+    }
+}
+
+/// `length` random instructions, for streams run from x3000 with random
+/// registers. Instructions that could leave the neighbourhood, like JMP,
+/// JSRR, TRAP and RTI, are left out, as is the reserved opcode.
+pub fn random_program(seed: u64, length: usize) -> Vec<u16> {
+    let mut random = Random::new(seed);
+    let mut words = Vec::with_capacity(length);
+    while words.len() < length {
+        let word = random.next_word();
+        let jsrr = word >> 12 == 0b0100 && word & 1 << 11 == 0;
+        if !matches!(word >> 12, 0b1000 | 0b1100 | 0b1101 | 0b1111) && !jsrr {
+            words.push(word);
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{machine::MachineBuilder, register::General};
+
+    #[test]
+    fn test_resources() {
+        let lockstep = Lockstep {
+            every: 1000,
+            budget: 200_000,
+            keys: b"wasdwasd\n  jjkkhhll".to_vec(),
+        };
+        for path in ["resources/2048.obj", "resources/rogue.obj"] {
+            let machine = || {
+                MachineBuilder::new()
+                    .load(path)
+                    .unwrap()
+                    .quiet()
+                    .build()
+                    .into_cpu()
+            };
+            let instructions = lockstep
+                .engines(machine)
+                .unwrap_or_else(|e| panic!("{}", e));
+            assert_eq!(instructions, lockstep.budget, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_random_streams() {
+        let lockstep = Lockstep {
+            every: 16,
+            budget: 2000,
+            keys: b"abc".to_vec(),
+        };
+        for seed in 0..200 {
+            let machine = || {
+                let mut random = Random::new(seed);
+                let mut builder = MachineBuilder::new()
+                    .image(0x3000, &random_program(seed, 64))
+                    .image(0x3040, &[0xF025])
+                    .quiet();
+                let general = [
+                    General::R0,
+                    General::R1,
+                    General::R2,
+                    General::R3,
+                    General::R4,
+                    General::R5,
+                    General::R6,
+                    General::R7,
+                ];
+                for register in general {
+                    builder = builder.register(register, random.next_word());
+                }
+                builder.build().into_cpu()
+            };
+            if let Err(divergence) = lockstep.engines(machine) {
+                panic!("seed {}: {}", seed, divergence);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        let program = [0x1261, 0x1261, 0x1261, 0x1261, 0xF025]; // ADD R1, R1, #1
+        let machine = || {
+            MachineBuilder::new()
+                .image(0x3000, &program)
+                .quiet()
+                .build()
+                .into_cpu()
+        };
+        let mut left = machine();
+        let mut right = machine();
+        right.memory.write_memory(0x3002, 0x1262);
+
+        let lockstep = Lockstep {
+            every: 8,
+            ..Default::default()
+        };
+        // the word itself differs from the start
+        let divergence = lockstep.run(&mut left, &mut right).unwrap_err();
+        assert_eq!(divergence.agreed, 0);
+        assert_eq!(
+            divergence.differences,
+            [Difference::Memory(0x3002, 0x1261, 0x1262)]
+        );
+
+        let mut left = machine();
+        let mut right = machine();
+        right.set_engine(Engine::Blocks);
+        assert_eq!(lockstep.run(&mut left, &mut right), Ok(5));
+
+        // the HALT goes to an operating system on one side only, found
+        // within the first stretch of eight
+        let mut left = machine();
+        let mut right = machine();
+        left.os_traps = true;
+        let divergence = lockstep.run(&mut left, &mut right).unwrap_err();
+        assert_eq!((divergence.agreed, divergence.pc), (4, 0x3004));
+        assert!(divergence.differences.contains(&Difference::Ended(
+            (StepResult::Continue, 1),
+            (StepResult::Halted, 1)
+        )));
+        assert!(divergence.listing.contains("-> x3004"));
+    }
+}
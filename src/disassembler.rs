@@ -0,0 +1,198 @@
+//! LC-3 assembly text for memory words
+//!
+//! PC-relative operands are shown as the address they refer to, by label
+//! when the symbol table has one, so the output reads like the source:
+//!
+//! ```text
+//! x3002  BRp LOOP
+//! x3003  LD R2, x3010
+//! ```
+
+use std::fmt::Write;
+
+use crate::{
+    debug_info::SymbolTable,
+    instructions::{Instructions, JumpRegisterType, JumpType, LoadType},
+};
+
+fn target(address: u16, symbols: &SymbolTable) -> String {
+    match symbols.name_at(address) {
+        Some(name) => name.to_string(),
+        None => format!("x{:04X}", address),
+    }
+}
+
+fn operand(operand: &LoadType) -> String {
+    match operand {
+        LoadType::Register { src_register } => format!("R{}", src_register),
+        LoadType::Immediate { value } => format!("#{}", *value as i16),
+    }
+}
+
+/// The instruction `word` as it would run at `address`
+pub fn disassemble(word: u16, address: u16, symbols: &SymbolTable) -> String {
+    let next = address.wrapping_add(1);
+    let relative = |offset: u16| target(next.wrapping_add(offset), symbols);
+    match Instructions::from(word) {
+        Instructions::Branch {
+            pc_offset_9,
+            n,
+            z,
+            p,
+        } => {
+            if !(n || z || p) {
+                return "NOP".to_string();
+            }
+            let mut mnemonic = String::from("BR");
+            for (set, flag) in [(n, 'n'), (z, 'z'), (p, 'p')] {
+                if set && !(n && z && p) {
+                    mnemonic.push(flag);
+                }
+            }
+            format!("{} {}", mnemonic, relative(pc_offset_9))
+        }
+        Instructions::Add {
+            dest_register,
+            src_register,
+            add_type,
+        } => format!(
+            "ADD R{}, R{}, {}",
+            dest_register,
+            src_register,
+            operand(&add_type)
+        ),
+        Instructions::And {
+            dest_register,
+            src_register,
+            add_type,
+        } => format!(
+            "AND R{}, R{}, {}",
+            dest_register,
+            src_register,
+            operand(&add_type)
+        ),
+        Instructions::LoadDirect {
+            pc_offset_9,
+            dest_register,
+        } => format!("LD R{}, {}", dest_register, relative(pc_offset_9)),
+        Instructions::StoreDirect {
+            pc_offset_9,
+            src_register,
+        } => format!("ST R{}, {}", src_register, relative(pc_offset_9)),
+        Instructions::JumpRegister(JumpRegisterType::FromOffset { pc_offset_11 }) => {
+            format!("JSR {}", relative(pc_offset_11))
+        }
+        Instructions::JumpRegister(JumpRegisterType::FromRegister { base_register }) => {
+            format!("JSRR R{}", base_register)
+        }
+        Instructions::LoadRegister {
+            offset6,
+            base_register,
+            dest_register,
+        } => format!(
+            "LDR R{}, R{}, #{}",
+            dest_register, base_register, offset6 as i16
+        ),
+        Instructions::StoreRegister {
+            offset6,
+            base_register,
+            src_register,
+        } => format!(
+            "STR R{}, R{}, #{}",
+            src_register, base_register, offset6 as i16
+        ),
+        Instructions::Not {
+            dest_register,
+            src_register,
+        } => format!("NOT R{}, R{}", dest_register, src_register),
+        Instructions::LoadIndirect {
+            pc_offset_9,
+            dest_register,
+        } => format!("LDI R{}, {}", dest_register, relative(pc_offset_9)),
+        Instructions::StoreIndirect {
+            pc_offset_9,
+            src_register,
+        } => format!("STI R{}, {}", src_register, relative(pc_offset_9)),
+        Instructions::Jump(JumpType::Return) => "RET".to_string(),
+        Instructions::Jump(JumpType::BaseRegister(base_register)) => {
+            format!("JMP R{}", base_register)
+        }
+        Instructions::LoadEffectiveAddress {
+            pc_offset_9,
+            dest_register,
+        } => format!("LEA R{}, {}", dest_register, relative(pc_offset_9)),
+        Instructions::Trap { trap_vector } => match trap_vector {
+            0x20 => "GETC".to_string(),
+            0x21 => "OUT".to_string(),
+            0x22 => "PUTS".to_string(),
+            0x23 => "IN".to_string(),
+            0x24 => "PUTSP".to_string(),
+            0x25 => "HALT".to_string(),
+            vector => format!("TRAP x{:02X}", vector),
+        },
+        Instructions::ReturnFromInterrupt => "RTI".to_string(),
+        Instructions::UnImplemented(_) => format!(".FILL x{:04X}", word),
+    }
+}
+
+/// One line per word: address, label, word and instruction
+pub fn listing(origin: u16, words: &[u16], symbols: &SymbolTable) -> String {
+    let mut text = String::new();
+    for (offset, word) in words.iter().enumerate() {
+        let address = origin.wrapping_add(offset as u16);
+        writeln!(
+            text,
+            "x{:04X}  {:<12}  {:04X}  {}",
+            address,
+            symbols.name_at(address).unwrap_or_default(),
+            word,
+            disassemble(*word, address, symbols)
+        )
+        .unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler;
+
+    #[test]
+    fn test_reassembles() {
+        let source = "
+        .ORIG x3000
+LOOP    ADD R1, R1, #-1
+        AND R2, R2, R3
+        BRnp LOOP
+        BRnzp DONE
+        LDR R4, R6, #-2
+        JSR LOOP
+        JSRR R5
+        LEA R0, DONE
+        NOT R0, R0
+        RTI
+        RET
+DONE    HALT
+        .END
+";
+        let assembly = assembler::assemble(source).unwrap();
+        let listing = listing(assembly.origin, &assembly.words, &assembly.symbols);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "x3000  LOOP          127F  ADD R1, R1, #-1");
+        assert!(lines[2].ends_with("BRnp LOOP"));
+        assert!(lines[3].ends_with("BR DONE"));
+        assert!(lines[4].ends_with("LDR R4, R6, #-2"));
+        assert!(lines[11].ends_with("HALT"));
+
+        // the listing assembles back to the same words
+        let instructions: Vec<&str> = lines.iter().map(|line| &line[27..]).collect();
+        let mut source = String::from(".ORIG x3000\n");
+        for (index, instruction) in instructions.iter().enumerate() {
+            let label = assembly.symbols.name_at(0x3000 + index as u16);
+            source.push_str(&format!("{} {}\n", label.unwrap_or(""), instruction));
+        }
+        source.push_str(".END\n");
+        assert_eq!(assembler::assemble(&source).unwrap().words, assembly.words);
+    }
+}
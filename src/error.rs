@@ -0,0 +1,93 @@
+//! Errors of the library
+//!
+//! A [`Fault`] is the program's doing: the machine met an instruction it
+//! cannot execute and stopped in front of it. [`Error`] covers everything
+//! that can go wrong while setting a machine up or running it.
+
+use std::{fmt, io};
+
+use crate::assembler::AssembleError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// opcode 1101, which the ISA reserves
+    ReservedOpcode,
+    /// a TRAP vector with no built in routine and no operating system
+    /// loaded to handle it
+    UnsupportedTrap(u8),
+    /// execution reached the device page under
+    /// [`crate::cpu::ExecutionPolicy::Fault`]
+    DevicePage,
+}
+
+/// Why execution stopped, with the PC still at the offending instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub pc: u16,
+    /// the instruction word
+    pub word: u16,
+    pub kind: FaultKind,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FaultKind::ReservedOpcode => {
+                write!(f, "reserved opcode x{:04X} at x{:04X}", self.word, self.pc)
+            }
+            FaultKind::UnsupportedTrap(vector) => {
+                write!(f, "unsupported TRAP x{:02X} at x{:04X}", vector, self.pc)
+            }
+            FaultKind::DevicePage => {
+                write!(f, "execution ran into the device page at x{:04X}", self.pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Assemble(AssembleError),
+    Fault(Fault),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Assemble(e) => write!(f, "{}", e),
+            Error::Fault(fault) => write!(f, "{}", fault),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Assemble(e) => Some(e),
+            Error::Fault(fault) => Some(fault),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<AssembleError> for Error {
+    fn from(error: AssembleError) -> Self {
+        Error::Assemble(error)
+    }
+}
+
+impl From<Fault> for Error {
+    fn from(fault: Fault) -> Self {
+        Error::Fault(fault)
+    }
+}
@@ -0,0 +1,206 @@
+//! Host file access for LC-3 programs
+//!
+//! An opt-in extension: while a [`FileSystem`] is attached to
+//! [`crate::cpu::VmCPU`] the trap vectors x30 to x34 open, read, write,
+//! close and seek files inside one sandbox directory. Without it those
+//! vectors are unknown like any other.
+//!
+//! | trap | inputs                                           | R0 on success   |
+//! |------|--------------------------------------------------|-----------------|
+//! | x30  | R0 path (one char per word, zero ended), R1 mode | handle          |
+//! | x31  | R0 handle, R1 buffer, R2 maximum count           | words read      |
+//! | x32  | R0 handle, R1 buffer, R2 count                   | words written   |
+//! | x33  | R0 handle                                        | 0               |
+//! | x34  | R0 handle, R1 signed offset, R2 whence           | position [15:0] |
+//!
+//! Buffers hold one byte per word, like strings for PUTS. Modes are 0 read,
+//! 1 write (created or truncated), 2 append and 3 read and write; whence is
+//! 0 from the start, 1 from the current position and 2 from the end. Seeking
+//! leaves bits [31:16] of the position in R1.
+//!
+//! Errors come back as a negative [`FileError`] code in R0, so the N flag
+//! is set exactly when a call failed. File operations cannot be undone by
+//! reverse execution and open files are not part of snapshots.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    NotFound = 1,
+    /// outside the sandbox or not permitted by the host
+    Denied = 2,
+    BadHandle = 3,
+    InvalidArgument = 4,
+    Io = 5,
+}
+
+impl FileError {
+    /// The value left in R0
+    pub fn code(self) -> u16 {
+        (-(self as i16)) as u16
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => FileError::NotFound,
+            io::ErrorKind::PermissionDenied => FileError::Denied,
+            io::ErrorKind::InvalidInput => FileError::InvalidArgument,
+            _ => FileError::Io,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FileSystem {
+    root: PathBuf,
+    // indexed by handle
+    handles: Vec<Option<File>>,
+}
+
+impl FileSystem {
+    /// Give programs access to the files below `root`
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        Ok(Self {
+            root: fs::canonicalize(root)?,
+            handles: Vec::new(),
+        })
+    }
+
+    /// Another sandbox on the same root, with no files open
+    pub fn fresh(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            handles: Vec::new(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Host path for `path`, refused if it could leave the sandbox
+    fn resolve(&self, path: &str) -> Result<PathBuf, FileError> {
+        let relative = Path::new(path);
+        if path.is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(FileError::Denied);
+        }
+
+        // symbolic links must not point out of the sandbox either
+        let resolved = self.root.join(relative);
+        let existing = match fs::canonicalize(&resolved) {
+            Ok(existing) => existing,
+            Err(_) => {
+                let parent = resolved.parent().ok_or(FileError::Denied)?;
+                fs::canonicalize(parent)?.join(resolved.file_name().ok_or(FileError::Denied)?)
+            }
+        };
+        if !existing.starts_with(&self.root) {
+            return Err(FileError::Denied);
+        }
+        Ok(existing)
+    }
+
+    pub fn open(&mut self, path: &str, mode: u16) -> Result<u16, FileError> {
+        let path = self.resolve(path)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            3 => options.read(true).write(true),
+            _ => return Err(FileError::InvalidArgument),
+        };
+        let file = options.open(path)?;
+
+        let handle = match self.handles.iter().position(Option::is_none) {
+            Some(free) => free,
+            None if self.handles.len() < i16::MAX as usize => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+            None => return Err(FileError::InvalidArgument),
+        };
+        self.handles[handle] = Some(file);
+        Ok(handle as u16)
+    }
+
+    fn file(&mut self, handle: u16) -> Result<&mut File, FileError> {
+        self.handles
+            .get_mut(handle as usize)
+            .and_then(Option::as_mut)
+            .ok_or(FileError::BadHandle)
+    }
+
+    /// Up to `count` bytes, fewer at the end of the file
+    pub fn read(&mut self, handle: u16, count: u16) -> Result<Vec<u8>, FileError> {
+        let mut bytes = Vec::new();
+        self.file(handle)?
+            .take(count as u64)
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn write(&mut self, handle: u16, bytes: &[u8]) -> Result<u16, FileError> {
+        self.file(handle)?.write_all(bytes)?;
+        Ok(bytes.len() as u16)
+    }
+
+    pub fn close(&mut self, handle: u16) -> Result<(), FileError> {
+        self.file(handle)?;
+        self.handles[handle as usize] = None;
+        Ok(())
+    }
+
+    pub fn seek(&mut self, handle: u16, offset: i16, whence: u16) -> Result<u64, FileError> {
+        let position = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(FileError::InvalidArgument),
+        };
+        Ok(self.file(handle)?.seek(position)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sandbox(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("files-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_stays_in_sandbox() {
+        let root = sandbox("escape");
+        let mut files = FileSystem::new(&root).unwrap();
+
+        for path in ["../secret", "/etc/passwd", "a/../../b", ""] {
+            assert_eq!(files.open(path, 0), Err(FileError::Denied), "{}", path);
+        }
+        assert_eq!(files.open("missing", 0), Err(FileError::NotFound));
+        assert_eq!(files.read(7, 1), Err(FileError::BadHandle));
+
+        let handle = files.open("level.txt", 1).unwrap();
+        assert_eq!(files.write(handle, b"#..@"), Ok(4));
+        files.close(handle).unwrap();
+        assert_eq!(files.close(handle), Err(FileError::BadHandle));
+
+        let handle = files.open("./level.txt", 0).unwrap();
+        assert_eq!(files.seek(handle, -2, 2), Ok(2));
+        assert_eq!(files.read(handle, 10).unwrap(), b".@");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! Undo log for reverse execution
+//!
+//! While a [`History`] is attached to a [`crate::cpu::VmCPU`] every
+//! instruction leaves an [`UndoRecord`] with the old value of each register
+//! and memory word it wrote, the input bytes it consumed, the old state
+//! of any device it changed and any change to the shadow call stack.
+//! Stepping back restores the old values and queues the input bytes for
+//! replay, so running forward again reads exactly the same keys.
+//!
+//! Output already written by OUT/PUTS cannot be taken back.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::callstack::CallStackChange;
+
+pub const DEFAULT_HISTORY_DEPTH: usize = 100_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UndoRecord {
+    /// register index and the value before the write
+    pub registers: Vec<(usize, u16)>,
+    /// address and the value before the write
+    pub memory: Vec<(u16, u16)>,
+    /// input bytes consumed, in order
+    pub input: Vec<u8>,
+    /// call or return seen by the shadow call stack
+    pub call_stack: Option<CallStackChange>,
+    /// state of each device the instruction changed, by device name
+    pub devices: BTreeMap<String, Vec<u8>>,
+    /// privilege and priority bits of the PSR, saved SSP and saved USP if
+    /// an interrupt or RTI changed them
+    pub system: Option<[u16; 3]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    depth: usize,
+    records: VecDeque<UndoRecord>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::with_depth(DEFAULT_HISTORY_DEPTH)
+    }
+}
+
+impl History {
+    /// Keep at most `depth` instructions, dropping the oldest first
+    pub fn with_depth(depth: usize) -> Self {
+        Self {
+            depth,
+            records: VecDeque::new(),
+        }
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.records.len() > depth {
+            self.records.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, record: UndoRecord) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.records.len() == self.depth {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
@@ -12,7 +12,7 @@ fn sign_extend(mut x: Number, bit_count: usize) -> Number {
     if ((x >> (bit_count - 1)) & 1) == 1 {
         x |= 0xFFFF << bit_count;
     }
-    return x;
+    x
 }
 
 pub(crate) fn get_number_from_bits(bit_slice: &[Bit]) -> Number {
@@ -42,20 +42,20 @@ fn print_bit(val: &bool, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LoadType {
     Register { src_register: Register },
     Immediate { value: u16 },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 
 pub enum JumpType {
     BaseRegister(Register),
     Return,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JumpRegisterType {
     FromOffset { pc_offset_11: u16 },
     FromRegister { base_register: Register },
@@ -63,7 +63,7 @@ pub enum JumpRegisterType {
 
 /// OpCode is 16 bits
 /// long with last 4 bits storing op-code
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instructions {
     UnImplemented(u16),
     Branch {
@@ -156,9 +156,110 @@ pub enum Instructions {
     Trap {
         trap_vector: u16,
     },
+    // RTI
+    ReturnFromInterrupt,
+}
+
+impl From<u16> for Instructions {
+    fn from(value: u16) -> Self {
+        let mut instruction_slice = [false; 16];
+        for (i, bit) in instruction_slice.iter_mut().enumerate() {
+            *bit = (value >> i) & 1 == 1;
+        }
+        Instructions::parse_instruction(&instruction_slice)
+    }
+}
+
+impl From<&Instructions> for u16 {
+    fn from(instruction: &Instructions) -> Self {
+        instruction.encode()
+    }
 }
 
 impl Instructions {
+    /// The instruction word. Bits the ISA leaves unused come out as the
+    /// assembler writes them, so `Instructions::from(word).encode()` can
+    /// differ from `word` in those bits only.
+    pub fn encode(&self) -> u16 {
+        let op = |op_code: u16| op_code << 12;
+        let offset = |value: u16, bits: u16| value & ((1 << bits) - 1);
+        let operand = |add_type: &LoadType| match add_type {
+            LoadType::Register { src_register } => *src_register,
+            LoadType::Immediate { value } => 1 << 5 | offset(*value, 5),
+        };
+        match self {
+            Instructions::UnImplemented(op_code) => op(*op_code),
+            Instructions::Branch {
+                pc_offset_9,
+                p,
+                z,
+                n,
+            } => {
+                op(0)
+                    | (*n as u16) << 11
+                    | (*z as u16) << 10
+                    | (*p as u16) << 9
+                    | offset(*pc_offset_9, 9)
+            }
+            Instructions::Add {
+                dest_register,
+                src_register,
+                add_type,
+            } => op(1) | dest_register << 9 | src_register << 6 | operand(add_type),
+            Instructions::LoadDirect {
+                pc_offset_9,
+                dest_register,
+            } => op(2) | dest_register << 9 | offset(*pc_offset_9, 9),
+            Instructions::StoreDirect {
+                pc_offset_9,
+                src_register,
+            } => op(3) | src_register << 9 | offset(*pc_offset_9, 9),
+            Instructions::JumpRegister(JumpRegisterType::FromOffset { pc_offset_11 }) => {
+                op(4) | 1 << 11 | offset(*pc_offset_11, 11)
+            }
+            Instructions::JumpRegister(JumpRegisterType::FromRegister { base_register }) => {
+                op(4) | base_register << 6
+            }
+            Instructions::And {
+                dest_register,
+                src_register,
+                add_type,
+            } => op(5) | dest_register << 9 | src_register << 6 | operand(add_type),
+            Instructions::LoadRegister {
+                offset6,
+                base_register,
+                dest_register,
+            } => op(6) | dest_register << 9 | base_register << 6 | offset(*offset6, 6),
+            Instructions::StoreRegister {
+                offset6,
+                base_register,
+                src_register,
+            } => op(7) | src_register << 9 | base_register << 6 | offset(*offset6, 6),
+            Instructions::ReturnFromInterrupt => op(8),
+            Instructions::Not {
+                dest_register,
+                src_register,
+            } => op(9) | dest_register << 9 | src_register << 6 | 0x3F,
+            Instructions::LoadIndirect {
+                pc_offset_9,
+                dest_register,
+            } => op(10) | dest_register << 9 | offset(*pc_offset_9, 9),
+            Instructions::StoreIndirect {
+                pc_offset_9,
+                src_register,
+            } => op(11) | src_register << 9 | offset(*pc_offset_9, 9),
+            Instructions::Jump(JumpType::BaseRegister(base_register)) => {
+                op(12) | base_register << 6
+            }
+            Instructions::Jump(JumpType::Return) => op(12) | 7 << 6,
+            Instructions::LoadEffectiveAddress {
+                pc_offset_9,
+                dest_register,
+            } => op(14) | dest_register << 9 | offset(*pc_offset_9, 9),
+            Instructions::Trap { trap_vector } => op(15) | offset(*trap_vector, 8),
+        }
+    }
+
     // parse instruction
     pub fn parse_instruction(instruction_slice: &[Bit; 16]) -> Instructions {
         // get last 4 bits
@@ -225,7 +326,7 @@ impl Instructions {
                             src_register: get_number_from_bits(&instruction_slice[0..3]),
                         },
                         true => LoadType::Immediate {
-                            value: get_number_from_bits(&instruction_slice[0..5]),
+                            value: sign_extend(get_number_from_bits(&instruction_slice[0..5]), 5),
                         },
                     },
                 }
@@ -268,7 +369,8 @@ impl Instructions {
             15 => Instructions::Trap {
                 trap_vector: get_number_from_bits(&instruction_slice[0..8]),
             },
-            8 | 13 => Instructions::UnImplemented(op_code),
+            8 => Instructions::ReturnFromInterrupt,
+            13 => Instructions::UnImplemented(op_code),
             _ => panic!("Not implemented {:x}", op_code),
         }
     }
@@ -281,28 +383,58 @@ pub mod test {
 
     #[test]
     fn test_create() {
+        // bit 0 is the least significant bit
         let instruction_slice = &mut [false; 16];
-        instruction_slice[15] = true;
+        instruction_slice[15] = false;
         instruction_slice[14] = true;
         instruction_slice[13] = true;
-        instruction_slice[12] = false;
+        instruction_slice[12] = true;
         let value = get_number_from_bits(&instruction_slice[12..16]);
 
         assert_eq!(value, 7);
 
-        instruction_slice[15] = false;
+        instruction_slice[15] = true;
         instruction_slice[14] = true;
         instruction_slice[13] = true;
-        instruction_slice[12] = true;
+        instruction_slice[12] = false;
         let value = get_number_from_bits(&instruction_slice[12..16]);
 
         assert_eq!(value, 14);
     }
 
+    #[test]
+    fn test_encode_round_trip() {
+        for word in 0..=u16::MAX {
+            let instruction = Instructions::from(word);
+            let encoded = instruction.encode();
+            assert_eq!(Instructions::from(encoded), instruction, "x{:04X}", word);
+            if !matches!(
+                instruction,
+                Instructions::Not { .. }
+                    | Instructions::Jump(_)
+                    | Instructions::JumpRegister(JumpRegisterType::FromRegister { .. })
+                    | Instructions::ReturnFromInterrupt
+                    | Instructions::UnImplemented(_)
+                    | Instructions::Trap { .. }
+                    | Instructions::Add {
+                        add_type: LoadType::Register { .. },
+                        ..
+                    }
+                    | Instructions::And {
+                        add_type: LoadType::Register { .. },
+                        ..
+                    }
+            ) {
+                assert_eq!(encoded, word, "{:?}", instruction);
+            }
+        }
+    }
+
     #[test]
     fn test_parse_add() {
+        // op code 0001
         let instruction_slice = &mut [false; 16];
-        instruction_slice[15] = true;
+        instruction_slice[12] = true;
         instruction_slice[5] = false;
 
         let ins = Instructions::parse_instruction(instruction_slice);
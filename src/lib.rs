@@ -0,0 +1,98 @@
+//! LC-3 virtual machine
+//!
+//! A [`Machine`] holds the processor state, 64K words of memory, the
+//! memory mapped [`Device`]s and a [`Console`] for the keyboard and display.
+//! [`MachineBuilder`] puts one together from an `.obj` image or assembly
+//! source, and [`Machine::run`] executes it for a bounded number of
+//! instructions, so a host like an autograder or a web page stays in
+//! control. Programs that execute something the machine cannot end in a
+//! [`Fault`] rather than a panic.
+//!
+//! The modules below are the stable API. What else the `vm` binary is
+//! made of, the debugger, profiler and the rest, lives in `unstable`, is
+//! hidden from the documentation and may change in any release.
+
+pub mod assembler;
+pub mod console;
+pub mod debug_info;
+pub mod devices;
+pub mod disassembler;
+pub mod error;
+pub mod instructions;
+pub mod machine;
+pub mod memory;
+pub mod register;
+pub mod snapshot;
+
+mod blocks;
+mod callstack;
+mod cfg;
+#[cfg(test)]
+mod conformance;
+mod coverage;
+mod cpu;
+mod debugger;
+mod differential;
+mod files;
+mod history;
+mod profile;
+#[cfg(test)]
+mod semantics;
+mod terminal;
+mod testing;
+mod trap;
+mod video;
+
+/// Internals of the `vm` binary, with no stability promise
+#[doc(hidden)]
+pub mod unstable {
+    pub mod blocks {
+        pub use crate::blocks::*;
+    }
+    pub mod callstack {
+        pub use crate::callstack::*;
+    }
+    pub mod cfg {
+        pub use crate::cfg::*;
+    }
+    pub mod coverage {
+        pub use crate::coverage::*;
+    }
+    pub mod cpu {
+        pub use crate::cpu::*;
+    }
+    pub mod debugger {
+        pub use crate::debugger::*;
+    }
+    pub mod differential {
+        pub use crate::differential::*;
+    }
+    pub mod files {
+        pub use crate::files::*;
+    }
+    pub mod history {
+        pub use crate::history::*;
+    }
+    pub mod profile {
+        pub use crate::profile::*;
+    }
+    pub mod testing {
+        pub use crate::testing::*;
+    }
+    pub mod trap {
+        pub use crate::trap::*;
+    }
+    pub mod video {
+        pub use crate::video::*;
+    }
+}
+
+pub use blocks::Engine;
+pub use console::{BufferConsole, Console, StdioConsole};
+pub use cpu::{ExecutionPolicy, StepResult};
+pub use devices::{Bus, Device, DeviceContext, Interrupt};
+pub use error::{Error, Fault, FaultKind};
+pub use files::FileSystem;
+pub use instructions::Instructions;
+pub use machine::{Exit, Fill, Machine, MachineBuilder};
+pub use memory::Memory;
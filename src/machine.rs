@@ -0,0 +1,606 @@
+//! Putting a machine together
+//!
+//! ```
+//! use vm::{BufferConsole, Exit, MachineBuilder};
+//!
+//! let mut machine = MachineBuilder::new()
+//!     .assemble(".ORIG x3000\nLEA R0, HELLO\nPUTS\nHALT\nHELLO .STRINGZ \"hi\"\n.END")?
+//!     .console(BufferConsole::default())
+//!     .quiet()
+//!     .build();
+//! assert_eq!(machine.run(1000)?, Exit::Halted);
+//! # Ok::<(), vm::Error>(())
+//! ```
+//!
+//! Whatever is not configured starts as at reset: the PC at the origin of
+//! the program, registers and memory zeroed, condition codes at Z and the
+//! processor in user mode at priority 0 with the standard devices.
+
+use std::path::Path;
+
+use crate::{
+    assembler,
+    blocks::Engine,
+    console::Console,
+    cpu::{ExecutionPolicy, StepResult, VmCPU, DEFAULT_SSP, FL_NEG, FL_POS, FL_ZRO, PSR_USER},
+    devices::{Bus, Device, Random},
+    error::{Error, Fault},
+    files::FileSystem,
+    memory::{self, Memory},
+    register::{General, Registers, REGISTER_COUNT},
+    snapshot::Snapshot,
+};
+
+/// The whole machine: processor, memory, devices and console
+///
+/// To see what a program printed, give the builder a console you keep a
+/// handle to, such as an `Rc<RefCell<BufferConsole>>`.
+pub struct Machine {
+    cpu: VmCPU,
+}
+
+/// How [`Machine::run`] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Halted,
+    /// GETC with no key available from the console
+    WaitingForInput,
+    /// the instruction budget was spent
+    OutOfBudget,
+}
+
+/// What memory holds where no program was loaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fill {
+    #[default]
+    Zero,
+    /// pseudo-random words from a seed, to catch reads of uninitialized
+    /// memory
+    Random(u64),
+}
+
+#[derive(Default)]
+pub struct MachineBuilder {
+    // the program first, then further images on top of it
+    images: Vec<(u16, Vec<u16>)>,
+    fill: Fill,
+    registers: [Option<u16>; 8],
+    entry: Option<u16>,
+    psr: Option<u16>,
+    // kept apart from `psr` so neither call undoes the other
+    supervisor: bool,
+    supervisor_stack: Option<u16>,
+    bus: Option<Bus>,
+    devices: Vec<(u16, Box<dyn Device>)>,
+    console: Option<Box<dyn Console>>,
+    seed: Option<u64>,
+    files: Option<FileSystem>,
+    quiet: bool,
+    engine: Engine,
+    execution_policy: ExecutionPolicy,
+    operating_system: Option<(u16, Vec<u16>)>,
+    decode_cache: Option<bool>,
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load `words` from `origin`. The first image loaded is the program,
+    /// which sets the entry point; later ones, like an operating system,
+    /// only add to memory.
+    pub fn image(mut self, origin: u16, words: &[u16]) -> Self {
+        self.images.push((origin, words.to_vec()));
+        self
+    }
+
+    /// Load an operating system image on top of every other image. TRAP
+    /// then jumps through its trap vector table instead of running the
+    /// built-in routines.
+    pub fn operating_system(mut self, origin: u16, words: &[u16]) -> Self {
+        self.operating_system = Some((origin, words.to_vec()));
+        self
+    }
+
+    /// Load the bytes of an `.obj` file
+    pub fn obj(self, bytes: &[u8]) -> Result<Self, Error> {
+        let (origin, words) = memory::parse_obj(bytes)?;
+        Ok(self.image(origin, &words))
+    }
+
+    /// Assemble and load LC-3 source
+    pub fn assemble(self, source: &str) -> Result<Self, Error> {
+        let assembly = assembler::assemble(source)?;
+        Ok(self.image(assembly.origin, &assembly.words))
+    }
+
+    /// Load an `.obj` file, or an `.asm` file which is assembled
+    pub fn load<P: AsRef<Path>>(self, path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let is_asm = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("asm"));
+        if is_asm {
+            self.assemble(&std::fs::read_to_string(path)?)
+        } else {
+            self.obj(&std::fs::read(path)?)
+        }
+    }
+
+    pub fn fill(mut self, fill: Fill) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Initial value of a general purpose register
+    pub fn register(mut self, register: General, value: u16) -> Self {
+        self.registers[usize::from(Registers::GeneralRegister(register))] = Some(value);
+        self
+    }
+
+    /// Start here instead of at the origin of the program
+    pub fn entry(mut self, address: u16) -> Self {
+        self.entry = Some(address);
+        self
+    }
+
+    /// Initial processor status: privilege in bit 15, priority in bits
+    /// [10:8] and condition codes in bits [2:0]. The condition codes become
+    /// Z unless exactly one of them is set. [`MachineBuilder::supervisor`]
+    /// clears bit 15 whether it comes before or after.
+    pub fn psr(mut self, psr: u16) -> Self {
+        self.psr = Some(psr);
+        self
+    }
+
+    /// Start in supervisor mode rather than user mode
+    pub fn supervisor(mut self) -> Self {
+        self.supervisor = true;
+        self
+    }
+
+    /// Initial user stack pointer: R6 in user mode, the saved one when
+    /// starting in supervisor mode
+    pub fn stack_pointer(self, address: u16) -> Self {
+        self.register(General::R6, address)
+    }
+
+    /// Initial supervisor stack pointer, [`crate::cpu::DEFAULT_SSP`] unless
+    /// set: R6 when starting in supervisor mode, otherwise where the stack
+    /// starts when an interrupt arrives
+    pub fn supervisor_stack_pointer(mut self, address: u16) -> Self {
+        self.supervisor_stack = Some(address);
+        self
+    }
+
+    /// Replace the standard devices
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    /// Attach a device at `base` on the device page, next to the standard
+    /// ones or those given to [`MachineBuilder::bus`]. Panics like
+    /// [`Bus::attach`] when it does not fit.
+    pub fn device(mut self, base: u16, device: impl Device + 'static) -> Self {
+        self.devices.push((base, Box::new(device)));
+        self
+    }
+
+    /// Keyboard and display, stdin and stdout by default
+    pub fn console(mut self, console: impl Console + 'static) -> Self {
+        self.console = Some(Box::new(console));
+        self
+    }
+
+    /// Seed the random number device, also one on a bus given to
+    /// [`MachineBuilder::bus`]. Panics at [`MachineBuilder::build`] when
+    /// there is no device named `random`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Enable the file traps inside a sandbox
+    pub fn files(mut self, files: FileSystem) -> Self {
+        self.files = Some(files);
+        self
+    }
+
+    /// Leave out the "Exiting" line HALT prints
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    /// How [`Machine::run`] executes instructions, one at a time unless set
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// What running into the device page does, wrapping on unless set
+    pub fn execution_policy(mut self, policy: ExecutionPolicy) -> Self {
+        self.execution_policy = policy;
+        self
+    }
+
+    /// Whether decoded instructions are kept, on unless set
+    pub fn decode_cache(mut self, enabled: bool) -> Self {
+        self.decode_cache = Some(enabled);
+        self
+    }
+
+    pub fn build(self) -> Machine {
+        let origin = self.images.first().map_or(0x3000, |(origin, _)| *origin);
+        let mut memory = Memory::load_from_words(origin, &[]);
+        if let Fill::Random(seed) = self.fill {
+            let mut random = Random::new(seed);
+            let words: Vec<u16> = (0..1 << 16).map(|_| random.next_word()).collect();
+            memory.load_image(&words);
+        }
+        for (origin, words) in self.images.iter().chain(&self.operating_system) {
+            memory.load_words(*origin, words);
+        }
+        if let Some(enabled) = self.decode_cache {
+            memory.set_decode_cache(enabled);
+        }
+
+        let mut registers = [0; 10];
+        for (register, value) in registers.iter_mut().zip(self.registers) {
+            *register = value.unwrap_or(0);
+        }
+        let mut cpu = VmCPU::new(registers, memory);
+        if let Some(entry) = self.entry {
+            cpu.update_register(Registers::ProgramCounter, entry);
+        }
+        if self.psr.is_some() || self.supervisor {
+            let mut psr = self.psr.unwrap_or(0);
+            if self.supervisor {
+                psr &= !PSR_USER;
+            }
+            let condition = match psr & 0b111 {
+                condition @ (FL_NEG | FL_ZRO | FL_POS) => condition,
+                _ => FL_ZRO,
+            };
+            cpu.set_psr(psr & !0b111 | condition);
+        }
+        let stack = Registers::GeneralRegister(General::R6);
+        cpu.saved_ssp = self.supervisor_stack.unwrap_or(DEFAULT_SSP);
+        if cpu.psr() & PSR_USER == 0 {
+            cpu.saved_usp = cpu.read_register(stack);
+            cpu.update_register(stack, cpu.saved_ssp);
+        }
+
+        if let Some(bus) = self.bus {
+            cpu.devices = bus;
+        }
+        for (base, device) in self.devices {
+            cpu.devices.attach(base, device);
+        }
+        if let Some(seed) = self.seed {
+            // the state a snapshot would restore is the seed itself
+            cpu.devices
+                .get_mut("random")
+                .expect("a seed needs a device named `random`")
+                .restore(&seed.to_be_bytes());
+        }
+        if let Some(console) = self.console {
+            cpu.console = console;
+        }
+        cpu.files = self.files;
+        cpu.quiet = self.quiet;
+        cpu.os_traps = self.operating_system.is_some();
+        cpu.set_engine(self.engine);
+        cpu.execution_policy = self.execution_policy;
+        Machine { cpu }
+    }
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+
+    /// Execute at most `budget` instructions with the engine given to
+    /// [`MachineBuilder::engine`]
+    pub fn run(&mut self, budget: u64) -> Result<Exit, Fault> {
+        self.cpu.run(budget)
+    }
+
+    /// Execute one instruction, or take a pending interrupt
+    pub fn step(&mut self) -> StepResult {
+        self.cpu.step()
+    }
+
+    /// The word at `address` without side effects, as far as devices allow
+    pub fn peek(&self, address: u16) -> u16 {
+        self.cpu.peek(address)
+    }
+
+    /// Store `value` at `address` the way ST does, so device registers see
+    /// the write
+    pub fn poke(&mut self, address: u16, value: u16) {
+        self.cpu.write_memory(address, value);
+    }
+
+    /// R0 to R7, the PC and the condition register
+    pub fn registers(&self) -> &[u16; REGISTER_COUNT] {
+        &self.cpu.registers
+    }
+
+    pub fn register(&self, register: Registers) -> u16 {
+        self.cpu.read_register(register)
+    }
+
+    pub fn set_register(&mut self, register: Registers, value: u16) {
+        self.cpu.update_register(register, value);
+    }
+
+    /// Processor status: privilege, priority and condition codes
+    pub fn psr(&self) -> u16 {
+        self.cpu.psr()
+    }
+
+    /// The whole state, to save or to go back to with [`Machine::restore`]
+    pub fn snapshot(&self) -> Snapshot {
+        self.cpu.snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.cpu.restore(snapshot);
+    }
+
+    /// The processor underneath, with everything the `vm` binary needs.
+    /// Like `crate::unstable` it may change in any release.
+    #[doc(hidden)]
+    pub fn into_cpu(self) -> VmCPU {
+        self.cpu
+    }
+}
+
+impl VmCPU {
+    /// Execute at most `budget` instructions with the engine set by
+    /// [`VmCPU::set_engine`]
+    pub fn run(&mut self, budget: u64) -> Result<Exit, Fault> {
+        let mut executed = 0;
+        while executed < budget {
+            let (result, count) = self.advance(budget - executed);
+            executed += count;
+            match result {
+                StepResult::Continue => {}
+                StepResult::Halted => return Ok(Exit::Halted),
+                StepResult::WaitingForInput => return Ok(Exit::WaitingForInput),
+                StepResult::Fault(fault) => return Err(fault),
+            }
+        }
+        Ok(Exit::OutOfBudget)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        console::BufferConsole,
+        devices::{DeviceContext, RANDOM_BASE},
+        error::FaultKind,
+    };
+
+    #[derive(Debug)]
+    struct Constant;
+
+    impl Device for Constant {
+        fn name(&self) -> &str {
+            "constant"
+        }
+
+        fn size(&self) -> u16 {
+            1
+        }
+
+        fn read(&mut self, _offset: u16, _context: &mut DeviceContext) -> u16 {
+            42
+        }
+
+        fn write(&mut self, _offset: u16, _value: u16, _context: &mut DeviceContext) {}
+
+        fn peek(&self, _offset: u16) -> u16 {
+            42
+        }
+    }
+
+    #[test]
+    fn test_initial_state() {
+        let reset = MachineBuilder::new().image(0x3000, &[0x1234]).build();
+        assert_eq!(*reset.registers(), [0, 0, 0, 0, 0, 0, 0, 0, 0x3000, FL_ZRO]);
+        assert_eq!(reset.psr(), PSR_USER | FL_ZRO);
+        assert_eq!(reset.peek(0x3001), 0);
+
+        let machine = MachineBuilder::new()
+            .image(0x3000, &[0x1234])
+            .image(0x0200, &[0x5678])
+            .register(General::R1, 7)
+            .stack_pointer(0xFDFF)
+            .supervisor_stack_pointer(0x2FF0)
+            .entry(0x3100)
+            .psr(0x0400 | FL_NEG)
+            .device(0xFE20, Constant)
+            .build();
+        assert_eq!(machine.registers()[1], 7);
+        assert_eq!(machine.register(Registers::ProgramCounter), 0x3100);
+        // supervisor mode at priority 4, N set, on the supervisor stack
+        assert_eq!(machine.psr(), 0x0400 | FL_NEG);
+        assert_eq!(machine.registers()[6], 0x2FF0);
+        assert_eq!(machine.cpu.saved_usp, 0xFDFF);
+        assert_eq!(
+            (machine.peek(0x3000), machine.peek(0x0200)),
+            (0x1234, 0x5678)
+        );
+        // next to the standard devices
+        assert_eq!(machine.peek(0xFE20), 42);
+        assert_ne!(machine.peek(RANDOM_BASE), 0);
+
+        // two condition codes are not a valid state
+        let user = MachineBuilder::new()
+            .psr(PSR_USER | FL_NEG | FL_POS)
+            .build();
+        assert_eq!(user.psr(), PSR_USER | FL_ZRO);
+        let supervisor = MachineBuilder::new().supervisor().build();
+        assert_eq!(supervisor.psr(), FL_ZRO);
+        assert_eq!(supervisor.registers()[6], DEFAULT_SSP);
+        let user = MachineBuilder::new()
+            .stack_pointer(0xFDFF)
+            .supervisor_stack_pointer(0x2FF0)
+            .build();
+        assert_eq!((user.registers()[6], user.cpu.saved_ssp), (0xFDFF, 0x2FF0));
+
+        // in either order
+        let psr = PSR_USER | 0x0300 | FL_POS;
+        let before = MachineBuilder::new().supervisor().psr(psr).build();
+        let after = MachineBuilder::new().psr(psr).supervisor().build();
+        assert_eq!(before.psr(), 0x0300 | FL_POS);
+        assert_eq!(after.psr(), 0x0300 | FL_POS);
+    }
+
+    #[test]
+    fn test_fill() {
+        let memory = |fill: Fill| {
+            let machine = MachineBuilder::new()
+                .image(0x3000, &[0x1234, 0x5678])
+                .fill(fill)
+                .build();
+            machine.cpu.memory.words().to_vec()
+        };
+        let first = memory(Fill::Random(1));
+        assert_eq!(first, memory(Fill::Random(1)));
+        assert_ne!(first, memory(Fill::Random(2)));
+        assert!(first[0x3002..0x3100].iter().any(|word| *word != 0));
+        // the program is loaded over the fill
+        assert_eq!(first[0x3000..0x3002], [0x1234, 0x5678]);
+        assert!(memory(Fill::Zero)
+            .iter()
+            .skip(0x3002)
+            .all(|word| *word == 0));
+    }
+
+    #[test]
+    fn test_seed() {
+        let first_word = |machine: Machine| machine.peek(RANDOM_BASE);
+        let standard = first_word(MachineBuilder::new().build());
+        let seeded = first_word(MachineBuilder::new().seed(7).build());
+        assert_ne!(standard, seeded);
+        assert_eq!(seeded, first_word(MachineBuilder::new().seed(7).build()));
+        assert_eq!(
+            seeded,
+            first_word(MachineBuilder::new().bus(Bus::seeded(7)).build())
+        );
+        // the seed reaches a bus of our own
+        assert_eq!(
+            seeded,
+            first_word(MachineBuilder::new().bus(Bus::standard()).seed(7).build())
+        );
+        let mut bus = Bus::default();
+        bus.attach(0xFE20, Box::new(Random::default()));
+        let machine = MachineBuilder::new().bus(bus).seed(7).build();
+        assert_eq!(machine.peek(0xFE20), seeded);
+    }
+
+    #[test]
+    #[should_panic(expected = "a seed needs a device named `random`")]
+    fn test_seed_without_random() {
+        MachineBuilder::new().bus(Bus::default()).seed(7).build();
+    }
+
+    #[test]
+    fn test_supervisor_interrupt() {
+        let mut machine = MachineBuilder::new()
+            .assemble(
+                "
+        .ORIG x3000
+        LD R0, HANDLER_ADDR
+        STI R0, VECTOR
+        LD R0, RELOAD
+        STI R0, TRR
+        LD R0, CONTROL
+        STI R0, TCR
+LOOP    ADD R1, R1, #0
+        BRz LOOP
+        HALT
+HANDLER ADD R1, R1, #1
+        STI R1, TSR
+        STI R1, TCR
+        RTI
+HANDLER_ADDR .FILL HANDLER
+VECTOR  .FILL x0181
+RELOAD  .FILL #5
+TRR     .FILL xFE0A
+CONTROL .FILL xC000
+TCR     .FILL xFE09
+TSR     .FILL xFE08
+        .END
+",
+            )
+            .unwrap()
+            .supervisor()
+            .stack_pointer(0xFDFF)
+            .console(BufferConsole::default())
+            .quiet()
+            .build();
+        assert_eq!(machine.run(100), Ok(Exit::Halted));
+        assert_eq!(machine.registers()[1], 1);
+        // PSR and PC went on the supervisor stack and RTI took them off
+        assert_eq!(machine.registers()[6], DEFAULT_SSP);
+        assert_eq!(machine.peek(DEFAULT_SSP - 1), FL_ZRO);
+        assert_eq!(machine.psr() & PSR_USER, 0);
+        assert_eq!(machine.cpu.saved_usp, 0xFDFF);
+    }
+
+    #[test]
+    fn test_step_poke_restore() {
+        let mut machine = MachineBuilder::new()
+            .assemble(".ORIG x3000\nLD R0, VALUE\nHALT\nVALUE .FILL #1\n.END")
+            .unwrap()
+            .console(BufferConsole::default())
+            .build();
+        let start = machine.snapshot();
+        machine.poke(0x3002, 9);
+        assert_eq!(machine.step(), StepResult::Continue);
+        assert_eq!(machine.register(Registers::GeneralRegister(General::R0)), 9);
+        assert_eq!(machine.register(Registers::ProgramCounter), 0x3001);
+
+        machine.restore(&start);
+        assert_eq!(machine.peek(0x3002), 1);
+        machine.set_register(Registers::ProgramCounter, 0x3001);
+        assert_eq!(machine.run(10), Ok(Exit::Halted));
+        assert_eq!(machine.registers()[0], 0);
+    }
+
+    #[test]
+    fn test_run_ends() {
+        let machine = |source: &str| {
+            MachineBuilder::new()
+                .assemble(&format!(".ORIG x3000\n{}\n.END", source))
+                .unwrap()
+                .console(BufferConsole::default())
+                .build()
+        };
+        assert_eq!(machine("HALT").run(10), Ok(Exit::Halted));
+        assert_eq!(machine("GETC").run(10), Ok(Exit::WaitingForInput));
+        assert_eq!(machine("LOOP BR LOOP").run(10), Ok(Exit::OutOfBudget));
+
+        let mut faulty = machine("ADD R0, R0, #1\nTRAP x40");
+        assert_eq!(
+            faulty.run(10),
+            Err(Fault {
+                pc: 0x3001,
+                word: 0xF040,
+                kind: FaultKind::UnsupportedTrap(0x40)
+            })
+        );
+        // stopped in front of the TRAP, R7 untouched
+        assert_eq!(faulty.registers()[8], 0x3001);
+        assert_eq!(faulty.registers()[7], 0);
+    }
+}
@@ -34,23 +34,11 @@
 //! FL_ZRO = 1 << 1, /* 0 */
 //! FL_NEG = 1 << 2, /* - */
 
-use cpu::VmCPU;
+mod cli;
+mod dap;
+mod repl;
 
-use crate::memory::Memory;
-
-mod cpu;
-mod instructions;
-mod memory;
-mod register;
-mod trap;
-
-fn main() -> std::io::Result<()> {
-    let file_name = "./resources/rogue.obj";
-    let memory = Memory::load_from_file(file_name)?;
-
-    let mut vm = VmCPU::new([0; 10], memory);
-
-    vm.execute();
-
-    Ok(())
+fn main() {
+    let status = cli::main(std::env::args().skip(1).collect());
+    std::process::exit(status);
 }
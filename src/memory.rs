@@ -1,64 +1,156 @@
 use std::{
+    fmt,
     fs::File,
     io::{self, Read},
     path::Path,
 };
 
-#[derive(Debug)]
+use crate::{history::UndoRecord, instructions::Instructions};
+
 pub struct Memory {
-    data: [u16; 1 << 16],
+    data: Box<[u16; 1 << 16]>,
     pub pc_start: usize,
+    // memory writes of the current instruction while history is on
+    journal: Option<UndoRecord>,
+    // decoded instructions by address, filled in as they are fetched and
+    // cleared when the word changes
+    decoded: Vec<Option<Instructions>>,
+    decode_cache: bool,
 }
 
-const KEY_BOARD_STATUS: u16 = 0xFE00;
-const KEY_BOARD_DATA: u16 = 0xFE02;
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Memory")
+            .field("pc_start", &self.pc_start)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Origin and words of an `.obj` file
+pub fn parse_obj(buf: &[u8]) -> io::Result<(u16, Vec<u16>)> {
+    let mut iter = buf.chunks(2);
+    let pc_buffer = iter
+        .next()
+        .filter(|chunk| chunk.len() == 2)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing origin"))?;
+
+    let origin = (pc_buffer[0] as u16) << 8 | pc_buffer[1] as u16;
+    let words = iter
+        .filter(|elem| elem.len() == 2)
+        .map(|elem| (elem[0] as u16) << 8 | elem[1] as u16)
+        .collect();
+    Ok((origin, words))
+}
 
 impl Memory {
     pub fn load_from_file<P: AsRef<Path>>(file_path: P) -> io::Result<Self> {
-        let mut file = File::open(file_path).expect("File not found");
+        let mut file = File::open(file_path)?;
 
         let mut buf: Vec<u8> = Vec::new();
         file.read_to_end(&mut buf)?;
-        let mut iter = buf.chunks(2);
-        let pc_buffer = iter.next().unwrap();
-
-        let mut pc: usize = ((pc_buffer[0] as u16) << 8 | pc_buffer[1] as u16) as usize;
-
-        let pc_start = pc;
-
-        let mut memory = [0u16; 1 << 16];
+        Self::load_from_bytes(&buf)
+    }
 
-        for elem in iter {
-            let instruction = (elem[0] as u16) << 8 | elem[1] as u16;
+    /// Load an image in `.obj` layout: big endian origin followed by words
+    pub fn load_from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let (origin, words) = parse_obj(buf)?;
+        Ok(Self::load_from_words(origin, &words))
+    }
 
-            memory[pc] = instruction;
+    pub fn load_from_words(origin: u16, words: &[u16]) -> Self {
+        // boxed, 128KiB is too much to move around on the stack
+        let mut memory: Box<[u16; 1 << 16]> = vec![0u16; 1 << 16].try_into().unwrap();
 
-            pc = pc + 1;
+        for (offset, instruction) in words.iter().enumerate() {
+            memory[(origin as usize + offset) & 0xFFFF] = *instruction;
         }
 
-        Ok(Self {
+        Self {
             data: memory,
-            pc_start,
-        })
+            pc_start: origin as usize,
+            journal: None,
+            decoded: Vec::new(),
+            decode_cache: true,
+        }
+    }
+
+    /// Copy `words` in from `origin` on top of what is loaded, for example an
+    /// operating system image next to a program. `pc_start` stays as it is.
+    pub fn load_words(&mut self, origin: u16, words: &[u16]) {
+        for (offset, word) in words.iter().enumerate() {
+            let location = origin.wrapping_add(offset as u16);
+            self.data[location as usize] = *word;
+            self.invalidate(location);
+        }
     }
 
     pub fn write_memory(&mut self, location: usize, value: u16) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.memory.push((location as u16, self.data[location]));
+        }
         self.data[location] = value;
+        self.invalidate(location as u16);
     }
 
-    pub fn read_memory(&mut self, location: u16) -> u16 {
-        if location == KEY_BOARD_STATUS {
-            let mut buffer = [0; 1];
-            std::io::stdin().read_exact(&mut buffer).unwrap();
-            println!("Key pressed: {}", buffer[0] as char);
+    /// The instruction at `location`, decoded once and then served from a
+    /// cache until the word is written. RAM only, like [`Memory::peek`].
+    pub fn fetch(&mut self, location: u16) -> Instructions {
+        if !self.decode_cache {
+            return Instructions::from(self.data[location as usize]);
+        }
+        if self.decoded.is_empty() {
+            self.decoded = vec![None; 1 << 16];
+        }
+        *self.decoded[location as usize]
+            .get_or_insert_with(|| Instructions::from(self.data[location as usize]))
+    }
+
+    /// Turn the decode cache used by [`Memory::fetch`] on or off, it is on
+    /// by default
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+        self.decoded = Vec::new();
+    }
+
+    pub fn decode_cache(&self) -> bool {
+        self.decode_cache
+    }
 
-            if buffer[0] != 0 {
-                self.data[KEY_BOARD_STATUS as usize] = 1 << 15;
-                self.data[KEY_BOARD_DATA as usize] = buffer[0] as u16;
-            } else {
-                self.data[KEY_BOARD_STATUS as usize] = 0;
-            }
+    fn invalidate(&mut self, location: u16) {
+        if let Some(decoded) = self.decoded.get_mut(location as usize) {
+            *decoded = None;
         }
-        return self.data[location as usize];
+    }
+
+    /// Start recording writes for an undo record
+    pub(crate) fn begin_journal(&mut self) {
+        self.journal = Some(UndoRecord::default());
+    }
+
+    pub(crate) fn take_journal(&mut self) -> Option<UndoRecord> {
+        self.journal.take()
+    }
+
+    /// All 65536 words
+    pub fn words(&self) -> &[u16] {
+        &self.data[..]
+    }
+
+    /// Replace the whole address space, used by snapshots
+    pub(crate) fn load_image(&mut self, words: &[u16]) {
+        self.data.copy_from_slice(words);
+        self.decoded.fill(None);
+    }
+
+    /// Write without journaling, used when undoing
+    pub(crate) fn restore(&mut self, location: u16, value: u16) {
+        self.data[location as usize] = value;
+        self.invalidate(location);
+    }
+
+    /// Read RAM only. Addresses on the device page may be claimed by a
+    /// device, see [`crate::cpu::VmCPU::peek`]
+    pub fn peek(&self, location: u16) -> u16 {
+        self.data[location as usize]
     }
 }
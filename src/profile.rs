@@ -0,0 +1,281 @@
+//! Instruction level profiler
+//!
+//! While a [`Profiler`] is attached to a [`crate::cpu::VmCPU`] every
+//! executed instruction is counted three ways: by address, by opcode and by
+//! the chain of subroutines active when it ran. Subroutines are the targets
+//! of JSR/JSRR as tracked by the shadow call stack, named from the symbol
+//! table when reporting.
+//!
+//! Reports come as text tables ([`Profiler::report`]) or in the collapsed
+//! stack format read by flamegraph tools ([`Profiler::collapsed_stacks`]):
+//!
+//! ```text
+//! MAIN;DRAW_BOARD;PRINT_ROW 1520
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use crate::debug_info::SymbolTable;
+
+pub const OPCODE_NAMES: [&str; 16] = [
+    "BR", "ADD", "LD", "ST", "JSR", "AND", "LDR", "STR", "RTI", "NOT", "LDI", "STI", "JMP", "RES",
+    "LEA", "TRAP",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubroutineCounts {
+    pub calls: u64,
+    /// instructions run while the subroutine was anywhere on the stack
+    pub inclusive: u64,
+    /// instructions of the subroutine itself
+    pub exclusive: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    // where the program started, the outermost frame of every stack
+    entry: u16,
+    total: u64,
+    addresses: Vec<u64>,
+    opcodes: [u64; 16],
+    subroutines: BTreeMap<u16, SubroutineCounts>,
+    // subroutine entry points from the outermost in, and their count
+    stacks: HashMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+    pub fn new(entry: u16) -> Self {
+        Self {
+            entry,
+            total: 0,
+            addresses: vec![0; 1 << 16],
+            opcodes: [0; 16],
+            subroutines: BTreeMap::new(),
+            stacks: HashMap::new(),
+        }
+    }
+
+    /// Count the instruction `word` at `pc`, run inside the subroutines
+    /// entered at `targets`, outermost first
+    pub fn record(&mut self, pc: u16, word: u16, targets: &[u16]) {
+        self.total += 1;
+        self.addresses[pc as usize] += 1;
+        self.opcodes[(word >> 12) as usize] += 1;
+
+        let mut stack = Vec::with_capacity(targets.len() + 1);
+        stack.push(self.entry);
+        stack.extend_from_slice(targets);
+
+        // recursive subroutines only count once for inclusive time
+        let mut seen = stack.clone();
+        seen.sort_unstable();
+        seen.dedup();
+        for target in seen {
+            self.subroutines.entry(target).or_default().inclusive += 1;
+        }
+        self.subroutines
+            .entry(*stack.last().unwrap())
+            .or_default()
+            .exclusive += 1;
+        *self.stacks.entry(stack).or_default() += 1;
+    }
+
+    /// A call to the subroutine at `target` was made
+    pub fn enter(&mut self, target: u16) {
+        self.subroutines.entry(target).or_default().calls += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn count_at(&self, address: u16) -> u64 {
+        self.addresses[address as usize]
+    }
+
+    pub fn opcode_count(&self, opcode: usize) -> u64 {
+        self.opcodes[opcode]
+    }
+
+    pub fn subroutine(&self, target: u16) -> Option<SubroutineCounts> {
+        self.subroutines.get(&target).copied()
+    }
+
+    fn name(&self, symbols: &SymbolTable, address: u16) -> String {
+        match symbols.name_at(address) {
+            Some(name) => name.to_string(),
+            None if address == self.entry => "<entry>".to_string(),
+            None => format!("x{:04X}", address),
+        }
+    }
+
+    fn location(symbols: &SymbolTable, address: u16) -> String {
+        match symbols.nearest(address) {
+            Some((label, start)) if start == address => label.to_string(),
+            Some((label, start)) => format!("{}+{}", label, address - start),
+            None => String::new(),
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => count as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// Text tables of the `top` hottest addresses, the opcode histogram and
+    /// the subroutines ordered by inclusive count
+    pub fn report(&self, symbols: &SymbolTable, top: usize) -> String {
+        let mut text = String::new();
+        writeln!(text, "{} instructions executed", self.total).unwrap();
+
+        let mut hot: Vec<(usize, u64)> = self
+            .addresses
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        writeln!(text, "\nHot spots").unwrap();
+        writeln!(text, "{:<8}{:>12}{:>8}  location", "address", "count", "%").unwrap();
+        for (address, count) in hot.into_iter().take(top) {
+            writeln!(
+                text,
+                "x{:04X}   {:>12}{:>8.2}  {}",
+                address,
+                count,
+                self.percent(count),
+                Self::location(symbols, address as u16)
+            )
+            .unwrap();
+        }
+
+        writeln!(text, "\nOpcodes").unwrap();
+        writeln!(text, "{:<8}{:>12}{:>8}", "opcode", "count", "%").unwrap();
+        let mut opcodes: Vec<(usize, u64)> = self
+            .opcodes
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (opcode, count) in opcodes {
+            writeln!(
+                text,
+                "{:<8}{:>12}{:>8.2}",
+                OPCODE_NAMES[opcode],
+                count,
+                self.percent(count)
+            )
+            .unwrap();
+        }
+
+        writeln!(text, "\nSubroutines").unwrap();
+        writeln!(
+            text,
+            "{:<20}{:>8}{:>12}{:>8}{:>12}{:>8}",
+            "name", "calls", "inclusive", "%", "exclusive", "%"
+        )
+        .unwrap();
+        let mut subroutines: Vec<(&u16, &SubroutineCounts)> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (target, counts) in subroutines {
+            writeln!(
+                text,
+                "{:<20}{:>8}{:>12}{:>8.2}{:>12}{:>8.2}",
+                self.name(symbols, *target),
+                counts.calls,
+                counts.inclusive,
+                self.percent(counts.inclusive),
+                counts.exclusive,
+                self.percent(counts.exclusive)
+            )
+            .unwrap();
+        }
+        text
+    }
+
+    /// One line per distinct call chain, `outer;inner count`, sorted
+    pub fn collapsed_stacks(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let names: Vec<String> = stack
+                    .iter()
+                    .map(|address| self.name(symbols, *address))
+                    .collect();
+                format!("{} {}\n", names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::{assembler, cpu::VmCPU, memory::Memory};
+
+    #[test]
+    fn test_counts_per_subroutine() {
+        let assembly = assembler::assemble(
+            "
+        .ORIG x3000
+MAIN    JSR TWICE
+        JSR TWICE
+        HALT
+TWICE   ST R7, SAVE
+        JSR ONCE
+        JSR ONCE
+        LD R7, SAVE
+        RET
+ONCE    ADD R0, R0, #1
+        RET
+SAVE    .FILL #0
+        .END
+        ",
+        )
+        .unwrap();
+        let memory = Memory::load_from_words(assembly.origin, &assembly.words);
+        let mut vm = VmCPU::new([0; 10], memory);
+        vm.console = Box::new(BufferConsole::default());
+        vm.profiler = Some(Profiler::new(assembly.origin));
+        vm.execute().unwrap();
+
+        let profiler = vm.profiler.unwrap();
+        let symbols = &assembly.symbols;
+        let address = |name| symbols.address_of(name).unwrap();
+
+        // 3 in MAIN, 2 * 5 in TWICE, 4 * 2 in ONCE
+        assert_eq!(profiler.total(), 21);
+        assert_eq!(profiler.count_at(address("ONCE")), 4);
+        assert_eq!(profiler.opcode_count(4), 6);
+        assert_eq!(
+            profiler.subroutine(address("TWICE")),
+            Some(SubroutineCounts {
+                calls: 2,
+                inclusive: 18,
+                exclusive: 10,
+            })
+        );
+        assert_eq!(profiler.subroutine(address("MAIN")).unwrap().inclusive, 21);
+
+        assert_eq!(
+            profiler.collapsed_stacks(symbols),
+            "MAIN 3\nMAIN;TWICE 10\nMAIN;TWICE;ONCE 8\n"
+        );
+        let report = profiler.report(symbols, 5);
+        assert!(report.contains("21 instructions executed"));
+        assert!(report.lines().any(|line| line.starts_with("JSR ")));
+    }
+}
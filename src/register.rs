@@ -4,7 +4,7 @@ use std::io;
 
 pub const REGISTER_COUNT: usize = 10;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum General {
     R0,
     R1,
@@ -16,7 +16,7 @@ pub enum General {
     R7,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Registers {
     GeneralRegister(General),
     ProgramCounter, /* program counter */
@@ -31,7 +31,7 @@ impl From<Registers> for usize {
 
 impl From<u16> for Registers {
     fn from(value: u16) -> Self {
-        Registers::get_register(value as u16).unwrap()
+        Registers::get_register(value).unwrap()
     }
 }
 
@@ -63,7 +63,7 @@ impl Registers {
             7 => Ok(Registers::GeneralRegister(General::R7)),
             8 => Ok(Registers::ProgramCounter),
             9 => Ok(Registers::Condition),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "Invalid register")),
+            _ => Err(io::Error::other("Invalid register")),
         }
     }
 }
@@ -0,0 +1,285 @@
+//! Line based debugger for `vm debug`
+//!
+//! A terminal front end for [`Debugger`]. The program reads keys queued with
+//! `input` rather than the terminal, so commands and program input do not
+//! get mixed up; what it prints is shown after each command.
+
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use vm::{
+    console::BufferConsole,
+    debug_info::DebugInfo,
+    disassembler,
+    register::Registers,
+    unstable::{
+        cpu::{VmCPU, FL_NEG, FL_POS, FL_ZRO},
+        debugger::{Debugger, RunMode, StopReason},
+        testing::Script,
+    },
+};
+
+pub const HELP: &str = "\
+step [n]         (s) execute n instructions, into subroutines
+next             (n) execute one instruction, over subroutines
+finish           run until the current subroutine returns
+continue         (c) run until a breakpoint, watchpoint or HALT
+back [n]         undo n instructions
+reverse          run backwards until a breakpoint or watchpoint
+break <loc>      (b) stop before executing <loc>
+delete <loc>     (d) remove a breakpoint or watchpoint
+watch <loc>      (w) stop when the value at <loc> changes
+regs             (r) show registers
+x <loc> [n]      disassemble n words from <loc>
+bt               show the call stack
+input <text>     queue keys for the program, Enter added unless text ends in \\
+quit             (q) leave
+<loc> is an address like x3000 or a label
+";
+
+pub struct Repl {
+    pub debugger: Debugger,
+    console: Rc<RefCell<BufferConsole>>,
+    // instructions one command may run before giving up
+    budget: usize,
+}
+
+impl Repl {
+    pub fn new(mut vm: VmCPU, debug_info: DebugInfo, budget: usize) -> Self {
+        let console = Rc::new(RefCell::new(BufferConsole::default()));
+        vm.console = Box::new(console.clone());
+        Self {
+            debugger: Debugger::new(vm, debug_info),
+            console,
+            budget,
+        }
+    }
+
+    /// Read commands from `input` until `quit` or the end of input
+    pub fn run(&mut self, mut input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.location())?;
+        loop {
+            write!(out, "(vm) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            match self.command(line.trim()) {
+                Some(text) => write!(out, "{}", text)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Execute one command and return what to show, `None` to quit
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+        let count = || argument.and_then(|n| n.parse().ok()).unwrap_or(1usize);
+        let symbols = &self.debugger.debug_info.symbols;
+        let location = argument.and_then(|text| symbols.resolve(text));
+
+        let text = match (command, location) {
+            ("", _) => String::new(),
+            ("q" | "quit", _) => return None,
+            ("h" | "help", _) => HELP.to_string(),
+            ("s" | "step", _) => self.resume(RunMode::StepIn, count()),
+            ("n" | "next", _) => {
+                let mode = self.debugger.step_over_mode();
+                self.resume(mode, 1)
+            }
+            ("finish", _) => {
+                let mode = self.debugger.step_out_mode();
+                self.resume(mode, 1)
+            }
+            ("c" | "continue", _) => self.resume(RunMode::Continue, 1),
+            ("back", _) => self.resume(RunMode::ReverseStep, count()),
+            ("reverse", _) => self.resume(RunMode::ReverseContinue, 1),
+            ("b" | "break", Some(address)) => {
+                self.debugger.breakpoints.insert(address);
+                format!("breakpoint at {}\n", self.describe(address))
+            }
+            ("w" | "watch", Some(address)) => {
+                self.debugger.watchpoints.insert(address);
+                format!("watching {}\n", self.describe(address))
+            }
+            ("d" | "delete", Some(address)) => {
+                let removed = self.debugger.breakpoints.remove(&address)
+                    | self.debugger.watchpoints.remove(&address);
+                match removed {
+                    true => format!("deleted {}\n", self.describe(address)),
+                    false => format!("nothing set at {}\n", self.describe(address)),
+                }
+            }
+            ("r" | "regs", _) => self.registers(),
+            ("x", Some(address)) => {
+                let count = words.next().and_then(|n| n.parse().ok()).unwrap_or(8u16);
+                let memory: Vec<u16> = (0..count)
+                    .map(|offset| self.debugger.vm.peek(address.wrapping_add(offset)))
+                    .collect();
+                disassembler::listing(address, &memory, &self.debugger.debug_info.symbols)
+            }
+            ("bt", _) => self
+                .debugger
+                .backtrace()
+                .iter()
+                .enumerate()
+                .map(|(index, frame)| format!("#{} {}\n", index, frame))
+                .collect(),
+            ("input", _) => {
+                let text = line["input".len()..].trim_start();
+                match Script::parse(text) {
+                    Ok(script) => {
+                        let mut console = self.console.borrow_mut();
+                        console
+                            .input
+                            .extend(script.keys.iter().map(|(_, key)| *key));
+                        format!("{} keys queued\n", console.input.len())
+                    }
+                    Err(e) => format!("{}\n", e),
+                }
+            }
+            ("b" | "break" | "w" | "watch" | "d" | "delete" | "x", None) => match argument {
+                Some(text) => format!("unknown location `{}`\n", text),
+                None => format!("`{}` needs a location\n", command),
+            },
+            (other, _) => format!("unknown command `{}`, try `help`\n", other),
+        };
+        Some(text)
+    }
+
+    /// Run in `mode`, `times` over for single steps
+    fn resume(&mut self, mode: RunMode, times: usize) -> String {
+        let mut stop = None;
+        for _ in 0..times {
+            self.debugger.resume(mode);
+            stop = self.debugger.run(self.budget);
+            if stop != Some(StopReason::Step) {
+                break;
+            }
+        }
+        let mut text =
+            String::from_utf8_lossy(&self.console.borrow_mut().take_output()).into_owned();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        match stop {
+            Some(StopReason::Step) => {}
+            Some(StopReason::Breakpoint(_)) => text.push_str("breakpoint\n"),
+            Some(StopReason::Watchpoint(address)) => {
+                writeln!(
+                    text,
+                    "{} changed to x{:04X}",
+                    self.describe(address),
+                    self.debugger.vm.peek(address)
+                )
+                .unwrap();
+            }
+            Some(StopReason::Halted) => {
+                text.push_str("halted\n");
+                return text;
+            }
+            Some(StopReason::WaitingForInput) => text.push_str("waiting for input\n"),
+            Some(StopReason::StartOfHistory) => text.push_str("no more history\n"),
+            Some(StopReason::Fault(fault)) => writeln!(text, "fault: {}", fault).unwrap(),
+            None => writeln!(text, "still running after {} instructions", self.budget).unwrap(),
+        }
+        text.push_str(&self.location());
+        text.push('\n');
+        text
+    }
+
+    /// The next instruction, as `x3002  LOOP  BRp LOOP`
+    fn location(&self) -> String {
+        let pc = self.debugger.pc();
+        let symbols = &self.debugger.debug_info.symbols;
+        let word = self.debugger.vm.peek(pc);
+        let instruction = disassembler::disassemble(word, pc, symbols);
+        match symbols.name_at(pc) {
+            Some(label) => format!("x{:04X}  {}  {}", pc, label, instruction),
+            None => format!("x{:04X}  {}", pc, instruction),
+        }
+    }
+
+    fn describe(&self, address: u16) -> String {
+        match self.debugger.debug_info.symbols.name_at(address) {
+            Some(label) => format!("x{:04X} ({})", address, label),
+            None => format!("x{:04X}", address),
+        }
+    }
+
+    fn registers(&self) -> String {
+        let vm = &self.debugger.vm;
+        let general: Vec<String> = (0..8u16)
+            .map(|index| format!("R{} x{:04X}", index, vm.read_register(index.into())))
+            .collect();
+        let mut text = format!("{}\n{}", general[..4].join("  "), general[4..].join("  "));
+        let condition = vm.read_register(Registers::Condition);
+        let flags: String = [(FL_NEG, 'n'), (FL_ZRO, 'z'), (FL_POS, 'p')]
+            .iter()
+            .filter(|(flag, _)| condition & flag != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        writeln!(
+            text,
+            "\nPC x{:04X}  PSR x{:04X}  CC {}",
+            self.debugger.pc(),
+            vm.psr(),
+            flags
+        )
+        .unwrap();
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use vm::{assembler, debug_info::DebugInfo, memory::Memory};
+
+    #[test]
+    fn test_commands() {
+        let assembly = assembler::assemble(
+            "
+        .ORIG x3000
+        GETC
+        JSR NEXT
+        OUT
+        HALT
+NEXT    ADD R0, R0, #1
+        RET
+        .END
+",
+        )
+        .unwrap();
+        let vm = VmCPU::new(
+            [0; 10],
+            Memory::load_from_words(assembly.origin, &assembly.words),
+        );
+        let debug_info = DebugInfo {
+            symbols: assembly.symbols,
+            ..Default::default()
+        };
+        let mut repl = Repl::new(vm, debug_info, 1000);
+
+        let mut run = |line: &str| repl.command(line).unwrap();
+        assert_eq!(run("s"), "waiting for input\nx3000  GETC\n");
+        assert_eq!(run("input a\\"), "1 keys queued\n");
+        assert_eq!(run("b NEXT"), "breakpoint at x3004 (NEXT)\n");
+        assert_eq!(run("c"), "breakpoint\nx3004  NEXT  ADD R0, R0, #1\n");
+        assert_eq!(run("bt"), "#0 x3004 in NEXT\n#1 x3001\n");
+        assert_eq!(run("finish"), "x3002  OUT\n");
+        assert!(run("r").starts_with("R0 x0062  R1 x0000"));
+        assert_eq!(run("back 2"), "x3004  NEXT  ADD R0, R0, #1\n");
+        assert_eq!(run("x NOWHERE"), "unknown location `NOWHERE`\n");
+        assert_eq!(run("x x3001 1"), "x3001                4802  JSR NEXT\n");
+        assert_eq!(run("c"), "bExiting\nhalted\n");
+        assert!(repl.command("q").is_none());
+    }
+}
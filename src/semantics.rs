@@ -0,0 +1,93 @@
+//! Immediate and offset fields against the ISA table
+//!
+//! Every value of every field is decoded in each instruction that has one
+//! and compared with the extension the ISA specifies: sign extension for
+//! imm5 and the offsets, zero extension for the trap vector.
+
+use crate::instructions::{Instructions, JumpRegisterType, LoadType};
+
+/// The ISA table: field, the opcode and bits choosing the form with the
+/// field, the register and condition bits around it, which must not change
+/// it, its width and whether it is sign extended
+const FIELDS: [(&str, u16, u16, u32, bool); 12] = [
+    ("ADD imm5", 0x1020, 0x0FC0, 5, true),
+    ("AND imm5", 0x5020, 0x0FC0, 5, true),
+    ("BR PCoffset9", 0x0000, 0x0E00, 9, true),
+    ("LD PCoffset9", 0x2000, 0x0E00, 9, true),
+    ("ST PCoffset9", 0x3000, 0x0E00, 9, true),
+    ("LDI PCoffset9", 0xA000, 0x0E00, 9, true),
+    ("STI PCoffset9", 0xB000, 0x0E00, 9, true),
+    ("LEA PCoffset9", 0xE000, 0x0E00, 9, true),
+    ("JSR PCoffset11", 0x4800, 0x0000, 11, true),
+    ("LDR offset6", 0x6000, 0x0FC0, 6, true),
+    ("STR offset6", 0x7000, 0x0FC0, 6, true),
+    ("TRAP trapvect8", 0xF000, 0x0000, 8, false),
+];
+
+/// The immediate or offset `instruction` decoded to
+fn decoded(instruction: Instructions) -> Option<u16> {
+    match instruction {
+        Instructions::Add {
+            add_type: LoadType::Immediate { value },
+            ..
+        }
+        | Instructions::And {
+            add_type: LoadType::Immediate { value },
+            ..
+        } => Some(value),
+        Instructions::Branch { pc_offset_9, .. }
+        | Instructions::LoadDirect { pc_offset_9, .. }
+        | Instructions::StoreDirect { pc_offset_9, .. }
+        | Instructions::LoadIndirect { pc_offset_9, .. }
+        | Instructions::StoreIndirect { pc_offset_9, .. }
+        | Instructions::LoadEffectiveAddress { pc_offset_9, .. } => Some(pc_offset_9),
+        Instructions::JumpRegister(JumpRegisterType::FromOffset { pc_offset_11 }) => {
+            Some(pc_offset_11)
+        }
+        Instructions::LoadRegister { offset6, .. }
+        | Instructions::StoreRegister { offset6, .. } => Some(offset6),
+        Instructions::Trap { trap_vector } => Some(trap_vector),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_field_extension() {
+    for (name, form, others, width, signed) in FIELDS {
+        for raw in 0..1u16 << width {
+            let sign = raw >> (width - 1) & 1 == 1;
+            let expected = match signed && sign {
+                true => raw | 0xFFFF << width,
+                false => raw,
+            };
+            for others in [0, others] {
+                let word = form | others | raw;
+                assert_eq!(
+                    decoded(Instructions::from(word)),
+                    Some(expected),
+                    "{} in x{:04X}",
+                    name,
+                    word
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_extremes() {
+    // AND R0, R0, #-1 keeps every bit, #-16 clears the low four
+    for (word, value) in [(0x503F, 0xFFFF), (0x5030, 0xFFF0), (0x502F, 0x000F)] {
+        let instruction = Instructions::from(word);
+        assert_eq!(decoded(instruction), Some(value), "x{:04X}", word);
+    }
+    // the most negative offsets
+    for (word, value) in [(0x0100, 0xFF00), (0x4C00, 0xFC00), (0x6020, 0xFFE0)] {
+        assert_eq!(
+            decoded(Instructions::from(word)),
+            Some(value),
+            "x{:04X}",
+            word
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Save states of the whole machine
+//!
+//! On disk a snapshot is a header followed by tagged sections:
+//!
+//! ```text
+//! "LC3S" | version: u16 | section*
+//! section = tag: [u8; 4] | length: u32 | payload
+//! ```
+//!
+//! All integers are big endian, like `.obj` files. Readers skip sections
+//! they do not know, so later versions can add state without breaking old
+//! snapshots.
+//!
+//! | tag    | payload                                                        |
+//! |--------|----------------------------------------------------------------|
+//! | `REGS` | register count: u16, registers: u16 each                       |
+//! | `PSR ` | processor status register: u16                                 |
+//! | `SSP ` | saved supervisor and saved user stack pointer: u16 each        |
+//! | `ORIG` | load address of the program: u16                               |
+//! | `MEM ` | 256 pages of 256 words, see below                              |
+//! | `INPT` | input bytes read ahead but not yet consumed by the program     |
+//! | `DEV ` | name length: u16, name, state; one section per device          |
+//!
+//! Memory is written page by page. A run of all-zero pages becomes
+//! `0x00, count: u16`; any other page is `0x01` followed by its 256 words.
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use crate::{cpu::DEFAULT_SSP, register::REGISTER_COUNT};
+
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"LC3S";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const PAGE_SIZE: usize = 256;
+const PAGE_COUNT: usize = (1 << 16) / PAGE_SIZE;
+const ZERO_PAGES: u8 = 0x00;
+const RAW_PAGE: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: [u16; REGISTER_COUNT],
+    pub psr: u16,
+    /// the stack pointers of both modes not currently in R6
+    pub saved_stack_pointers: [u16; 2],
+    pub pc_start: u16,
+    /// all 65536 words
+    pub memory: Vec<u16>,
+    pub pending_input: Vec<u8>,
+    /// internal state of devices, keyed by device name. Device registers
+    /// that live in the memory map are already part of `memory`.
+    pub devices: BTreeMap<String, Vec<u8>>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < count {
+            return Err(invalid("truncated snapshot"));
+        }
+        let (head, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn push_section(bytes: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    bytes.extend_from_slice(tag);
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+}
+
+fn encode_memory(memory: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut zero_run: u16 = 0;
+
+    for page in memory.chunks(PAGE_SIZE) {
+        if page.iter().all(|word| *word == 0) {
+            zero_run += 1;
+            continue;
+        }
+        if zero_run > 0 {
+            bytes.push(ZERO_PAGES);
+            bytes.extend_from_slice(&zero_run.to_be_bytes());
+            zero_run = 0;
+        }
+        bytes.push(RAW_PAGE);
+        for word in page {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+    }
+    if zero_run > 0 {
+        bytes.push(ZERO_PAGES);
+        bytes.extend_from_slice(&zero_run.to_be_bytes());
+    }
+    bytes
+}
+
+fn decode_memory(payload: &[u8]) -> io::Result<Vec<u16>> {
+    let mut reader = Reader { bytes: payload };
+    let mut memory = Vec::with_capacity(1 << 16);
+
+    while memory.len() < PAGE_COUNT * PAGE_SIZE {
+        match reader.u8()? {
+            ZERO_PAGES => {
+                let count = reader.u16()? as usize;
+                memory.resize(memory.len() + count * PAGE_SIZE, 0);
+            }
+            RAW_PAGE => {
+                for _ in 0..PAGE_SIZE {
+                    memory.push(reader.u16()?);
+                }
+            }
+            _ => return Err(invalid("unknown memory page encoding")),
+        }
+    }
+    if memory.len() != PAGE_COUNT * PAGE_SIZE || !reader.bytes.is_empty() {
+        return Err(invalid("memory section does not cover 64K words"));
+    }
+    Ok(memory)
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+
+        let mut registers = (REGISTER_COUNT as u16).to_be_bytes().to_vec();
+        for register in self.registers {
+            registers.extend_from_slice(&register.to_be_bytes());
+        }
+        push_section(&mut bytes, b"REGS", &registers);
+        push_section(&mut bytes, b"PSR ", &self.psr.to_be_bytes());
+        let stack_pointers: Vec<u8> = self
+            .saved_stack_pointers
+            .iter()
+            .flat_map(|pointer| pointer.to_be_bytes())
+            .collect();
+        push_section(&mut bytes, b"SSP ", &stack_pointers);
+        push_section(&mut bytes, b"ORIG", &self.pc_start.to_be_bytes());
+        push_section(&mut bytes, b"MEM ", &encode_memory(&self.memory));
+        push_section(&mut bytes, b"INPT", &self.pending_input);
+
+        for (name, state) in &self.devices {
+            let mut payload = (name.len() as u16).to_be_bytes().to_vec();
+            payload.extend_from_slice(name.as_bytes());
+            payload.extend_from_slice(state);
+            push_section(&mut bytes, b"DEV ", &payload);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader { bytes };
+        if reader.take(4)? != SNAPSHOT_MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let version = reader.u16()?;
+        if version > SNAPSHOT_VERSION {
+            return Err(invalid(&format!(
+                "snapshot version {} is newer than supported version {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+
+        let mut registers = None;
+        let mut psr = None;
+        let mut saved_stack_pointers = [DEFAULT_SSP, 0];
+        let mut pc_start = 0;
+        let mut memory = None;
+        let mut pending_input = Vec::new();
+        let mut devices = BTreeMap::new();
+
+        while !reader.bytes.is_empty() {
+            let tag = reader.take(4)?;
+            let length = reader.u32()? as usize;
+            let payload = reader.take(length)?;
+            let mut section = Reader { bytes: payload };
+
+            match tag {
+                b"REGS" => {
+                    let count = section.u16()? as usize;
+                    if count != REGISTER_COUNT {
+                        return Err(invalid("unexpected register count"));
+                    }
+                    let mut values = [0; REGISTER_COUNT];
+                    for value in values.iter_mut() {
+                        *value = section.u16()?;
+                    }
+                    registers = Some(values);
+                }
+                b"PSR " => psr = Some(section.u16()?),
+                b"SSP " => saved_stack_pointers = [section.u16()?, section.u16()?],
+                b"ORIG" => pc_start = section.u16()?,
+                b"MEM " => memory = Some(decode_memory(payload)?),
+                b"INPT" => pending_input = payload.to_vec(),
+                b"DEV " => {
+                    let length = section.u16()? as usize;
+                    let name = String::from_utf8(section.take(length)?.to_vec())
+                        .map_err(|_| invalid("device name is not UTF-8"))?;
+                    devices.insert(name, section.bytes.to_vec());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            registers: registers.ok_or_else(|| invalid("missing REGS section"))?,
+            psr: psr.ok_or_else(|| invalid("missing PSR section"))?,
+            saved_stack_pointers,
+            pc_start,
+            memory: memory.ok_or_else(|| invalid("missing MEM section"))?,
+            pending_input,
+            devices,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, file_path: P) -> io::Result<()> {
+        fs::write(file_path, self.to_bytes())
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(file_path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(file_path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::{cpu::VmCPU, memory::Memory};
+
+    fn vm(file_name: &str) -> VmCPU {
+        let memory = Memory::load_from_file(file_name).unwrap();
+        let mut vm = VmCPU::new([0; REGISTER_COUNT], memory);
+        vm.console = Box::new(BufferConsole::default());
+        vm
+    }
+
+    #[test]
+    fn test_round_trip_is_compact() {
+        let vm = vm("./resources/2048.obj");
+        let snapshot = vm.snapshot();
+        let bytes = snapshot.to_bytes();
+
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+        // 2048 spans 5 pages, everything else is zero
+        assert!(bytes.len() < 6 * PAGE_SIZE * 2, "{} bytes", bytes.len());
+    }
+
+    #[test]
+    fn test_restore_resumes_identically() {
+        let mut reference = vm("./resources/2048.obj");
+        reference.unread_input(b"nwasdwasd");
+        for _ in 0..20_000 {
+            reference.step();
+        }
+        let snapshot = Snapshot::from_bytes(&reference.snapshot().to_bytes()).unwrap();
+        assert!(!snapshot.pending_input.is_empty());
+        for _ in 0..50_000 {
+            reference.step();
+        }
+
+        // memory and pending keys all come from the snapshot
+        let mut restored = vm("./resources/rogue.obj");
+        restored.restore(&snapshot);
+        for _ in 0..50_000 {
+            restored.step();
+        }
+
+        assert_eq!(restored.registers, reference.registers);
+        assert_eq!(restored.snapshot(), reference.snapshot());
+    }
+
+    #[test]
+    fn test_rejects_newer_version() {
+        let mut bytes = vm("./resources/2048.obj").snapshot().to_bytes();
+        bytes[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_be_bytes());
+        assert!(Snapshot::from_bytes(&bytes).is_err());
+        assert!(Snapshot::from_bytes(b"LC3").is_err());
+    }
+}
@@ -0,0 +1,165 @@
+//! Raw terminal mode for interactive programs
+//!
+//! Games like 2048 and rogue read single keys: the terminal must hand over
+//! each key as it is pressed, without waiting for Enter and without echoing
+//! it. [`RawMode`] switches stdin into that mode and puts the original
+//! settings back when dropped, including while unwinding from a panic. A
+//! Ctrl-C handler restores them too before the process exits.
+//!
+//! Keys are read straight from the file descriptor, so [`key_ready`] sees
+//! exactly what [`read_key`] will return. Once stdin is closed no key is
+//! ever ready and reads come back empty: GETC stops waiting for input,
+//! while a program polling KBSR polls on until `--max-instructions`.
+
+use std::fmt;
+
+pub use imp::{key_ready, read_key, RawMode};
+
+impl fmt::Debug for RawMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawMode").finish_non_exhaustive()
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        io,
+        mem::MaybeUninit,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            OnceLock,
+        },
+    };
+
+    // settings from before the first switch, for the signal handler
+    static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+    // stdin reached its end, nothing more will come
+    static CLOSED: AtomicBool = AtomicBool::new(false);
+
+    pub struct RawMode {
+        original: libc::termios,
+    }
+
+    impl RawMode {
+        /// Switch stdin to non-canonical, no-echo mode. `None` when stdin is
+        /// not a terminal.
+        pub fn enter() -> io::Result<Option<Self>> {
+            if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+                return Ok(None);
+            }
+
+            let mut termios = MaybeUninit::<libc::termios>::uninit();
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = unsafe { termios.assume_init() };
+            let _ = ORIGINAL.set(original);
+
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            set(&raw)?;
+
+            let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            unsafe {
+                libc::signal(libc::SIGINT, handler);
+                libc::signal(libc::SIGTERM, handler);
+            }
+            Ok(Some(Self { original }))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            let _ = set(&self.original);
+            unsafe {
+                libc::signal(libc::SIGINT, libc::SIG_DFL);
+                libc::signal(libc::SIGTERM, libc::SIG_DFL);
+            }
+        }
+    }
+
+    fn set(termios: &libc::termios) -> io::Result<()> {
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // only async-signal-safe calls in here
+    extern "C" fn on_signal(signal: libc::c_int) {
+        if let Some(original) = ORIGINAL.get() {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original) };
+        }
+        unsafe { libc::_exit(128 + signal) };
+    }
+
+    /// Whether a key can be read without blocking, never once stdin is
+    /// closed
+    pub fn key_ready() -> io::Result<bool> {
+        if CLOSED.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let mut poll_fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll_fd, 1, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            // a hang up with nothing left to read is the end of input
+            _ => Ok(poll_fd.revents & libc::POLLIN != 0),
+        }
+    }
+
+    /// Block until a key arrives, `None` at the end of input
+    pub fn read_key() -> io::Result<Option<u8>> {
+        let mut key = 0u8;
+        if CLOSED.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        loop {
+            match unsafe { libc::read(libc::STDIN_FILENO, (&mut key as *mut u8).cast(), 1) } {
+                1 => return Ok(Some(key)),
+                0 => {
+                    CLOSED.store(true, Ordering::Relaxed);
+                    return Ok(None);
+                }
+                _ => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::io::{self, Read};
+
+    pub struct RawMode;
+
+    impl RawMode {
+        pub fn enter() -> io::Result<Option<Self>> {
+            Ok(None)
+        }
+    }
+
+    pub fn key_ready() -> io::Result<bool> {
+        // stdin cannot be checked without reading it
+        Ok(true)
+    }
+
+    pub fn read_key() -> io::Result<Option<u8>> {
+        let mut buffer = [0; 1];
+        match io::stdin().read(&mut buffer)? {
+            0 => Ok(None),
+            _ => Ok(Some(buffer[0])),
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! Golden output tests for whole programs
+//!
+//! A test case types a keystroke script into a program and compares
+//! everything it prints, through OUT, PUTS, PUTSP or the display registers,
+//! with a golden file. Cases are small `key = value` files:
+//!
+//! ```text
+//! # 2048.case
+//! program = ../2048.obj
+//! input = 2048.keys
+//! golden = 2048.out
+//! budget = 5000000
+//! after_input = 200000
+//! seed = 7
+//! ```
+//!
+//! Paths are relative to the case file. `budget` is the most instructions
+//! the run may take; a program still running when it is spent fails the
+//! case. A run also ends when the program halts, or when it waits in GETC
+//! after the last key. Programs that poll KBSR never wait, so
+//! `after_input` ends the run that many instructions after the last key was
+//! read instead.
+//!
+//! Scripts are typed as written. `@<n>` at the start of a line holds the
+//! rest of the line back until `n` instructions after the previous key;
+//! each line ends with Enter unless it ends in `\`. Lines starting with `#`
+//! are comments. `\n`, `\r`, `\e`, `\s`, `\\` and `\xHH` escape newline,
+//! carriage return, escape, space, backslash and any byte.
+
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    blocks::Engine,
+    console::BufferConsole,
+    cpu::{StepResult, VmCPU},
+    debugger,
+    error::Fault,
+    machine::MachineBuilder,
+};
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Keys to type, each after a number of instructions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    pub keys: Vec<(u64, u8)>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut keys = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| invalid(format!("line {}: {}", index + 1, message));
+
+            let (mut delay, mut rest) = (0, line);
+            if let Some(delayed) = line.strip_prefix('@') {
+                let (count, keys) = delayed.split_once(' ').unwrap_or((delayed, ""));
+                delay = count
+                    .parse()
+                    .map_err(|_| error("expected `@<instructions>`"))?;
+                rest = keys;
+            }
+            // an odd number of trailing backslashes leaves one unescaped
+            let trailing = rest.len() - rest.trim_end_matches('\\').len();
+            let (rest, enter) = match trailing % 2 {
+                1 => (&rest[..rest.len() - 1], false),
+                _ => (rest, true),
+            };
+
+            let mut bytes = Vec::new();
+            let mut chars = rest.chars();
+            while let Some(char) = chars.next() {
+                if char != '\\' {
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(char.encode_utf8(&mut buffer).as_bytes());
+                    continue;
+                }
+                bytes.push(match chars.next() {
+                    Some('n') => b'\n',
+                    Some('r') => b'\r',
+                    Some('e') => 0x1B,
+                    Some('s') => b' ',
+                    Some('\\') => b'\\',
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        u8::from_str_radix(&hex, 16).map_err(|_| error("expected `\\xHH`"))?
+                    }
+                    _ => return Err(error("unknown escape")),
+                });
+            }
+            if enter {
+                bytes.push(b'\n');
+            }
+
+            for (position, byte) in bytes.into_iter().enumerate() {
+                keys.push((if position == 0 { delay } else { 0 }, byte));
+            }
+        }
+        Ok(Self { keys })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub program: PathBuf,
+    pub script: Script,
+    pub golden: PathBuf,
+    pub budget: u64,
+    pub after_input: Option<u64>,
+    pub seed: Option<u64>,
+}
+
+pub const DEFAULT_BUDGET: u64 = 10_000_000;
+
+impl TestCase {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut program = None;
+        let mut script = Script::default();
+        let mut golden = None;
+        let mut budget = DEFAULT_BUDGET;
+        let mut after_input = None;
+        let mut seed = None;
+        for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error =
+                |message: &str| invalid(format!("{}:{}: {}", path.display(), index + 1, message));
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`"))?;
+            let value = value.trim();
+            let number = || value.parse().map_err(|_| error("expected a number"));
+            match key.trim() {
+                "program" => program = Some(directory.join(value)),
+                "input" => script = Script::parse(&fs::read_to_string(directory.join(value))?)?,
+                "golden" => golden = Some(directory.join(value)),
+                "budget" => budget = number()?,
+                "after_input" => after_input = Some(number()?),
+                "seed" => seed = Some(number()?),
+                other => return Err(error(&format!("unknown key `{}`", other))),
+            }
+        }
+
+        Ok(Self {
+            program: program.ok_or_else(|| invalid(format!("{}: no program", path.display())))?,
+            golden: golden.unwrap_or_else(|| path.with_extension("out")),
+            name,
+            script,
+            budget,
+            after_input,
+            seed,
+        })
+    }
+
+    /// Run the program with the script and capture its output
+    pub fn run(&self) -> io::Result<Run> {
+        self.run_with(Engine::default())
+    }
+
+    pub fn run_with(&self, engine: Engine) -> io::Result<Run> {
+        let (origin, words, _) = debugger::read_program(&self.program, None)?;
+        let mut builder = MachineBuilder::new().image(origin, &words).engine(engine);
+        if let Some(seed) = self.seed {
+            builder = builder.seed(seed);
+        }
+        let mut vm = builder.build().into_cpu();
+        Ok(type_script(
+            &mut vm,
+            &self.script,
+            self.budget,
+            self.after_input,
+            VmCPU::advance,
+        ))
+    }
+
+    /// Run and compare with the golden file, or write the golden file when
+    /// `update` is set
+    pub fn check(&self, update: bool) -> io::Result<Outcome> {
+        let run = self.run()?;
+        match run.end {
+            End::OutOfBudget => return Ok(Outcome::OutOfBudget(run)),
+            End::Fault(fault) => return Ok(Outcome::Fault(fault)),
+            _ => {}
+        }
+        if update {
+            fs::write(&self.golden, &run.output)?;
+            return Ok(Outcome::Updated);
+        }
+
+        let expected = match fs::read(&self.golden) {
+            Ok(expected) => expected,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Outcome::NoGolden),
+            Err(e) => return Err(e),
+        };
+        if expected == run.output {
+            Ok(Outcome::Passed)
+        } else {
+            Ok(Outcome::Failed(diff(
+                &String::from_utf8_lossy(&expected),
+                &String::from_utf8_lossy(&run.output),
+            )))
+        }
+    }
+}
+
+/// Type `script` into `vm` and capture what it prints, running at most
+/// `budget` instructions through `step`. Like [`VmCPU::advance`], `step`
+/// gets the most instructions it may execute and returns how the last one
+/// ended and how many it executed. The console of `vm` is replaced.
+pub fn type_script(
+    vm: &mut VmCPU,
+    script: &Script,
+    budget: u64,
+    after_input: Option<u64>,
+    mut step: impl FnMut(&mut VmCPU, u64) -> (StepResult, u64),
+) -> Run {
+    let console = Rc::new(RefCell::new(BufferConsole::default()));
+    vm.console = Box::new(console.clone());
+
+    let mut keys = script.keys.iter().peekable();
+    // instructions since the previous key was typed, and since the
+    // last one was read
+    let mut waited = 0;
+    let mut idle = 0;
+    let mut end = End::OutOfBudget;
+    let mut instructions = 0;
+    while instructions < budget {
+        while let Some((delay, key)) = keys.peek() {
+            if waited < *delay {
+                break;
+            }
+            console.borrow_mut().input.push_back(*key);
+            keys.next();
+            waited = 0;
+        }
+        let typed = keys.peek().is_none() && console.borrow().input.is_empty();
+
+        // up to the next key, and one at a time while the idle count may
+        // start with any instruction
+        let mut limit = budget - instructions;
+        if let Some((delay, _)) = keys.peek() {
+            limit = limit.min(delay - waited);
+        }
+        match after_input {
+            Some(after_input) if typed => limit = limit.min(after_input - idle),
+            Some(_) if keys.peek().is_none() => limit = 1,
+            _ => {}
+        }
+
+        let (result, count) = step(vm, limit);
+        instructions += count;
+        match result {
+            StepResult::Halted => {
+                end = End::Halted;
+                break;
+            }
+            StepResult::WaitingForInput if keys.peek().is_none() => {
+                end = End::WaitingForInput;
+                break;
+            }
+            StepResult::Fault(fault) => {
+                end = End::Fault(fault);
+                break;
+            }
+            _ => {}
+        }
+        waited += count;
+        if typed {
+            idle += count;
+            if after_input.is_some_and(|limit| idle >= limit) {
+                end = End::AfterInput;
+                break;
+            }
+        }
+    }
+
+    let output = console.borrow_mut().take_output();
+    Run {
+        output,
+        end,
+        instructions,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Halted,
+    /// GETC with no keys left
+    WaitingForInput,
+    /// `after_input` instructions passed after the last key
+    AfterInput,
+    OutOfBudget,
+    Fault(Fault),
+}
+
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub output: Vec<u8>,
+    pub end: End,
+    pub instructions: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Passed,
+    Updated,
+    /// the output differs from the golden file, as a diff
+    Failed(String),
+    NoGolden,
+    OutOfBudget(Run),
+    Fault(Fault),
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        matches!(self, Outcome::Passed | Outcome::Updated)
+    }
+}
+
+// control characters made visible, so escape sequences show up in diffs
+fn visible(line: &str) -> String {
+    line.chars()
+        .map(|char| match char {
+            '\x1b' => "\\e".to_string(),
+            '\r' => "\\r".to_string(),
+            char if char.is_control() => format!("\\x{:02X}", char as u32),
+            char => char.to_string(),
+        })
+        .collect()
+}
+
+/// Line diff of `expected` and `actual` with `-`/`+` markers and two lines
+/// of context around each change
+pub fn diff(expected: &str, actual: &str) -> String {
+    const CONTEXT: usize = 2;
+    let old: Vec<&str> = expected.split_inclusive('\n').collect();
+    let new: Vec<&str> = actual.split_inclusive('\n').collect();
+
+    // longest common subsequence, from the back
+    let mut common = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i], i));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(('-', old[i], i));
+            i += 1;
+        } else {
+            lines.push(('+', new[j], i));
+            j += 1;
+        }
+    }
+
+    let changed: Vec<usize> = (0..lines.len())
+        .filter(|index| lines[*index].0 != ' ')
+        .collect();
+    let mut text = String::new();
+    let mut last_shown = None;
+    for (index, (marker, line, old_line)) in lines.iter().enumerate() {
+        let near = changed
+            .iter()
+            .any(|change| change.abs_diff(index) <= CONTEXT);
+        if !near {
+            continue;
+        }
+        if last_shown.is_none_or(|last| last + 1 != index) {
+            writeln!(text, "@@ line {} @@", old_line + 1).unwrap();
+        }
+        last_shown = Some(index);
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        writeln!(text, "{} {}", marker, visible(line)).unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let script = Script::parse("# start\n@100 y\\\nwa\\sd\\x41\\\n").unwrap();
+        let bytes: Vec<u8> = script.keys.iter().map(|(_, key)| *key).collect();
+        assert_eq!(bytes, b"ywa dA");
+        assert_eq!(script.keys[0], (100, b'y'));
+        assert_eq!(script.keys[1], (0, b'w'));
+        assert!(Script::parse("@x y").is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let diff = diff(
+            "1\n2\n3\n4\n5\n6\n7\n8\n9\n",
+            "1\n2\n3\nfour\n5\n6\n7\n8\n9\n\x1b[H",
+        );
+        assert_eq!(
+            diff,
+            "@@ line 2 @@\n  2\n  3\n- 4\n+ four\n  5\n  6\n@@ line 8 @@\n  8\n  9\n+ \\e[H\n"
+        );
+    }
+
+    #[test]
+    fn test_golden_cases() {
+        let mut cases: Vec<PathBuf> = fs::read_dir("./resources/golden")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "case")
+            })
+            .collect();
+        cases.sort();
+        assert!(!cases.is_empty());
+
+        for path in cases {
+            let case = TestCase::load(&path).unwrap();
+            match case.check(false).unwrap() {
+                Outcome::Passed => {}
+                Outcome::Failed(diff) => panic!("{} differs:\n{}", case.name, diff),
+                other => panic!("{}: {:?}", case.name, other),
+            }
+        }
+    }
+}
@@ -6,18 +6,31 @@ pub enum TrapType {
     In,
     PutSp,
     Halt,
+    // host files, only while a `crate::files::FileSystem` is attached
+    FileOpen,
+    FileRead,
+    FileWrite,
+    FileClose,
+    FileSeek,
 }
 
-impl From<u16> for TrapType {
-    fn from(value: u16) -> Self {
-        match value {
+impl TryFrom<u16> for TrapType {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, ()> {
+        Ok(match value {
             0x20 => TrapType::Get,
             0x21 => TrapType::Out,
             0x22 => TrapType::Put,
             0x23 => TrapType::In,
             0x24 => TrapType::PutSp,
             0x25 => TrapType::Halt,
-            _ => panic!("Invalid trap type {:x}", value),
-        }
+            0x30 => TrapType::FileOpen,
+            0x31 => TrapType::FileRead,
+            0x32 => TrapType::FileWrite,
+            0x33 => TrapType::FileClose,
+            0x34 => TrapType::FileSeek,
+            _ => return Err(()),
+        })
     }
 }
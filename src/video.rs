@@ -0,0 +1,314 @@
+//! Framebuffer display
+//!
+//! The 128x124 pixels live in ordinary memory from xC000 up to the device
+//! page, one word per pixel, row by row. Each word holds 15-bit RGB:
+//!
+//! ```text
+//! 15 | 14..10 | 9..5  | 4..0
+//!  0 | red    | green | blue
+//! ```
+//!
+//! Nothing here needs a window system: a [`Frame`] is captured from memory
+//! and written as PPM or PNG, or drawn on a terminal with ANSI colors and
+//! half blocks. A [`FrameRecorder`] attached to [`crate::cpu::VmCPU`] does
+//! that every N instructions.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+pub const FRAMEBUFFER_BASE: u16 = 0xC000;
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 124;
+
+/// 8 bits per channel from a 15-bit pixel
+pub fn rgb(pixel: u16) -> [u8; 3] {
+    let channel = |shift: u16| {
+        let value = ((pixel >> shift) & 0x1F) as u8;
+        value << 3 | value >> 2
+    };
+    [channel(10), channel(5), channel(0)]
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pixels: Vec<u16>,
+}
+
+impl Frame {
+    /// Copy the framebuffer out of a full memory image
+    pub fn capture(memory: &[u16]) -> Self {
+        let start = FRAMEBUFFER_BASE as usize;
+        Self {
+            pixels: memory[start..start + WIDTH * HEIGHT].to_vec(),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * WIDTH + x]
+    }
+
+    fn rgb_rows(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.pixels
+            .chunks(WIDTH)
+            .map(|row| row.iter().flat_map(|pixel| rgb(*pixel)).collect())
+    }
+
+    /// Binary PPM (P6)
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+        for row in self.rgb_rows() {
+            bytes.extend(row);
+        }
+        bytes
+    }
+
+    /// Truecolor PNG with uncompressed image data
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(HEIGHT * (1 + WIDTH * 3));
+        for row in self.rgb_rows() {
+            // no filter
+            raw.push(0);
+            raw.extend(row);
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(WIDTH as u32).to_be_bytes());
+        header.extend_from_slice(&(HEIGHT as u32).to_be_bytes());
+        // 8 bits per channel, RGB, default compression, filter and no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut bytes, b"IHDR", &header);
+        png_chunk(&mut bytes, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut bytes, b"IEND", &[]);
+        bytes
+    }
+
+    /// Two pixels per character cell using `▀` with the upper pixel as
+    /// foreground and the lower one as background, 24-bit colors
+    pub fn to_ansi(&self) -> String {
+        let mut text = String::new();
+        for y in (0..HEIGHT).step_by(2) {
+            for x in 0..WIDTH {
+                let [r, g, b] = rgb(self.pixel(x, y));
+                let [br, bg, bb] = rgb(self.pixel(x, y + 1));
+                write!(
+                    text,
+                    "\x1b[38;2;{};{};{};48;2;{};{};{}m▀",
+                    r, g, b, br, bg, bb
+                )
+                .unwrap();
+            }
+            text.push_str("\x1b[0m\n");
+        }
+        text
+    }
+}
+
+fn png_chunk(bytes: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = bytes.len();
+    bytes.extend_from_slice(tag);
+    bytes.extend_from_slice(data);
+    let crc = crc32(&bytes[start..]);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// zlib stream made of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        bytes.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        bytes.push(blocks.peek().is_none() as u8);
+        let length = block.len() as u16;
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes.extend_from_slice(&(!length).to_le_bytes());
+        bytes.extend_from_slice(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    bytes.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    bytes
+}
+
+/// Where recorded frames go
+pub enum FrameSink {
+    /// numbered `frame-000001.ppm` files in a directory
+    Ppm(PathBuf),
+    /// numbered `frame-000001.png` files in a directory
+    Png(PathBuf),
+    /// redrawn in place on a terminal
+    Ansi(Box<dyn Write>),
+}
+
+/// Captures the framebuffer every `interval` instructions and passes it to
+/// the sink when it changed since the last frame
+pub struct FrameRecorder {
+    interval: u64,
+    sink: FrameSink,
+    instructions: u64,
+    frames: usize,
+    last: Option<Frame>,
+    // the first failed write, which ends the recording
+    error: Option<io::Error>,
+}
+
+impl FrameRecorder {
+    pub fn new(interval: u64, sink: FrameSink) -> Self {
+        Self {
+            interval: interval.max(1),
+            sink,
+            instructions: 0,
+            frames: 0,
+            last: None,
+            error: None,
+        }
+    }
+
+    /// Frames written so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Why the recording stopped early, a full disk or a closed pipe
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Count an executed instruction. A frame that cannot be written stops
+    /// the recording rather than the program; see [`FrameRecorder::error`].
+    pub fn tick(&mut self, memory: &[u16]) {
+        if self.error.is_some() {
+            return;
+        }
+        self.instructions += 1;
+        if !self.instructions.is_multiple_of(self.interval) {
+            return;
+        }
+        let frame = Frame::capture(memory);
+        if self.last.as_ref() == Some(&frame) {
+            return;
+        }
+        match self.write(&frame) {
+            Ok(()) => {
+                self.frames += 1;
+                self.last = Some(frame);
+            }
+            Err(error) => self.error = Some(error),
+        }
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let number = self.frames + 1;
+        match &mut self.sink {
+            FrameSink::Ppm(directory) => fs::write(
+                directory.join(format!("frame-{:06}.ppm", number)),
+                frame.to_ppm(),
+            )?,
+            FrameSink::Png(directory) => fs::write(
+                directory.join(format!("frame-{:06}.png", number)),
+                frame.to_png(),
+            )?,
+            FrameSink::Ansi(out) => {
+                // cursor home, then draw over the previous frame
+                write!(out, "\x1b[H{}", frame.to_ansi())?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame() -> Frame {
+        let mut memory = vec![0; 1 << 16];
+        // red top left, white bottom right
+        memory[FRAMEBUFFER_BASE as usize] = 0x7C00;
+        memory[FRAMEBUFFER_BASE as usize + WIDTH * HEIGHT - 1] = 0x7FFF;
+        Frame::capture(&memory)
+    }
+
+    #[test]
+    fn test_ppm() {
+        let ppm = frame().to_ppm();
+        let header = b"P6\n128 124\n255\n";
+        assert!(ppm.starts_with(header));
+        assert_eq!(ppm.len(), header.len() + WIDTH * HEIGHT * 3);
+        assert_eq!(ppm[header.len()..header.len() + 4], [255, 0, 0, 0]);
+        assert_eq!(ppm[ppm.len() - 3..], [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_png_checksums() {
+        let png = frame().to_png();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        // IEND has no data and a well known CRC
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_ansi_half_blocks() {
+        let ansi = frame().to_ansi();
+        assert_eq!(ansi.lines().count(), HEIGHT / 2);
+        assert!(ansi.starts_with("\x1b[38;2;255;0;0;48;2;0;0;0m▀"));
+    }
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_write_stops_recording() {
+        let mut memory = vec![0; 1 << 16];
+        let mut recorder = FrameRecorder::new(1, FrameSink::Ansi(Box::new(Closed)));
+        recorder.tick(&memory);
+        assert_eq!(recorder.frames(), 0);
+        assert_eq!(
+            recorder.error().map(io::Error::kind),
+            Some(io::ErrorKind::BrokenPipe)
+        );
+
+        // later frames are not even tried
+        memory[FRAMEBUFFER_BASE as usize] = 0x7FFF;
+        recorder.tick(&memory);
+        assert_eq!(recorder.instructions, 1);
+    }
+}
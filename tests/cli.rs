@@ -0,0 +1,90 @@
+//! The `vm` binary as a separate process, for what needs real stdin and
+//! stdout
+
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+// exit statuses from `cli`
+const EXIT_HALTED: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_FAULT: i32 = 3;
+const EXIT_BUDGET: i32 = 4;
+const EXIT_NO_INPUT: i32 = 5;
+
+#[test]
+fn test_exit_status() {
+    let directory = std::env::temp_dir().join(format!("cli-status-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let program = |name: &str, source: &str| {
+        let path = directory.join(name);
+        fs::write(&path, format!(".ORIG x3000\n{}\n.END\n", source)).unwrap();
+        path.to_string_lossy().into_owned()
+    };
+    let halts = program("halts.asm", "ADD R0, R0, #1\nHALT");
+    let spins = program("spins.asm", "LOOP BR LOOP");
+    let faults = program("faults.asm", ".FILL xD000");
+    let reads = program("reads.asm", "GETC\nHALT");
+    let script = directory.join("keys");
+    fs::write(&script, "").unwrap();
+    let script = script.to_string_lossy();
+
+    let status = |line: String| {
+        Command::new(env!("CARGO_BIN_EXE_vm"))
+            .args(line.split_whitespace())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+            .code()
+    };
+    assert_eq!(status(format!("run {} --quiet", halts)), Some(EXIT_HALTED));
+    assert_eq!(
+        status(format!("diff {} --max-instructions 5000", spins)),
+        Some(EXIT_HALTED)
+    );
+    assert_eq!(
+        status(format!("{} --max-instructions 50", spins)),
+        Some(EXIT_BUDGET)
+    );
+    assert_eq!(status(format!("run {}", faults)), Some(EXIT_FAULT));
+    assert_eq!(
+        status(format!("run {} --input-script {}", reads, script)),
+        Some(EXIT_NO_INPUT)
+    );
+    assert_eq!(
+        status(format!("run {} --entry NOWHERE", halts)),
+        Some(EXIT_ERROR)
+    );
+    assert_eq!(status("frobnicate".to_string()), Some(EXIT_USAGE));
+    assert_eq!(status(String::new()), Some(EXIT_USAGE));
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_closed_stdin() {
+    let directory = std::env::temp_dir().join(format!("cli-stdin-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let reads = directory.join("reads.asm");
+    fs::write(&reads, ".ORIG x3000\nGETC\nGETC\nHALT\n.END\n").unwrap();
+    let vm = || {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_vm"));
+        command.args(["run", reads.to_str().unwrap(), "--quiet"]);
+        command.stdout(Stdio::null());
+        command
+    };
+
+    // GETC at the end of input waits for a key that will not come
+    let status = vm().stdin(Stdio::null()).status().unwrap();
+    assert_eq!(status.code(), Some(EXIT_NO_INPUT));
+    for (input, expected) in [(&b"w"[..], EXIT_NO_INPUT), (b"wa", EXIT_HALTED)] {
+        let mut child = vm().stdin(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(expected));
+    }
+    fs::remove_dir_all(directory).unwrap();
+}